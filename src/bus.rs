@@ -1,17 +1,48 @@
 use crate::clint::CLINT;
 use crate::dram::Dram;
+use std::io;
 use crate::exception::Exception;
-use crate::param::{CLINT_BASE, CLINT_END, DRAM_BASE, DRAM_END, PLIC_BASE, PLIC_END, UART_BASE, UART_END, VIRTIO_BASE, VIRTIO_END};
+use crate::htif::Htif;
+use crate::memory::SparseMemory;
+use crate::param::{CLINT_BASE, CLINT_END, DRAM_BASE, DRAM_END, PLIC_BASE, PLIC_END, ROM_BASE, ROM_END, RTC_BASE, RTC_END, SYSCON_BASE, SYSCON_END, UART_BASE, UART_END, VIRTIO_BASE, VIRTIO_END};
 use crate::plic::PLIC;
+use crate::rom::Rom;
+use crate::rtc::{Rtc, RtcClock};
+use crate::snapshot::{Reader, Writer};
+use crate::syscon::Syscon;
 use crate::uart::UART;
 use crate::virtio::VirtioBlock;
 
+/// A request from the guest to stop or restart the machine.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Shutdown {
+    /// Power off and exit the emulator with the given exit code.
+    PowerOff(i32),
+    /// Reset the machine and boot again.
+    Reboot,
+}
+
+/// Return the status the emulator exits with for the exit code `code` of the guest. The host only
+/// keeps the low 8 bits of a status, so a nonzero code that would come out as 0 there, like the
+/// number 256 of a failing test, exits with 255.
+pub fn exit_status(code: u64) -> i32 {
+    code.min(255) as i32
+}
+
 pub struct Bus {
-    dram: Dram,
+    /// The dram, whose changes can be journaled to go back in time.
+    pub dram: Dram,
     plic: PLIC,
     clint: CLINT,
+    syscon: Syscon,
+    htif: Option<Htif>,
+    /// The boot ROM, which the loader fills in with the firmware entry.
+    pub rom: Rom,
     pub uart: UART,
     pub virtio_blk: VirtioBlock,
+    pub rtc: Rtc,
+    /// The address space of a program run in user mode. It replaces the dram and the devices.
+    pub memory: Option<SparseMemory>,
 }
 
 impl Bus {
@@ -20,13 +51,135 @@ impl Bus {
             dram: Dram::new(code),
             plic: PLIC::new(),
             clint: CLINT::new(),
+            syscon: Syscon::new(),
+            htif: None,
+            rom: Rom::new(DRAM_BASE, 0),
             uart: UART::new(),
             virtio_blk: VirtioBlock::new(disk_image),
+            rtc: Rtc::new(RtcClock::Host),
+            memory: None,
+        }
+    }
+
+    /// Let the devices that count executed instructions know that one more has been executed.
+    pub fn tick(&mut self) {
+        self.clint.tick();
+        self.rtc.tick();
+    }
+
+    /// Bring the devices back to their power-on state and clear the dram. The uart, the rtc
+    /// and the contents of the disk are kept, as they live outside of the machine, and so is the
+    /// boot ROM.
+    pub fn reset(&mut self) {
+        self.dram = Dram::new(Vec::new());
+        self.plic = PLIC::new();
+        self.clint = CLINT::new();
+        self.syscon = Syscon::new();
+        self.virtio_blk.reset();
+    }
+
+    /// Save the devices. Pending shutdown requests are not saved.
+    pub fn save(&self, w: &mut Writer) {
+        self.rom.save(w);
+        self.plic.save(w);
+        self.clint.save(w);
+        self.uart.save(w);
+        self.virtio_blk.save(w);
+        self.rtc.save(w);
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.rom.restore(r)?;
+        self.plic.restore(r)?;
+        self.clint.restore(r)?;
+        self.uart.restore(r)?;
+        self.virtio_blk.restore(r)?;
+        self.rtc.restore(r)
+    }
+
+    /// Return the time of the CLINT timer.
+    pub fn mtime(&self) -> u64 {
+        self.clint.mtime()
+    }
+
+    /// Print the state of the device called `name`. Return false if there is no such device.
+    pub fn dump_device(&self, name: &str) -> bool {
+        match name {
+            "plic" => self.plic.dump(),
+            "clint" => self.clint.dump(),
+            "uart" => self.uart.dump(),
+            "virtio" => self.virtio_blk.dump(&self.dram),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Copy `data` to the memory at `addr`, e.g. to load a program.
+    pub fn store_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        if let Some(memory) = &mut self.memory {
+            return match memory.write(addr, data) {
+                true => Ok(()),
+                false => Err(Exception::StoreAMOAccessFault(addr)),
+            };
+        }
+        let end = addr.checked_add(data.len() as u64).ok_or(Exception::StoreAMOAccessFault(addr))?;
+        if addr < DRAM_BASE || end > DRAM_END + 1 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        let index = (addr - DRAM_BASE) as usize;
+        self.dram.write(index, data);
+        Ok(())
+    }
+
+    /// Copy the memory at `addr` into `buf`.
+    pub fn load_bytes(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        if let Some(memory) = &mut self.memory {
+            return match memory.read(addr, buf) {
+                true => Ok(()),
+                false => Err(Exception::LoadAccessFault(addr)),
+            };
+        }
+        let end = addr.checked_add(buf.len() as u64).ok_or(Exception::LoadAccessFault(addr))?;
+        if addr < DRAM_BASE || end > DRAM_END + 1 {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let index = (addr - DRAM_BASE) as usize;
+        buf.copy_from_slice(&self.dram.dram[index..index + buf.len()]);
+        Ok(())
+    }
+
+    /// Watch the tohost word of `htif` for commands of the guest.
+    pub fn set_htif(&mut self, htif: Htif) {
+        self.htif = Some(htif);
+    }
+
+    /// Drop the console output of the guest, while instructions that already ran are executed
+    /// again.
+    pub fn mute_console(&mut self, muted: bool) {
+        self.uart.set_muted(muted);
+        if let Some(htif) = &mut self.htif {
+            htif.muted = muted;
         }
     }
 
+    pub fn htif(&self) -> Option<&Htif> {
+        self.htif.as_ref()
+    }
+
+    /// Return the shutdown request raised by a device, if any.
+    pub fn shutdown(&mut self) -> Option<Shutdown> {
+        self.syscon.take_request()
+            .or_else(|| self.htif.as_mut().and_then(|htif| htif.take_request()))
+    }
+
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if let Some(memory) = &mut self.memory {
+            return memory.load(addr, size);
+        }
         match addr {
+            ROM_BASE..=ROM_END => self.rom.load(addr, size),
+            SYSCON_BASE..=SYSCON_END => self.syscon.load(addr, size),
+            RTC_BASE..=RTC_END => self.rtc.load(addr, size),
             CLINT_BASE..=CLINT_END => self.clint.load(addr, size),
             PLIC_BASE..=PLIC_END => self.plic.load(addr, size),
             DRAM_BASE..=DRAM_END => self.dram.load(addr, size),
@@ -37,10 +190,23 @@ impl Bus {
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if let Some(memory) = &mut self.memory {
+            return memory.store(addr, size, value);
+        }
         match addr {
+            ROM_BASE..=ROM_END => self.rom.store(addr, size, value),
+            SYSCON_BASE..=SYSCON_END => self.syscon.store(addr, size, value),
+            RTC_BASE..=RTC_END => self.rtc.store(addr, size, value),
             CLINT_BASE..=CLINT_END => self.clint.store(addr, size, value),
             PLIC_BASE..=PLIC_END => self.plic.store(addr, size, value),
-            DRAM_BASE..=DRAM_END => self.dram.store(addr, size, value),
+            DRAM_BASE..=DRAM_END => {
+                self.dram.store(addr, size, value)?;
+                match &mut self.htif {
+                    Some(htif) if htif.is_tohost(addr, size) => htif.handle(&mut self.dram),
+                    _ => (),
+                }
+                Ok(())
+            }
             UART_BASE..=UART_END => self.uart.store(addr, size, value),
             VIRTIO_BASE..=VIRTIO_END => self.virtio_blk.store(addr, size, value),
             _ => Err(Exception::StoreAMOAccessFault(addr)),
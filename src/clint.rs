@@ -1,6 +1,8 @@
+use std::io;
 use crate::exception::Exception;
 use crate::exception::Exception::{LoadAccessFault, StoreAMOAccessFault};
 use crate::param::*;
+use crate::snapshot::{Reader, Writer};
 
 pub struct CLINT {
     mtime: u64,
@@ -12,6 +14,31 @@ impl CLINT {
         Self { mtime: 0, mtimecmp: 0 }
     }
 
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn dump(&self) {
+        println!("{:-^80}", "clint");
+        println!("mtime = {:<#18x}  mtimecmp = {:<#18x}\n", self.mtime, self.mtimecmp);
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        w.u64(self.mtime);
+        w.u64(self.mtimecmp);
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.mtime = r.u64()?;
+        self.mtimecmp = r.u64()?;
+        Ok(())
+    }
+
+    /// Advance the timer by one tick per executed instruction.
+    pub fn tick(&mut self) {
+        self.mtime = self.mtime.wrapping_add(1);
+    }
+
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
         if size != 64 {
             return Err(LoadAccessFault(addr));
@@ -0,0 +1,382 @@
+//! Command line options of the emulator.
+
+use crate::rtc::RtcClock;
+use std::ops::Range;
+use crate::cpu::{Machine, Mode, Supervisor, User};
+use crate::trace::TraceFilter;
+use crate::watchpoint::{Action, Watchpoint};
+
+pub const USAGE: &str = "Usage: R-RISCV [options] <filename> <(option) image>
+       R-RISCV [options] --kernel <file>[@addr]
+       R-RISCV [options] --user <program> [args...]
+       R-RISCV [options] --pk <program> [args...]
+
+Options:
+    --kernel <file>[@addr]
+                         load a kernel (raw or ELF) for the program, or run it directly
+    --initrd <file>[@addr]
+                         load an initial ramdisk and announce it in the device tree
+    --dtb <file>[@addr]  load a device tree blob instead of the generated one; its address
+                         is passed in a1
+    --append <args>      kernel command line, set as /chosen/bootargs in the device tree
+    --dump-dtb <file>    write the device tree given to the guest to <file> and exit
+    --user               run a statically linked Linux program with its syscalls served by
+                         the emulator; the arguments after it are its command line
+    --pk                 like --user, for a newlib program built for the riscv-pk proxy kernel
+    --sbi                serve SBI calls and start the kernel in S-mode, without a firmware
+    --disk <image>       disk image of the virtio block device
+    --tohost <addr>      address of the HTIF tohost word
+    --fromhost <addr>    address of the HTIF fromhost word
+    --symbols <elf>      read symbols (e.g. tohost/fromhost) from an ELF file instead of
+                         the program
+    --semihosting <dir>  serve semihosting calls, with files sandboxed in <dir>
+    --semihosting-cmdline <args>
+                         command line returned to semihosting programs
+    --gdb <port|path>    wait for gdb to attach on a localhost TCP port or a Unix socket,
+                         with the hart stopped before its first instruction
+    --reverse            keep checkpoints so that gdb can step and continue backwards
+    --monitor            enable the monitor, opened by typing Ctrl-A c on the console
+    --paused             start in the monitor, before the first instruction
+    --watch <addr>[-<end>][,<flags>]
+                         log the accesses to the byte at <addr>, or to the addresses from
+                         <addr> to <end>; the flags are any of r, w and x for the accesses
+                         caught (default rw), phys for physical addresses, value=<n> to
+                         catch only the accesses of a value, and pause to open the monitor
+                         after the access; can be given more than once
+    --trace <file>       write a commit log of the retired instructions to <file>, in the
+                         format of Spike's --log-commits
+    --trace-pc <start>-<end>
+                         trace only the instructions at addresses from <start> to <end>
+    --trace-mode <modes> trace only the instructions run in the given privilege modes,
+                         e.g. m or s,u
+    --trace-window <first>-<end>
+                         trace only the retired instructions numbered from <first> to
+                         <end>, counting from 0
+    --lockstep <file>    compare every retired instruction with a reference commit log, e.g.
+                         from Spike, and stop at the first difference
+    --coverage <file>    write the source lines executed to <file> in the lcov format, using
+                         the DWARF line table of the program, or of the --symbols file
+    --stats <file>       write counts of the executed instructions, branches, loads and
+                         stores and traps, and the instructions never executed, to <file>
+                         as JSON at exit
+    --profile <file>     count the retired instructions by function and write them to <file>
+                         as folded stacks for flamegraph tools
+    --profile-stacks     attribute the instructions to call stacks, reconstructed from the
+                         calls and returns through ra and t0
+    --profile-top <n>    print the <n> functions that retired the most instructions at exit
+                         (default 20)
+    --snapshot <file>    save a snapshot of the machine to <file> when the emulator receives
+                         SIGUSR1
+    --snapshot-at <n>    also save the snapshot before the instruction numbered <n>, counting
+                         the retired instructions from 0
+    --restore <file>     start from a snapshot instead of booting; the other options have
+                         to set up the machine like the one that was saved
+    --record <file>      log the console input and the host times the guest reads to <file>
+    --replay <file>      run with the inputs logged by --record instead of the ones of the host
+    --rtc <host|secs>    follow the host clock (default) or run a deterministic clock
+                         starting at <secs> since 1970";
+
+/// A file to load, optionally at a given address.
+#[derive(Debug, Clone)]
+pub struct ImageArg {
+    pub path: String,
+    pub addr: Option<u64>,
+}
+
+impl ImageArg {
+    /// Parse `<file>[@addr]`.
+    fn parse(s: &str) -> Result<ImageArg, String> {
+        match s.rsplit_once('@') {
+            Some((path, addr)) => Ok(ImageArg { path: String::from(path), addr: Some(parse_u64(addr)?) }),
+            None => Ok(ImageArg { path: String::from(s), addr: None }),
+        }
+    }
+}
+
+/// The syscall interface a program run in user mode is built for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Abi {
+    /// Linux, as seen by programs linked against glibc or musl.
+    Linux,
+    /// The riscv-pk proxy kernel, as seen by programs linked against newlib.
+    Pk,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    /// The program to run, either an ELF executable or a flat binary loaded at the start of the dram.
+    pub program: Option<String>,
+    /// A kernel the program boots. Without a program, the kernel runs directly.
+    pub kernel: Option<ImageArg>,
+    /// An initial ramdisk for the kernel.
+    pub initrd: Option<ImageArg>,
+    /// A device tree blob for the kernel. Without it, one describing the machine is generated.
+    pub dtb: Option<ImageArg>,
+    /// The kernel command line.
+    pub append: Option<String>,
+    /// File to write the device tree to instead of running.
+    pub dump_dtb: Option<String>,
+    /// Run the program in user mode, serving its syscalls as the given interface defines them.
+    pub user: Option<Abi>,
+    /// Command line of a program run in user mode, starting with the program.
+    pub args: Vec<String>,
+    /// Serve the SBI calls of an S-mode kernel in the emulator.
+    pub sbi: bool,
+    /// The disk image backing the virtio block device.
+    pub disk: Option<String>,
+    /// HTIF tohost address given on the command line.
+    pub tohost: Option<u64>,
+    /// HTIF fromhost address given on the command line.
+    pub fromhost: Option<u64>,
+    /// ELF file to read the symbol table from.
+    pub symbols: Option<String>,
+    /// Directory the files of semihosting programs live in. Semihosting is off without it.
+    pub semihosting: Option<String>,
+    /// Command line returned by SYS_GET_CMDLINE. Defaults to the program name.
+    pub semihosting_cmdline: Option<String>,
+    /// Clock of the real-time clock device.
+    pub rtc: RtcClock,
+    /// TCP port or Unix socket to serve gdb on.
+    pub gdb: Option<String>,
+    /// Let gdb run the guest backwards.
+    pub reverse: bool,
+    /// Enable the monitor.
+    pub monitor: bool,
+    /// Open the monitor before the first instruction.
+    pub paused: bool,
+    /// Watchpoints served without a debugger.
+    pub watchpoints: Vec<Watchpoint>,
+    /// File to write the commit log to.
+    pub trace: Option<String>,
+    /// The instructions to write to the commit log.
+    pub trace_filter: TraceFilter,
+    /// Reference commit log to run in lockstep with.
+    pub lockstep: Option<String>,
+    /// File to write the coverage of the source lines to.
+    pub coverage: Option<String>,
+    /// File to write the statistics of the run to.
+    pub stats: Option<String>,
+    /// File to write the profile to.
+    pub profile: Option<String>,
+    /// Reconstruct the call stacks in the profile.
+    pub profile_stacks: bool,
+    /// Number of functions printed with the profile.
+    pub profile_top: usize,
+    /// File to save snapshots to.
+    pub snapshot: Option<String>,
+    /// Save a snapshot before this instruction.
+    pub snapshot_at: Option<u64>,
+    /// Snapshot to start from.
+    pub restore: Option<String>,
+    /// File to log the nondeterministic inputs to.
+    pub record: Option<String>,
+    /// File to replay the nondeterministic inputs from.
+    pub replay: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            program: None,
+            kernel: None,
+            initrd: None,
+            dtb: None,
+            append: None,
+            dump_dtb: None,
+            user: None,
+            args: Vec::new(),
+            sbi: false,
+            disk: None,
+            tohost: None,
+            fromhost: None,
+            symbols: None,
+            semihosting: None,
+            semihosting_cmdline: None,
+            rtc: RtcClock::Host,
+            gdb: None,
+            reverse: false,
+            monitor: false,
+            paused: false,
+            watchpoints: Vec::new(),
+            trace: None,
+            trace_filter: TraceFilter::default(),
+            lockstep: None,
+            coverage: None,
+            stats: None,
+            profile: None,
+            profile_stacks: false,
+            profile_top: 20,
+            snapshot: None,
+            snapshot_at: None,
+            restore: None,
+            record: None,
+            replay: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parse the command line arguments, without the program name.
+    pub fn parse(args: &[String]) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut positional = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            // In user mode, everything after the program is its command line.
+            if config.user.is_some() && !positional.is_empty() {
+                config.args.push(arg.clone());
+                continue;
+            }
+            if !arg.starts_with("--") {
+                positional.push(arg.clone());
+                continue;
+            }
+            let mut value = || iter.next().cloned().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--kernel" => config.kernel = Some(ImageArg::parse(&value()?)?),
+                "--initrd" => config.initrd = Some(ImageArg::parse(&value()?)?),
+                "--dtb" => config.dtb = Some(ImageArg::parse(&value()?)?),
+                "--append" => config.append = Some(value()?),
+                "--dump-dtb" => config.dump_dtb = Some(value()?),
+                "--user" | "--pk" if !cfg!(unix) => return Err(format!("{} needs a Unix host", arg)),
+                "--user" => config.user = Some(Abi::Linux),
+                "--pk" => config.user = Some(Abi::Pk),
+                "--sbi" => config.sbi = true,
+                "--disk" => config.disk = Some(value()?),
+                "--tohost" => config.tohost = Some(parse_u64(&value()?)?),
+                "--fromhost" => config.fromhost = Some(parse_u64(&value()?)?),
+                "--symbols" => config.symbols = Some(value()?),
+                "--semihosting" => config.semihosting = Some(value()?),
+                "--semihosting-cmdline" => config.semihosting_cmdline = Some(value()?),
+                "--rtc" => config.rtc = match value()?.as_str() {
+                    "host" => RtcClock::Host,
+                    // The time is kept in nanoseconds, which go up to the year 2554.
+                    epoch => match parse_u64(epoch)? {
+                        secs if secs > u64::MAX / 1_000_000_000 => return Err(format!("Invalid time {}", epoch)),
+                        secs => RtcClock::Fixed(secs),
+                    },
+                },
+                "--gdb" => config.gdb = Some(value()?),
+                "--reverse" => config.reverse = true,
+                "--monitor" => config.monitor = true,
+                "--paused" => (config.monitor, config.paused) = (true, true),
+                "--watch" => {
+                    let watchpoint = parse_watchpoint(&value()?)?;
+                    config.monitor |= watchpoint.action == Action::Pause;
+                    config.watchpoints.push(watchpoint);
+                }
+                "--trace" => config.trace = Some(value()?),
+                "--trace-pc" => config.trace_filter.pc = Some(parse_range(&value()?)?),
+                "--trace-mode" => config.trace_filter.modes = Some(parse_modes(&value()?)?),
+                "--trace-window" => config.trace_filter.window = Some(parse_range(&value()?)?),
+                "--lockstep" => config.lockstep = Some(value()?),
+                "--coverage" => config.coverage = Some(value()?),
+                "--stats" => config.stats = Some(value()?),
+                "--profile" => config.profile = Some(value()?),
+                "--profile-stacks" => config.profile_stacks = true,
+                "--profile-top" => config.profile_top = parse_u64(&value()?)? as usize,
+                "--snapshot" => config.snapshot = Some(value()?),
+                "--snapshot-at" => config.snapshot_at = Some(parse_u64(&value()?)?),
+                "--restore" => config.restore = Some(value()?),
+                "--record" => config.record = Some(value()?),
+                "--replay" => config.replay = Some(value()?),
+                _ => return Err(format!("Unknown option {}", arg)),
+            }
+        }
+        if config.snapshot_at.is_some() && config.snapshot.is_none() {
+            return Err(String::from("--snapshot-at needs a --snapshot file"));
+        }
+        if config.record.is_some() && config.replay.is_some() {
+            return Err(String::from("--record and --replay cannot be used together"));
+        }
+        if config.reverse {
+            if config.gdb.is_none() {
+                return Err(String::from("--reverse needs --gdb"));
+            }
+            // Files written by the guest and the inputs of a log cannot be taken back.
+            if config.user.is_some() || config.semihosting.is_some() || config.record.is_some() || config.replay.is_some() {
+                return Err(String::from("--reverse cannot be used with --user, --pk, --semihosting, --record or --replay"));
+            }
+        }
+        if config.user.is_some() {
+            if positional.is_empty() {
+                return Err(String::from("--user and --pk need a program"));
+            }
+            // The program gets an address space of its own, without the machine and its dram.
+            if config.kernel.is_some() || config.initrd.is_some() || config.dtb.is_some() {
+                return Err(String::from("--kernel, --initrd and --dtb cannot be used with --user or --pk"));
+            }
+            // A snapshot holds a machine, which a program run in user mode does not have.
+            if config.restore.is_some() || config.snapshot.is_some() {
+                return Err(String::from("--snapshot and --restore cannot be used with --user or --pk"));
+            }
+        }
+        if positional.len() > 2 || (positional.is_empty() && config.kernel.is_none()) {
+            return Err(String::from("Expected a program or a kernel, and an optional disk image"));
+        }
+        let mut positional = positional.into_iter();
+        config.program = positional.next();
+        config.disk = positional.next().or(config.disk);
+        if config.user.is_some() {
+            config.args.splice(0..0, config.program.clone());
+        }
+        Ok(config)
+    }
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal number.
+pub fn parse_u64(s: &str) -> Result<u64, String> {
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse::<u64>(),
+    };
+    result.map_err(|_| format!("Invalid number {}", s))
+}
+
+/// Parse `<start>-<end>`, the range from `start` up to but not including `end`.
+fn parse_range(s: &str) -> Result<Range<u64>, String> {
+    match s.split_once('-') {
+        Some((start, end)) => Ok(parse_u64(start)?..parse_u64(end)?),
+        None => Err(format!("Invalid range {}, expected <start>-<end>", s)),
+    }
+}
+
+/// Parse the watchpoint `<addr>[-<end>][,<flags>]`.
+fn parse_watchpoint(s: &str) -> Result<Watchpoint, String> {
+    let mut fields = s.split(',');
+    let range = fields.next().unwrap_or_default();
+    let range = match range.contains('-') {
+        true => parse_range(range)?,
+        false => parse_u64(range).map(|addr| addr..addr.saturating_add(1))?,
+    };
+    let mut watchpoint = Watchpoint::with_action(range, Action::Log);
+    for flag in fields {
+        match flag {
+            "phys" => watchpoint.physical = true,
+            "pause" => watchpoint.action = Action::Pause,
+            _ if flag.starts_with("value=") => watchpoint.value = Some(parse_u64(&flag[6..])?),
+            _ if !flag.is_empty() && flag.chars().all(|c| "rwx".contains(c)) => {
+                watchpoint.read |= flag.contains('r');
+                watchpoint.write |= flag.contains('w');
+                watchpoint.execute |= flag.contains('x');
+            }
+            _ => return Err(format!("Invalid watchpoint flag {}", flag)),
+        }
+    }
+    if !(watchpoint.read || watchpoint.write || watchpoint.execute) {
+        (watchpoint.read, watchpoint.write) = (true, true);
+    }
+    Ok(watchpoint)
+}
+
+/// Parse a comma-separated list of privilege modes, given by their initials.
+fn parse_modes(s: &str) -> Result<Vec<Mode>, String> {
+    s.split(',')
+        .map(|mode| match mode {
+            "m" | "M" => Ok(Machine),
+            "s" | "S" => Ok(Supervisor),
+            "u" | "U" => Ok(User),
+            _ => Err(format!("Invalid privilege mode {}", mode)),
+        })
+        .collect()
+}
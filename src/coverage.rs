@@ -0,0 +1,101 @@
+//! The coverage module records the pc of every instruction the guest executes and maps it through
+//! the line table of the program's DWARF information, to get the source lines a run covered
+//! without instrumenting the program. The coverage is written in the tracefile format of lcov,
+//! which genhtml turns into a report:
+//!
+//! ```text
+//! SF:/src/firmware/main.c
+//! DA:12,1
+//! DA:13,0
+//! LF:2
+//! LH:1
+//! end_of_record
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use crate::dwarf::LineTable;
+
+pub struct Coverage {
+    path: String,
+    lines: LineTable,
+    /// The times the instruction at each pc was executed.
+    executed: HashMap<u64, u64>,
+}
+
+impl Coverage {
+    /// Write the coverage of the lines in `lines` to the file at `path` at exit.
+    pub fn new(path: String, lines: LineTable) -> Self {
+        Self { path, lines, executed: HashMap::new() }
+    }
+
+    /// Count the instruction at `pc`, which is about to be executed.
+    pub fn execute(&mut self, pc: u64) {
+        *self.executed.entry(pc).or_insert(0) += 1;
+    }
+
+    /// Write the lcov tracefile. A line counts the times its most executed instruction ran.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut files: BTreeMap<&str, BTreeMap<u64, u64>> = BTreeMap::new();
+        for range in &self.lines.ranges {
+            let count = range.range.clone().step_by(4).filter_map(|pc| self.executed.get(&pc)).max().copied();
+            let line = files.entry(&self.lines.files[range.file]).or_default().entry(range.line).or_insert(0);
+            *line = (*line).max(count.unwrap_or(0));
+        }
+
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        let (mut found, mut hit) = (0, 0);
+        writeln!(out, "TN:")?;
+        for (file, lines) in &files {
+            writeln!(out, "SF:{}", file)?;
+            for (line, count) in lines {
+                writeln!(out, "DA:{},{}", line, count)?;
+            }
+            let covered = lines.values().filter(|&&count| count > 0).count();
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "LH:{}", covered)?;
+            writeln!(out, "end_of_record")?;
+            (found, hit) = (found + lines.len(), hit + covered);
+        }
+        out.flush()?;
+        println!("Coverage: {} of {} lines in {} files executed, written to {}", hit, found, files.len(), self.path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dwarf::LineRange;
+
+    #[test]
+    fn test_finish() {
+        let range = |range, file, line| LineRange { range, file, line };
+        let lines = LineTable {
+            files: vec![String::from("/src/a.c"), String::from("/src/b.c")],
+            // Line 3 of a.c has instructions in two places, e.g. a loop condition.
+            ranges: vec![
+                range(0x1000..0x1008, 0, 3),
+                range(0x1008..0x1010, 0, 4),
+                range(0x1010..0x1014, 0, 3),
+                range(0x2000..0x2004, 1, 1),
+            ],
+        };
+        let path = std::env::temp_dir().join(format!("r-riscv-coverage-{}", std::process::id()));
+        let mut coverage = Coverage::new(path.to_str().unwrap().to_string(), lines);
+        for pc in [0x1000, 0x1004, 0x1004, 0x1004, 0x2000, 0x2000] {
+            coverage.execute(pc);
+        }
+        for _ in 0..5 {
+            coverage.execute(0x1010);
+        }
+        coverage.finish().unwrap();
+
+        let expected = "TN:\n\
+            SF:/src/a.c\nDA:3,5\nDA:4,0\nLF:2\nLH:1\nend_of_record\n\
+            SF:/src/b.c\nDA:1,2\nLF:1\nLH:1\nend_of_record\n";
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,18 +1,31 @@
 #![allow(dead_code)]
 
 use std::mem::size_of;
-use crate::bus::Bus;
+use crate::bus::{Bus, Shutdown};
+use crate::coverage::Coverage;
+use crate::crash::History;
 use crate::csr::*;
 use crate::exception::Exception;
 use crate::interrupt::Interrupt;
-use crate::param::{DESC_NUM, DRAM_BASE, DRAM_END, PAGE_SIZE, PLIC_SCLAIM, SECTOR_SIZE, UART_IRQ, VIRTIO_IRQ};
+use crate::profile::Profiler;
+use crate::sbi::Sbi;
+use crate::semihosting::Semihosting;
+use crate::stats::Stats;
+#[cfg(unix)]
+use crate::syscall::Syscalls;
+use crate::trace::Tracer;
+use crate::param::{DESC_NUM, DRAM_END, PAGE_SIZE, PLIC_SCLAIM, ROM_BASE, RTC_IRQ, SECTOR_SIZE, UART_IRQ, VIRTIO_IRQ};
+use crate::watchpoint::{Access, Action, Hit, Watchpoint};
 use crate::virtio::{VirtioBlkRequest, VirtqAvail, VirtqDesc, VirtqUsed};
 
 // Riscv Privilege Mode
-type Mode = u64;
-const User: Mode = 0b00;
-const Supervisor: Mode = 0b01;
-const Machine: Mode = 0b11;
+pub type Mode = u64;
+pub const User: Mode = 0b00;
+pub const Supervisor: Mode = 0b01;
+pub const Machine: Mode = 0b11;
+
+// The encoding of ecall.
+const ECALL: u64 = 0x00000073;
 
 pub enum AccessType {
     Instruction,
@@ -38,28 +51,226 @@ pub struct CPU {
     pub enable_paging: bool,
     /// physical page number (PPN) × PAGE_SIZE (4096).
     pub page_table: u64,
+    /// Semihosting handler for ebreak calls, if enabled.
+    pub semihosting: Option<Semihosting>,
+    /// Built-in SBI serving the ecalls of an S-mode kernel, if enabled.
+    pub sbi: Option<Sbi>,
+    /// Linux syscalls of a program run in user mode, if enabled.
+    #[cfg(unix)]
+    pub syscalls: Option<Syscalls>,
+    /// Memory accesses to watch.
+    pub watchpoints: Vec<Watchpoint>,
+    /// The first access that matched a watchpoint of the debugger since it was last cleared.
+    pub watch_hit: Option<Hit>,
+    /// Whether an access set off a watchpoint that pauses the hart in the monitor.
+    pub watch_pause: bool,
+    /// The last pcs executed, for the report of a crash.
+    pub history: History,
+    /// Number of instructions retired since the emulator started.
+    pub instret: u64,
+    /// Commit log of the retired instructions, if written or checked in lockstep.
+    pub tracer: Option<Tracer>,
+    /// Counts of the retired instructions by function, if profiled.
+    pub profiler: Option<Profiler>,
+    /// Counts of the executed instructions, accesses and traps, if kept.
+    pub stats: Option<Stats>,
+    /// The pcs executed, for the source lines they cover, if recorded.
+    pub coverage: Option<Coverage>,
 }
 
-const RVABI: [&str; 32] = [
+pub const RVABI: [&str; 32] = [
     "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
     "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
     "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
     "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
 ];
 
+/// A register the monitor and gdb read and write: an integer register, the pc, a CSR of
+/// `CSR_NAMES` or the privilege mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    X(usize),
+    Pc,
+    Csr(usize),
+    Priv,
+}
+
+impl Register {
+    /// Return the register called `name`, e.g. a0, x10, fp, pc, priv or mstatus.
+    pub fn by_name(name: &str) -> Option<Register> {
+        match name {
+            "pc" => return Some(Register::Pc),
+            "priv" => return Some(Register::Priv),
+            "fp" => return Some(Register::X(8)),
+            _ => (),
+        }
+        if let Some(i) = RVABI.iter().position(|&r| r == name) {
+            return Some(Register::X(i));
+        }
+        if let Some(i) = name.strip_prefix('x').and_then(|i| i.parse::<usize>().ok()).filter(|&i| i < 32) {
+            return Some(Register::X(i));
+        }
+        CSR_NAMES.iter().find(|(csr, _)| *csr == name).map(|(_, addr)| Register::Csr(*addr))
+    }
+}
+
 impl CPU {
     /// Create a new `Cpu` object.
     pub fn new(code: Vec<u8>, disk_image: Vec<u8>) -> Self {
         let mut regs = [0; 32];
         regs[2] = DRAM_END;
-        let pc = DRAM_BASE;
+        let pc = ROM_BASE;
         let bus = Bus::new(code, disk_image);
         let csr = CSR::new();
         let mode = Machine;
         let page_table = 0;
         let enable_paging = false;
+        let semihosting = None;
+        let sbi = None;
+        let watchpoints = Vec::new();
+        let watch_hit = None;
+        let watch_pause = false;
+        let instret = 0;
+        let tracer = None;
+        let profiler = None;
+        let stats = None;
+        let coverage = None;
+        let history = History::default();
+
+        Self {
+            regs, pc, bus, csr, mode, page_table, enable_paging, semihosting, sbi,
+            #[cfg(unix)]
+            syscalls: None,
+            watchpoints, watch_hit, watch_pause, history, instret, tracer, profiler, stats, coverage,
+        }
+    }
+
+    /// Reset the hart and the devices to the power-on state. The dram is cleared, so the program
+    /// has to be loaded again.
+    pub fn reset(&mut self) {
+        self.regs = [0; 32];
+        self.regs[2] = DRAM_END;
+        self.pc = ROM_BASE;
+        self.mode = Machine;
+        self.csr = CSR::new();
+        self.page_table = 0;
+        self.enable_paging = false;
+        self.bus.reset();
+        if self.sbi.is_some() {
+            self.sbi = Some(Sbi::new());
+        }
+    }
+
+    /// Advance the devices by one executed instruction.
+    pub fn tick(&mut self) {
+        self.bus.tick();
+        self.csr.store(TIME, self.bus.mtime());
+    }
+
+    /// Execute one instruction, then take the trap it raised or a pending interrupt. Return the
+    /// exception if it is fatal, in which case the hart cannot go on.
+    pub fn step(&mut self) -> Result<(), Exception> {
+        self.tick();
+        let (pc, mode) = (self.pc, self.mode);
+        self.history.push(pc, mode);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.begin();
+        }
+        let decoded = self.fetch().and_then(|inst| {
+            if let Some(stats) = &mut self.stats {
+                stats.decode(inst);
+            }
+            if let Some(coverage) = &mut self.coverage {
+                coverage.execute(pc);
+            }
+            Ok((inst, self.execute(inst)?))
+        });
+        match decoded {
+            Ok((inst, new_pc)) => {
+                self.pc = new_pc;
+                self.retire(pc, mode, inst);
+            }
+            // A call the emulator serves returns to the next instruction, so the ecall retires.
+            Err(e) if self.is_served(e) => {
+                self.handle_exception(e);
+                self.retire(pc, mode, ECALL);
+            }
+            Err(e) => {
+                // Under the built-in SBI, the kernel handles the exceptions of its processes.
+                let delegated = self.sbi.is_some() && mode == User && self.csr.is_medelegated(e.code());
+                self.handle_exception(e);
+                if e.is_fatal() && !delegated {
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(interrupt) = self.check_pending_interrupt() {
+            self.handle_interrupt(interrupt);
+        }
+        Ok(())
+    }
 
-        Self {regs, pc, bus, csr, mode, page_table, enable_paging}
+    /// Count the instruction `inst` at `pc`, which has just retired in `mode`.
+    fn retire(&mut self, pc: u64, mode: Mode, inst: u64) {
+        if let Some(mut tracer) = self.tracer.take() {
+            match tracer.commit(self, pc, mode, inst) {
+                Ok(()) => self.tracer = Some(tracer),
+                Err(e) => println!("Cannot write the trace: {}", e),
+            }
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.retire(pc, inst);
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.retire(inst, pc, self.pc);
+        }
+        self.instret += 1;
+    }
+
+    /// Return true if the exception `e` is a call the emulator serves rather than a trap: a call
+    /// of an S-mode kernel under the built-in SBI, or a syscall of a program run in user mode.
+    fn is_served(&self, e: Exception) -> bool {
+        match e {
+            Exception::EnvironmentCallFromSMode(_) => self.sbi.is_some(),
+            #[cfg(unix)]
+            Exception::EnvironmentCallFromUMode(_) => self.syscalls.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Return the request of the guest to stop or restart the machine, if any.
+    pub fn shutdown(&mut self) -> Option<Shutdown> {
+        let request = self.bus.shutdown()
+            .or_else(|| self.semihosting.as_mut().and_then(|sh| sh.take_request()))
+            .or_else(|| self.sbi.as_mut().and_then(|sbi| sbi.take_request()));
+        #[cfg(unix)]
+        let request = request.or_else(|| self.syscalls.as_mut().and_then(|syscalls| syscalls.take_request()));
+        request.or_else(|| self.tracer.as_mut().and_then(|tracer| tracer.take_request()))
+    }
+
+    /// Write out the buffered trace, the profile, the statistics and the coverage, before the
+    /// emulator exits.
+    pub fn flush_outputs(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.flush() {
+                println!("Cannot write the trace: {}", e);
+            }
+        }
+        if let Some(profiler) = self.profiler.take() {
+            if let Err(e) = profiler.finish() {
+                println!("Cannot write the profile: {}", e);
+            }
+        }
+        if let Some(stats) = self.stats.take() {
+            if let Err(e) = stats.finish() {
+                println!("Cannot write the statistics: {}", e);
+            }
+        }
+        if let Some(coverage) = self.coverage.take() {
+            if let Err(e) = coverage.finish() {
+                println!("Cannot write the coverage: {}", e);
+            }
+        }
     }
 
     pub fn reg(&self, r: &str) -> u64 {
@@ -98,6 +309,30 @@ impl CPU {
         }
     }
 
+    /// Return the value of `register`.
+    pub fn read_register(&self, register: Register) -> u64 {
+        match register {
+            Register::X(0) => 0,
+            Register::X(i) => self.regs[i],
+            Register::Pc => self.pc,
+            Register::Csr(addr) => self.csr.load(addr),
+            Register::Priv => self.mode,
+        }
+    }
+
+    /// Set `register` to `value`. Return false if the value is not a privilege mode for `priv`.
+    pub fn write_register(&mut self, register: Register, value: u64) -> bool {
+        match register {
+            Register::X(0) => (),
+            Register::X(i) => self.regs[i] = value,
+            Register::Pc => self.pc = value,
+            Register::Csr(addr) => self.csr.store(addr, value),
+            Register::Priv if [User, Supervisor, Machine].contains(&value) => self.mode = value,
+            Register::Priv => return false,
+        }
+        true
+    }
+
     pub fn dump_pc(&self) {
         println!("{:-^80}", "PC register");
         println!("PC = {:#x}\n", self.pc);
@@ -142,6 +377,29 @@ impl CPU {
         // 5. set trap value properly (stval in S-mode, mtval in M-mode)
         // 6. set xPIE to xIE (SPIE in S-mode, MPIE in M-mode)
         // 7. clear up xIE (SIE in S-mode, MIE in M-mode)
+        if let Some(stats) = &mut self.stats {
+            stats.trap(e.code());
+        }
+        // The calls of an S-mode kernel are served by the built-in SBI, if there is one, and so
+        // are the syscalls of a program run in user mode.
+        if self.is_served(e) {
+            match e {
+                Exception::EnvironmentCallFromSMode(_) => {
+                    let mut sbi = self.sbi.take().unwrap();
+                    sbi.call(self);
+                    self.sbi = Some(sbi);
+                }
+                #[cfg(unix)]
+                _ => {
+                    let mut syscalls = self.syscalls.take().unwrap();
+                    syscalls.call(self);
+                    self.syscalls = Some(syscalls);
+                }
+                #[cfg(not(unix))]
+                _ => unreachable!(),
+            }
+            return;
+        }
         let pc = self.pc;
         let mode = self.mode;
         let cause = e.code();
@@ -195,6 +453,9 @@ impl CPU {
         let pc = self.pc;
         let mode = self.mode;
         let cause = interrupt.code();
+        if let Some(stats) = &mut self.stats {
+            stats.trap(cause);
+        }
         // although cause contains a interrupt bit. Shift the cause make it out.
         let trap_in_s_mode = mode <= Supervisor && self.csr.is_midelegated(cause);
         let (STATUS, TVEC, CAUSE, TVAL, EPC, MASK_PIE, pie_i, MASK_IE, ie_i, MASK_PP, pp_i)
@@ -259,6 +520,14 @@ impl CPU {
         // the following are true: (a) either the current privilege mode is M and the MIE bit in the mstatus
         // register is set, or the current privilege mode has less privilege than M-mode; (b) bit i is set in both
         // mip and mie; and (c) if register mideleg exists, bit i is not set in mideleg.
+        // The timer of the built-in SBI raises a supervisor timer interrupt, which is pending
+        // in sip whether or not it is enabled.
+        if let Some(sbi) = &mut self.sbi {
+            if sbi.is_timer_interrupting(self.csr.load(TIME)) {
+                self.csr.store(MIP, self.csr.load(MIP) | MASK_STIP);
+            }
+        }
+
         if (self.mode == Machine) && (self.csr.load(MSTATUS) & MASK_MIE) == 0 {
             return None;
         }
@@ -274,6 +543,9 @@ impl CPU {
             self.disk_access();
             self.bus.store(PLIC_SCLAIM, 32, VIRTIO_IRQ).unwrap();
             self.csr.store(MIP, self.csr.load(MIP) | MASK_SEIP);
+        } else if self.bus.rtc.is_interrupting() {
+            self.bus.store(PLIC_SCLAIM, 32, RTC_IRQ).unwrap();
+            self.csr.store(MIP, self.csr.load(MIP) | MASK_SEIP);
         }
 
         // 3.1.9 & 4.1.3
@@ -506,20 +778,87 @@ impl CPU {
     /// Load a value from a dram.
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         let p_addr = self.translate(addr, AccessType::Load)?;
-        self.bus.load(p_addr, size)
+        let value = self.bus.load(p_addr, size)?;
+        // A fault retries the access, so only the one that happens sets off a watchpoint.
+        if !self.watchpoints.is_empty() {
+            self.watch(Access::Read, addr, p_addr, size, value);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.access(addr, size, None);
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.access(size, false);
+        }
+        Ok(value)
     }
 
     /// Store a value to a dram.
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         let p_addr = self.translate(addr, AccessType::Store)?;
-        self.bus.store(p_addr, size, value)
+        self.bus.store(p_addr, size, value)?;
+        if !self.watchpoints.is_empty() {
+            self.watch(Access::Write, addr, p_addr, size, value);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.access(addr, size, Some(value));
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.access(size, true);
+        }
+        Ok(())
+    }
+
+    /// Copy the memory at the virtual address `addr` into `buf`, as the hart would see it but
+    /// without setting off watchpoints. This is meant for debuggers.
+    pub fn load_bytes(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let p_addr = self.translate(addr.wrapping_add(i as u64), AccessType::Load)?;
+            *byte = self.bus.load(p_addr, 8)? as u8;
+        }
+        Ok(())
+    }
+
+    /// Copy `data` to the virtual address `addr`, without setting off watchpoints.
+    pub fn store_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        for (i, &byte) in data.iter().enumerate() {
+            let p_addr = self.translate(addr.wrapping_add(i as u64), AccessType::Store)?;
+            self.bus.store(p_addr, 8, byte as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Act on the watchpoints `access` of `size` bits at the virtual address `addr`, which is the
+    /// physical address `p_addr`, with `value` sets off. The debugger gets the first access that
+    /// matched one of its watchpoints since it last took one.
+    fn watch(&mut self, access: Access, addr: u64, p_addr: u64, size: u64, value: u64) {
+        for (i, watchpoint) in self.watchpoints.iter().enumerate() {
+            if !watchpoint.matches(access, addr, p_addr, size, value) {
+                continue;
+            }
+            match watchpoint.action {
+                Action::Stop if self.watch_hit.is_none() => {
+                    self.watch_hit = Some(Hit { watchpoint: watchpoint.clone(), addr });
+                }
+                Action::Stop => (),
+                Action::Log | Action::Pause => {
+                    println!("Watchpoint {}: {} of {} bytes at {:#x} (physical {:#x}) = {:#x} by pc {:#x}",
+                             i, access, size / 8, addr, p_addr, value, self.pc);
+                    self.watch_pause |= watchpoint.action == Action::Pause;
+                }
+            }
+        }
     }
 
     /// Get an instruction from the dram.
     pub fn fetch(&mut self) -> Result<u64, Exception> {
         let p_pc = self.translate(self.pc, AccessType::Instruction)?;
         match self.bus.load(p_pc, 32) {
-            Ok(inst) => Ok(inst),
+            Ok(inst) => {
+                if !self.watchpoints.is_empty() {
+                    self.watch(Access::Fetch, self.pc, p_pc, 32, inst);
+                }
+                Ok(inst)
+            }
             Err(_e) => Err(Exception::InstructionAccessFault(self.pc)),
         }
     }
@@ -975,6 +1314,13 @@ impl CPU {
                             }
                             (0x1, 0x0) => {
                                 // ebreak
+                                // A semihosting call is served by the emulator itself.
+                                if self.semihosting.is_some() && Semihosting::is_call(self) {
+                                    let mut semihosting = self.semihosting.take().unwrap();
+                                    semihosting.call(self);
+                                    self.semihosting = Some(semihosting);
+                                    return self.update_pc();
+                                }
                                 // Makes a request of the debugger bu raising a Breakpoint exception.
                                 return Err(Exception::Breakpoint(self.pc));
                             }
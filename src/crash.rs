@@ -0,0 +1,252 @@
+//! The crash module explains a fatal exception, after which the hart cannot go on. The report has
+//! the faulting instruction, the pcs the hart executed before it, a backtrace, the memory around
+//! the faulting address and, when paging is on, the walk of the page table for the address.
+//!
+//! The backtrace follows the frame records the calling convention keeps when a program is built
+//! with `-fno-omit-frame-pointer`: s0 points above the record, which holds the return address at
+//! s0-8 and the s0 of the caller at s0-16. Without frame pointers, only ra is known.
+
+use crate::cpu::{AccessType, Mode, CPU};
+use crate::disasm::disassemble;
+use crate::elf::SymbolTable;
+use crate::exception::Exception;
+use crate::monitor::hexdump;
+use crate::param::PAGE_SIZE;
+
+/// The number of pcs kept.
+const HISTORY_LEN: usize = 32;
+/// The number of frames the backtrace goes up at most.
+const MAX_FRAMES: usize = 64;
+/// The bytes of memory printed before and after the faulting address.
+const MEMORY_WINDOW: u64 = 32;
+
+// The names of the privilege modes, by their encoding.
+const MODES: [&str; 4] = ["user", "supervisor", "reserved", "machine"];
+
+/// The pcs of the last instructions the hart executed, and the modes they ran in.
+#[derive(Default)]
+pub struct History {
+    entries: [(u64, Mode); HISTORY_LEN],
+    /// The number of instructions recorded.
+    len: u64,
+}
+
+impl History {
+    /// Record the instruction at `pc`, which is about to be executed in `mode`.
+    pub fn push(&mut self, pc: u64, mode: Mode) {
+        self.entries[(self.len % HISTORY_LEN as u64) as usize] = (pc, mode);
+        self.len += 1;
+    }
+
+    /// Return the instructions recorded, from the oldest.
+    fn iter(&self) -> impl Iterator<Item = (u64, Mode)> + '_ {
+        let start = self.len.saturating_sub(HISTORY_LEN as u64);
+        (start..self.len).map(|i| self.entries[(i % HISTORY_LEN as u64) as usize])
+    }
+
+    fn last(&self) -> Option<(u64, Mode)> {
+        self.iter().last()
+    }
+}
+
+/// Print the report of the fatal exception `e`, which the hart has just taken.
+pub fn report(cpu: &mut CPU, e: Exception, symbols: &SymbolTable) {
+    let (pc, mode) = cpu.history.last().unwrap_or((cpu.pc, cpu.mode));
+    // The virtual address the exception is about, or the instruction for an illegal one. The
+    // value of an access fault is the physical address, so the address is computed again from
+    // the registers of the access, which it did not change.
+    let addr = match e {
+        Exception::IllegalInstruction(_) => pc,
+        Exception::InstructionAddrMisaligned(addr) | Exception::InstructionAccessFault(addr) => addr,
+        _ => read_u32(cpu, pc).and_then(|inst| access_addr(cpu, inst)).unwrap_or(e.value()),
+    };
+
+    println!("{:-^80}", "crash");
+    println!("{} in {} mode in {}", e, MODES[mode as usize], location(symbols, pc));
+    instruction(cpu, pc);
+    println!();
+
+    println!("{:-^80}", "recent instructions");
+    for (pc, mode) in cpu.history.iter().collect::<Vec<_>>() {
+        print!("{} ", MODES[mode as usize].chars().next().unwrap());
+        instruction(cpu, pc);
+    }
+    println!();
+
+    println!("{:-^80}", "backtrace");
+    println!("#0  {:#018x} in {}", pc, location(symbols, pc));
+    for (i, ra) in backtrace(cpu).into_iter().enumerate() {
+        println!("#{:<2} {:#018x} in {}", i + 1, ra, location(symbols, ra));
+    }
+    println!();
+
+    println!("{:-^80}", "memory");
+    let start = (addr & !0xf).wrapping_sub(MEMORY_WINDOW);
+    for line in (0..2 * MEMORY_WINDOW + 16).step_by(16) {
+        let line = start.wrapping_add(line);
+        let mut bytes = [0; 16];
+        match peek(cpu, line, &mut bytes) {
+            Ok(()) => hexdump(line, &bytes),
+            Err(e) => println!("{:#018x}: {}", line, e),
+        }
+    }
+    println!();
+
+    if cpu.enable_paging {
+        println!("{:-^80}", "translation");
+        walk(cpu, addr);
+        println!();
+    }
+}
+
+/// Return the function `addr` is in and the offset into it.
+fn location(symbols: &SymbolTable, addr: u64) -> String {
+    match symbols.function_at(addr) {
+        Some(symbol) => format!("{}+{:#x}", symbol.name, addr - symbol.addr),
+        None => String::from("??"),
+    }
+}
+
+/// Print the instruction at `pc`.
+fn instruction(cpu: &mut CPU, pc: u64) {
+    let mut inst = [0; 4];
+    match cpu.load_bytes(pc, &mut inst) {
+        Ok(()) => {
+            let inst = u32::from_le_bytes(inst);
+            println!("{:#018x}: {:08x}  {}", pc, inst, disassemble(inst as u64, pc));
+        }
+        Err(e) => println!("{:#018x}: {}", pc, e),
+    }
+}
+
+/// Return the virtual address the load, store or atomic `inst` accesses.
+fn access_addr(cpu: &CPU, inst: u64) -> Option<u64> {
+    let base = cpu.regs[((inst >> 15) & 0x1f) as usize];
+    let offset = match inst & 0x7f {
+        // Loads.
+        0x03 | 0x07 => (inst as i32 >> 20) as i64,
+        // Stores.
+        0x23 | 0x27 => (((inst as i32 >> 25) << 5) | ((inst >> 7) & 0x1f) as i32) as i64,
+        // Atomics.
+        0x2f => 0,
+        _ => return None,
+    };
+    Some(base.wrapping_add_signed(offset))
+}
+
+/// Return the return addresses of the calls that led to the current function, innermost first.
+fn backtrace(cpu: &mut CPU) -> Vec<u64> {
+    let mut frames = Vec::new();
+    let mut fp = cpu.regs[8];
+    while frames.len() < MAX_FRAMES && fp != 0 && fp.is_multiple_of(8) {
+        let (Some(ra), Some(caller)) = (read_u64(cpu, fp.wrapping_sub(8)), read_u64(cpu, fp.wrapping_sub(16))) else {
+            break;
+        };
+        if ra == 0 {
+            break;
+        }
+        frames.push(ra);
+        // The stack grows down, so the frame of the caller is above.
+        if caller <= fp {
+            break;
+        }
+        fp = caller;
+    }
+    if frames.is_empty() && cpu.regs[1] != 0 {
+        frames.push(cpu.regs[1]);
+    }
+    frames
+}
+
+fn read_u32(cpu: &mut CPU, addr: u64) -> Option<u64> {
+    let mut bytes = [0; 4];
+    cpu.load_bytes(addr, &mut bytes).ok()?;
+    Some(u32::from_le_bytes(bytes) as u64)
+}
+
+fn read_u64(cpu: &mut CPU, addr: u64) -> Option<u64> {
+    let mut bytes = [0; 8];
+    peek(cpu, addr, &mut bytes).ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Copy the memory at the virtual address `addr` into `buf`, which does not cross a page. Only the
+/// dram is read, since a faulting address or a broken frame pointer may well point at a device,
+/// which a read would change.
+fn peek(cpu: &mut CPU, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+    let addr = cpu.translate(addr, AccessType::Load)?;
+    cpu.bus.load_bytes(addr, buf)
+}
+
+/// Print the page table entries the Sv39 translation of `addr` goes through.
+fn walk(cpu: &mut CPU, addr: u64) {
+    println!("{:#x} through the page table at {:#x}", addr, cpu.page_table);
+    let mut table = cpu.page_table;
+    for level in (0..3).rev() {
+        let pte_addr = table + ((addr >> (12 + 9 * level)) & 0x1ff) * 8;
+        let pte = match cpu.bus.load(pte_addr, 64) {
+            Ok(pte) => pte,
+            Err(e) => {
+                println!("level {}: pte at {:#x}: {}", level, pte_addr, e);
+                return;
+            }
+        };
+        let flags: String = "vrwxugad".chars().enumerate()
+            .map(|(bit, flag)| if (pte >> bit) & 1 == 1 { flag } else { '-' })
+            .collect();
+        println!("level {}: pte at {:#x} = {:#018x} {}", level, pte_addr, pte, flags);
+        let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
+        if pte & 1 == 0 || pte & 0b110 == 0b100 {
+            println!("invalid entry");
+            return;
+        }
+        if pte & 0b1010 != 0 {
+            let offset = (1 << (12 + 9 * level)) - 1;
+            println!("leaf: physical address {:#x}", ((ppn << 12) & !offset) | (addr & offset));
+            return;
+        }
+        table = ppn * PAGE_SIZE;
+    }
+    println!("no leaf entry");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{Machine, User};
+    use crate::param::{DRAM_BASE, UART_BASE};
+
+    #[test]
+    fn test_history() {
+        let mut history = History::default();
+        assert_eq!(history.last(), None);
+        for i in 0..HISTORY_LEN as u64 + 5 {
+            history.push(DRAM_BASE + i * 4, if i % 2 == 0 { Machine } else { User });
+        }
+        let entries: Vec<_> = history.iter().collect();
+        assert_eq!(entries.len(), HISTORY_LEN);
+        assert_eq!(entries[0], (DRAM_BASE + 5 * 4, User));
+        assert_eq!(history.last(), Some((DRAM_BASE + (HISTORY_LEN as u64 + 4) * 4, Machine)));
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        // main calls f, which calls g: each record holds the return address and the caller's s0.
+        let (g, f, main) = (DRAM_BASE + 0x8000, DRAM_BASE + 0x8040, DRAM_BASE + 0x8080);
+        let frames: [(u64, u64, u64); 3] = [(g, 0x8000_1004, f), (f, 0x8000_1008, main), (main, 0x8000_100c, 0)];
+        for (fp, ra, caller) in frames {
+            cpu.store_bytes(fp - 8, &ra.to_le_bytes()).unwrap();
+            cpu.store_bytes(fp - 16, &caller.to_le_bytes()).unwrap();
+        }
+        cpu.regs[1] = 0x8000_1004;
+        cpu.regs[8] = g;
+        assert_eq!(backtrace(&mut cpu), vec![0x8000_1004, 0x8000_1008, 0x8000_100c]);
+
+        // Without frame pointers, or with one that points at a device, only ra is known.
+        cpu.regs[8] = 0;
+        assert_eq!(backtrace(&mut cpu), vec![0x8000_1004]);
+        cpu.regs[8] = UART_BASE + 16;
+        assert_eq!(backtrace(&mut cpu), vec![0x8000_1004]);
+    }
+}
@@ -1,6 +1,11 @@
 #![allow(dead_code)]
 
+use std::io;
+use crate::snapshot::{invalid, Reader, Writer};
+
 pub const MHARTID: usize = 0xf14;
+// Read-only copy of the mtime register of the CLINT.
+pub const TIME: usize = 0xc01;
 /// Machine status register.
 pub const MSTATUS: usize = 0x300;
 /// Machine exception delefation register.
@@ -44,6 +49,15 @@ pub const SIP: usize = 0x144;
 /// Supervisor address translation and protection.
 pub const SATP: usize = 0x180;
 
+/// Names of the CSRs above, as the assembler knows them.
+pub const CSR_NAMES: [(&str, usize); 22] = [
+    ("sstatus", SSTATUS), ("sie", SIE), ("stvec", STVEC), ("sscratch", SSCRATCH),
+    ("sepc", SEPC), ("scause", SCAUSE), ("stval", STVAL), ("sip", SIP), ("satp", SATP),
+    ("time", TIME), ("mhartid", MHARTID), ("mstatus", MSTATUS), ("medeleg", MEDELEG),
+    ("mideleg", MIDELEG), ("mie", MIE), ("mtvec", MTVEC), ("mcounteren", MCOUNTEREN),
+    ("mscratch", MSCRATCH), ("mepc", MEPC), ("mcause", MCAUSE), ("mtval", MTVAL), ("mip", MIP),
+];
+
 // mstatus and sstatus field mask
 pub const MASK_SIE: u64 = 1 << 1;
 pub const MASK_MIE: u64 = 1 << 3;
@@ -109,6 +123,25 @@ impl CSR {
         }
     }
 
+    /// Save the CSRs that are set.
+    pub fn save(&self, w: &mut Writer) {
+        let set: Vec<(usize, u64)> = self.csrs.iter().copied().enumerate().filter(|&(_, v)| v != 0).collect();
+        w.u32(set.len() as u32);
+        for (addr, value) in set {
+            w.u32(addr as u32);
+            w.u64(value);
+        }
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.csrs = [0; NUM_CSRS];
+        for _ in 0..r.u32()? {
+            let addr = r.u32()? as usize;
+            *self.csrs.get_mut(addr).ok_or_else(|| invalid("invalid CSR in snapshot"))? = r.u64()?;
+        }
+        Ok(())
+    }
+
     pub fn dump_csrs(&self) {
         println!("{:-^80}", "control status registers");
         let output = format!(
@@ -0,0 +1,135 @@
+//! The devicetree module describes the emulated machine to the guest. The layout follows the
+//! device tree of QEMU's virt machine, so that kernels built for it boot unchanged.
+
+use crate::bus::Bus;
+use crate::fdt::{Fdt, Node};
+use crate::interrupt::{Interrupt, MASK_INTERRUPT_BIT};
+use crate::param::*;
+
+// Handles that let nodes refer to each other.
+const CPU_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+const SYSCON_PHANDLE: u32 = 3;
+
+/// Build the device tree of the machine behind `bus`.
+pub fn generate(bus: &Bus) -> Fdt {
+    let mut root = Node::new("");
+    root.set_cells("#address-cells", &[2]);
+    root.set_cells("#size-cells", &[2]);
+    root.set_str("compatible", "riscv-virtio");
+    root.set_str("model", "riscv-virtio,rrve");
+
+    let chosen = root.child_mut("chosen");
+    let stdout = format!("/soc/serial@{:x}", UART_BASE);
+    chosen.set_str("stdout-path", &stdout);
+
+    let memory = root.child_mut(&format!("memory@{:x}", DRAM_BASE));
+    memory.set_str("device_type", "memory");
+    memory.set_cells("reg", &reg(DRAM_BASE, DRAM_SIZE));
+
+    let cpus = root.child_mut("cpus");
+    cpus.set_cells("#address-cells", &[1]);
+    cpus.set_cells("#size-cells", &[0]);
+    cpus.set_cells("timebase-frequency", &[TIMEBASE_FREQ as u32]);
+    let cpu = cpus.child_mut("cpu@0");
+    cpu.set_str("device_type", "cpu");
+    cpu.set_cells("reg", &[0]);
+    cpu.set_str("status", "okay");
+    cpu.set_str("compatible", "riscv");
+    cpu.set_str("riscv,isa", RISCV_ISA);
+    cpu.set_str("mmu-type", "riscv,sv39");
+    let intc = cpu.child_mut("interrupt-controller");
+    intc.set_cells("#interrupt-cells", &[1]);
+    intc.set_flag("interrupt-controller");
+    intc.set_str("compatible", "riscv,cpu-intc");
+    intc.set_cells("phandle", &[CPU_INTC_PHANDLE]);
+
+    let soc = root.child_mut("soc");
+    soc.set_cells("#address-cells", &[2]);
+    soc.set_cells("#size-cells", &[2]);
+    soc.set_str("compatible", "simple-bus");
+    soc.set_flag("ranges");
+
+    let syscon = soc.child_mut(&format!("test@{:x}", SYSCON_BASE));
+    syscon.set_strs("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+    syscon.set_cells("reg", &reg(SYSCON_BASE, SYSCON_SIZE));
+    syscon.set_cells("phandle", &[SYSCON_PHANDLE]);
+    for (name, value) in [("poweroff", SYSCON_PASS), ("reboot", SYSCON_RESET)] {
+        let node = soc.child_mut(name);
+        node.set_str("compatible", &format!("syscon-{}", name));
+        node.set_cells("regmap", &[SYSCON_PHANDLE]);
+        node.set_cells("offset", &[0]);
+        node.set_cells("value", &[value as u32]);
+    }
+
+    let rtc = device(soc, "rtc", RTC_BASE, RTC_SIZE, RTC_IRQ);
+    rtc.set_str("compatible", "google,goldfish-rtc");
+
+    let uart = device(soc, "serial", UART_BASE, UART_SIZE, UART_IRQ);
+    uart.set_str("compatible", "ns16550a");
+    uart.set_cells("clock-frequency", &[UART_CLOCK_FREQ as u32]);
+
+    let virtio = device(soc, "virtio_mmio", VIRTIO_BASE, VIRTIO_SIZE, VIRTIO_IRQ);
+    virtio.set_str("compatible", "virtio,mmio");
+
+    // The interrupt numbers of the hart the controllers are wired to.
+    let code = |i: Interrupt| (i.code() & !MASK_INTERRUPT_BIT) as u32;
+
+    let plic = soc.child_mut(&format!("plic@{:x}", PLIC_BASE));
+    plic.set_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    plic.set_cells("reg", &reg(PLIC_BASE, PLIC_SIZE));
+    plic.set_cells("#address-cells", &[0]);
+    plic.set_cells("#interrupt-cells", &[1]);
+    plic.set_flag("interrupt-controller");
+    plic.set_cells("interrupts-extended", &[
+        CPU_INTC_PHANDLE, code(Interrupt::MachineExternalInterrupt),
+        CPU_INTC_PHANDLE, code(Interrupt::SupervisorExternalInterrupt),
+    ]);
+    plic.set_cells("riscv,ndev", &[PLIC_NDEV as u32]);
+    plic.set_cells("phandle", &[PLIC_PHANDLE]);
+
+    let clint = soc.child_mut(&format!("clint@{:x}", CLINT_BASE));
+    clint.set_strs("compatible", &["sifive,clint0", "riscv,clint0"]);
+    clint.set_cells("reg", &reg(CLINT_BASE, CLINT_SIZE));
+    clint.set_cells("interrupts-extended", &[
+        CPU_INTC_PHANDLE, code(Interrupt::MachineSoftwareInterrupt),
+        CPU_INTC_PHANDLE, code(Interrupt::MachineTimerInterrupt),
+    ]);
+
+    if let Some(htif) = bus.htif() {
+        let node = root.child_mut("htif");
+        node.set_str("compatible", "ucb,htif0");
+        node.set_u64("tohost", htif.tohost());
+    }
+
+    Fdt { root, reserved: Vec::new(), boot_cpuid: 0 }
+}
+
+/// Add the node of a device at `base` that raises interrupt `irq` through the PLIC.
+fn device<'a>(soc: &'a mut Node, name: &str, base: u64, size: u64, irq: u64) -> &'a mut Node {
+    let node = soc.child_mut(&format!("{}@{:x}", name, base));
+    node.set_cells("reg", &reg(base, size));
+    node.set_cells("interrupts", &[irq as u32]);
+    node.set_cells("interrupt-parent", &[PLIC_PHANDLE]);
+    node
+}
+
+/// Return the cells of a `reg` property with two address and two size cells.
+fn reg(base: u64, size: u64) -> [u32; 4] {
+    [(base >> 32) as u32, base as u32, (size >> 32) as u32, size as u32]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let fdt = generate(&Bus::new(Vec::new(), Vec::new()));
+        assert_eq!(Fdt::parse(&fdt.to_bytes()).unwrap(), fdt);
+        let memory = fdt.root.children.iter().find(|c| c.name == "memory@80000000").unwrap();
+        let reg = memory.props.iter().find(|(n, _)| n == "reg").unwrap();
+        assert_eq!(reg.1, [0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x08, 0, 0, 0]);
+        assert!(fdt.root.children.iter().all(|c| c.name != "htif"));
+    }
+}
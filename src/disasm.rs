@@ -0,0 +1,230 @@
+//! The disasm module turns instructions back into assembly. It knows exactly the encodings that
+//! `CPU::execute` decodes, so anything else is reported as unknown, as the hart would raise an
+//! illegal instruction exception for it. Registers get their ABI names and CSRs their names.
+
+use crate::cpu::RVABI;
+use crate::csr::CSR_NAMES;
+
+/// The instructions `CPU::execute` decodes, in the order of its arms.
+pub const MNEMONICS: [&str; 68] = [
+    "lb", "lh", "lw", "ld", "lbu", "lhu", "lwu", "fence",
+    "addi", "slli", "slti", "sltiu", "xori", "srli", "srai", "ori", "andi", "auipc",
+    "addiw", "slliw", "srliw", "sraiw", "sb", "sh", "sw", "sd",
+    "amoadd.w", "amoadd.d", "amoswap.w", "amoswap.d",
+    "add", "mul", "sub", "sll", "slt", "sltu", "xor", "srl", "sra", "or", "and", "lui",
+    "addw", "subw", "sllw", "srlw", "divuw", "sraw", "remuw",
+    "beq", "bne", "blt", "bge", "bltu", "bgeu", "jalr", "jal",
+    "ecall", "ebreak", "sret", "mret", "sfence.vma",
+    "csrrw", "csrrs", "csrrc", "csrrwi", "csrrsi", "csrrci",
+];
+
+/// Return the assembly of `inst`, which is at `pc`. Branch and jump targets are absolute.
+pub fn disassemble(inst: u64, pc: u64) -> String {
+    let inst = inst as u32;
+    let opcode = inst & 0x7f;
+    let rd = RVABI[((inst >> 7) & 0x1f) as usize];
+    let rs1 = RVABI[((inst >> 15) & 0x1f) as usize];
+    let rs2 = RVABI[((inst >> 20) & 0x1f) as usize];
+    let funct3 = (inst >> 12) & 0x7;
+    let funct7 = inst >> 25;
+    // The immediates of the I, S, B, U and J formats, sign-extended.
+    let i_imm = (inst as i32 as i64) >> 20;
+    let s_imm = ((inst & 0xfe000000) as i32 as i64 >> 20) | ((inst >> 7) & 0x1f) as i64;
+    let b_imm = ((inst & 0x80000000) as i32 as i64 >> 19)
+        | ((inst & 0x80) << 4) as i64
+        | ((inst >> 20) & 0x7e0) as i64
+        | ((inst >> 7) & 0x1e) as i64;
+    let u_imm = inst >> 12;
+    let j_imm = ((inst & 0x80000000) as i32 as i64 >> 11)
+        | (inst & 0xff000) as i64
+        | ((inst >> 9) & 0x800) as i64
+        | ((inst >> 20) & 0x7fe) as i64;
+    let target = |imm: i64| pc.wrapping_add(imm as u64);
+
+    let (name, operands) = match opcode {
+        0x03 => {
+            let name = match funct3 {
+                0x0 => "lb",
+                0x1 => "lh",
+                0x2 => "lw",
+                0x3 => "ld",
+                0x4 => "lbu",
+                0x5 => "lhu",
+                0x6 => "lwu",
+                _ => return unknown(inst),
+            };
+            (name, format!("{}, {}({})", rd, i_imm, rs1))
+        }
+        0x0f if funct3 == 0 => ("fence", String::new()),
+        0x13 => {
+            let shamt = i_imm & 0x3f;
+            match funct3 {
+                0x0 => ("addi", format!("{}, {}, {}", rd, rs1, i_imm)),
+                0x1 => ("slli", format!("{}, {}, {}", rd, rs1, shamt)),
+                0x2 => ("slti", format!("{}, {}, {}", rd, rs1, i_imm)),
+                0x3 => ("sltiu", format!("{}, {}, {}", rd, rs1, i_imm)),
+                0x4 => ("xori", format!("{}, {}, {}", rd, rs1, i_imm)),
+                0x5 if funct7 >> 1 == 0x00 => ("srli", format!("{}, {}, {}", rd, rs1, shamt)),
+                0x5 if funct7 >> 1 == 0x10 => ("srai", format!("{}, {}, {}", rd, rs1, shamt)),
+                0x6 => ("ori", format!("{}, {}, {}", rd, rs1, i_imm)),
+                0x7 => ("andi", format!("{}, {}, {}", rd, rs1, i_imm)),
+                _ => return unknown(inst),
+            }
+        }
+        0x17 => ("auipc", format!("{}, {:#x}", rd, u_imm)),
+        0x1b => {
+            let shamt = i_imm & 0x1f;
+            match (funct3, funct7) {
+                (0x0, _) => ("addiw", format!("{}, {}, {}", rd, rs1, i_imm)),
+                (0x1, _) => ("slliw", format!("{}, {}, {}", rd, rs1, shamt)),
+                (0x5, 0x00) => ("srliw", format!("{}, {}, {}", rd, rs1, shamt)),
+                (0x5, 0x20) => ("sraiw", format!("{}, {}, {}", rd, rs1, shamt)),
+                _ => return unknown(inst),
+            }
+        }
+        0x23 => {
+            let name = match funct3 {
+                0x0 => "sb",
+                0x1 => "sh",
+                0x2 => "sw",
+                0x3 => "sd",
+                _ => return unknown(inst),
+            };
+            (name, format!("{}, {}({})", rs2, s_imm, rs1))
+        }
+        0x2f => {
+            let name = match (funct3, funct7 >> 2) {
+                (0x2, 0x00) => "amoadd.w",
+                (0x3, 0x00) => "amoadd.d",
+                (0x2, 0x01) => "amoswap.w",
+                (0x3, 0x01) => "amoswap.d",
+                _ => return unknown(inst),
+            };
+            let ordering = match funct7 & 0b11 {
+                0b11 => ".aqrl",
+                0b10 => ".aq",
+                0b01 => ".rl",
+                _ => "",
+            };
+            return format!("{:<7} {}, {}, ({})", format!("{}{}", name, ordering), rd, rs2, rs1);
+        }
+        0x33 => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x00) => "add",
+                (0x0, 0x01) => "mul",
+                (0x0, 0x20) => "sub",
+                (0x1, 0x00) => "sll",
+                (0x2, 0x00) => "slt",
+                (0x3, 0x00) => "sltu",
+                (0x4, 0x00) => "xor",
+                (0x5, 0x00) => "srl",
+                (0x5, 0x20) => "sra",
+                (0x6, 0x00) => "or",
+                (0x7, 0x00) => "and",
+                _ => return unknown(inst),
+            };
+            (name, format!("{}, {}, {}", rd, rs1, rs2))
+        }
+        0x37 => ("lui", format!("{}, {:#x}", rd, u_imm)),
+        0x3b => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x00) => "addw",
+                (0x0, 0x20) => "subw",
+                (0x1, 0x00) => "sllw",
+                (0x5, 0x00) => "srlw",
+                (0x5, 0x01) => "divuw",
+                (0x5, 0x20) => "sraw",
+                (0x7, 0x01) => "remuw",
+                _ => return unknown(inst),
+            };
+            (name, format!("{}, {}, {}", rd, rs1, rs2))
+        }
+        0x63 => {
+            let name = match funct3 {
+                0x0 => "beq",
+                0x1 => "bne",
+                0x4 => "blt",
+                0x5 => "bge",
+                0x6 => "bltu",
+                0x7 => "bgeu",
+                _ => return unknown(inst),
+            };
+            (name, format!("{}, {}, {:#x}", rs1, rs2, target(b_imm)))
+        }
+        0x67 => ("jalr", format!("{}, {}({})", rd, i_imm, rs1)),
+        0x6f => ("jal", format!("{}, {:#x}", rd, target(j_imm))),
+        0x73 => {
+            let csr = csr_name((inst >> 20) as usize);
+            let zimm = (inst >> 15) & 0x1f;
+            match funct3 {
+                0x0 => match ((inst >> 20) & 0x1f, funct7) {
+                    (0x0, 0x0) => ("ecall", String::new()),
+                    (0x1, 0x0) => ("ebreak", String::new()),
+                    (0x2, 0x8) => ("sret", String::new()),
+                    (0x2, 0x18) => ("mret", String::new()),
+                    (_, 0x9) => ("sfence.vma", format!("{}, {}", rs1, rs2)),
+                    _ => return unknown(inst),
+                },
+                0x1 => ("csrrw", format!("{}, {}, {}", rd, csr, rs1)),
+                0x2 => ("csrrs", format!("{}, {}, {}", rd, csr, rs1)),
+                0x3 => ("csrrc", format!("{}, {}, {}", rd, csr, rs1)),
+                0x5 => ("csrrwi", format!("{}, {}, {}", rd, csr, zimm)),
+                0x6 => ("csrrsi", format!("{}, {}, {}", rd, csr, zimm)),
+                0x7 => ("csrrci", format!("{}, {}, {}", rd, csr, zimm)),
+                _ => return unknown(inst),
+            }
+        }
+        _ => return unknown(inst),
+    };
+    match operands.is_empty() {
+        true => String::from(name),
+        false => format!("{:<7} {}", name, operands),
+    }
+}
+
+/// Return the name of `inst` as in `MNEMONICS`, without the ordering of an atomic, or None if it
+/// is unknown.
+pub fn mnemonic(inst: u64) -> Option<&'static str> {
+    let asm = disassemble(inst, 0);
+    let name = asm.split_whitespace().next().unwrap();
+    let name = [".aqrl", ".aq", ".rl"].iter().find_map(|s| name.strip_suffix(s)).unwrap_or(name);
+    MNEMONICS.iter().find(|&&m| m == name).copied()
+}
+
+fn unknown(inst: u32) -> String {
+    format!("unknown {:#010x}", inst)
+}
+
+fn csr_name(addr: usize) -> String {
+    match CSR_NAMES.iter().find(|(_, csr)| *csr == addr) {
+        Some((name, _)) => String::from(*name),
+        None => format!("{:#x}", addr),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(0x00000297, 0x1000), "auipc   t0, 0x0");
+        assert_eq!(disassemble(0xf1402573, 0x1004), "csrrs   a0, mhartid, zero");
+        assert_eq!(disassemble(0x0202b583, 0x1008), "ld      a1, 32(t0)");
+        assert_eq!(disassemble(0x00028067, 0x1010), "jalr    zero, 0(t0)");
+        assert_eq!(disassemble(0xfe0a8ce3, 0x80000020), "beq     s5, zero, 0x80000018");
+        assert_eq!(disassemble(0x0000006f, 0x80000010), "jal     zero, 0x80000010");
+        assert_eq!(disassemble(0xfea43423, 0), "sd      a0, -24(s0)");
+        assert_eq!(disassemble(0x40b50533, 0), "sub     a0, a0, a1");
+        assert_eq!(disassemble(0x0cb5252f, 0), "amoswap.w.aq a0, a1, (a0)");
+        assert_eq!(disassemble(0x4035d51b, 0), "sraiw   a0, a1, 3");
+        assert_eq!(disassemble(0x4285d513, 0), "srai    a0, a1, 40");
+        assert_eq!(disassemble(0x7c02d573, 0), "csrrwi  a0, 0x7c0, 5");
+        assert_eq!(disassemble(0x30200073, 0), "mret");
+        // wfi is not implemented.
+        assert_eq!(disassemble(0x10500073, 0), "unknown 0x10500073");
+        assert_eq!(mnemonic(0x0cb5252f), Some("amoswap.w"));
+        assert_eq!(mnemonic(0x4285d513), Some("srai"));
+        assert_eq!(mnemonic(0x10500073), None);
+    }
+}
@@ -1,9 +1,17 @@
+use std::collections::HashMap;
+use std::io;
 use crate::exception::Exception;
 use crate::exception::Exception::{LoadAccessFault, StoreAMOAccessFault};
-use crate::param::{DRAM_BASE, DRAM_SIZE};
+use crate::param::{DRAM_BASE, DRAM_SIZE, PAGE_SIZE};
+use crate::snapshot::{invalid, Reader, Writer};
+
+/// The pages of a journal, by index, as they were when it started.
+pub type Journal = HashMap<usize, Box<[u8]>>;
 
 pub struct Dram {
     pub dram: Vec<u8>,
+    /// The pages changed since the journal was started, saved before their first change.
+    journal: Option<Journal>,
 }
 
 
@@ -12,7 +20,60 @@ impl Dram {
     pub fn new(code: Vec<u8>) -> Dram {
         let mut dram = vec![0; DRAM_SIZE as usize];
         dram.splice(..code.len(), code.into_iter());
-        Self { dram }
+        Self { dram, journal: None }
+    }
+
+    /// Start keeping the pages as they are now before they change, and return the journal
+    /// kept so far.
+    pub fn take_journal(&mut self) -> Journal {
+        self.journal.replace(HashMap::new()).unwrap_or_default()
+    }
+
+    /// Put back the pages saved in `journal`.
+    pub fn undo(&mut self, journal: Journal) {
+        for (i, page) in journal {
+            let start = i * PAGE_SIZE as usize;
+            self.dram[start..start + PAGE_SIZE as usize].copy_from_slice(&page);
+        }
+    }
+
+    /// Save the pages covering `len` bytes at `index` in the journal, if they are not yet.
+    fn touch(&mut self, index: usize, len: usize) {
+        let Some(journal) = &mut self.journal else { return };
+        let page_size = PAGE_SIZE as usize;
+        for i in index / page_size..=(index + len.max(1) - 1) / page_size {
+            journal.entry(i).or_insert_with(|| self.dram[i * page_size..(i + 1) * page_size].into());
+        }
+    }
+
+    /// Copy `data` to `index`, which must be valid.
+    pub fn write(&mut self, index: usize, data: &[u8]) {
+        self.touch(index, data.len());
+        self.dram[index..index + data.len()].copy_from_slice(data);
+    }
+
+    /// Save the pages that are not all zeros, compressed.
+    pub fn save(&self, w: &mut Writer) {
+        let pages: Vec<(usize, &[u8])> = self.dram.chunks(PAGE_SIZE as usize)
+            .enumerate()
+            .filter(|(_, page)| page.iter().any(|&b| b != 0))
+            .collect();
+        w.u32(pages.len() as u32);
+        for (i, page) in pages {
+            w.u32(i as u32);
+            w.compressed(page);
+        }
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.dram.fill(0);
+        for _ in 0..r.u32()? {
+            let start = r.u32()? as usize * PAGE_SIZE as usize;
+            let page = self.dram.get_mut(start..start + PAGE_SIZE as usize)
+                .ok_or_else(|| invalid("invalid page in snapshot"))?;
+            r.compressed(page)?;
+        }
+        Ok(())
     }
 
     // addr/size must be valid
@@ -36,6 +97,7 @@ impl Dram {
         }
         let bytes = size / 8;
         let index = (addr - DRAM_BASE) as usize;
+        self.touch(index, bytes as usize);
         for i in 0..bytes {
             let offset = 8 * i as usize;
             self.dram[index + i as usize] = ((value >> offset) & 0xff) as u8;
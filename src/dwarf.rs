@@ -0,0 +1,406 @@
+//! The dwarf module reads the line tables of the DWARF debugging information, which map the
+//! addresses of a program back to the lines of its source. Versions 2 to 5 of the `.debug_line`
+//! section are understood.
+//! The format is described in https://dwarfstd.org/doc/DWARF5.pdf, section 6.2.
+
+use std::io;
+use std::ops::Range;
+
+// Standard opcodes.
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+// Extended opcodes.
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+// Content types of the directory and file entries of version 5.
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+// Forms of the directory and file entries of version 5.
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+/// The addresses of the instructions of a line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineRange {
+    pub range: Range<u64>,
+    /// The index of the source file in `LineTable::files`.
+    pub file: usize,
+    pub line: u64,
+}
+
+/// The lines of the source files of a program.
+#[derive(Debug, Default)]
+pub struct LineTable {
+    /// The paths of the source files.
+    pub files: Vec<String>,
+    pub ranges: Vec<LineRange>,
+}
+
+/// The sections the line table is read from. The string sections are only used by version 5.
+pub struct Sections<'a> {
+    pub debug_line: &'a [u8],
+    pub debug_line_str: &'a [u8],
+    pub debug_str: &'a [u8],
+}
+
+impl LineTable {
+    /// Read the line tables of every compilation unit in `sections`.
+    pub fn parse(sections: &Sections) -> io::Result<LineTable> {
+        let mut table = LineTable::default();
+        let mut cursor = Cursor { data: sections.debug_line, pos: 0 };
+        while cursor.pos < sections.debug_line.len() {
+            table.parse_unit(sections, &mut cursor)?;
+        }
+        Ok(table)
+    }
+
+    fn parse_unit(&mut self, sections: &Sections, cursor: &mut Cursor) -> io::Result<()> {
+        let (length, dwarf64) = match cursor.u32()? {
+            0xffff_ffff => (cursor.u64()? as usize, true),
+            length => (length as usize, false),
+        };
+        let end = cursor.pos.checked_add(length).filter(|&end| end <= cursor.data.len())
+            .ok_or_else(|| invalid("truncated line table"))?;
+        let version = cursor.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(invalid(&format!("line table version {} is not supported", version)));
+        }
+        if version >= 5 {
+            // The address and segment selector sizes.
+            cursor.bytes(2)?;
+        }
+        let header_length = match dwarf64 {
+            true => cursor.u64()? as usize,
+            false => cursor.u32()? as usize,
+        };
+        let program = cursor.pos + header_length;
+        let min_inst_length = cursor.u8()? as u64;
+        if version >= 4 {
+            // The maximum operations per instruction, only used by VLIW.
+            cursor.u8()?;
+        }
+        // Whether the rows start as statements. Every row is a line to the table.
+        cursor.u8()?;
+        let line_base = cursor.u8()? as i8 as i64;
+        let line_range = cursor.u8()? as u64;
+        let opcode_base = cursor.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return Err(invalid("bad line table header"));
+        }
+        let opcode_lengths = cursor.bytes(opcode_base as usize - 1)?.to_vec();
+
+        // The directories and files of the unit, by their number in the program. The files are
+        // indices in `self.files`.
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        if version >= 5 {
+            dirs = read_entries(sections, cursor, dwarf64)?.into_iter().map(|(dir, _)| dir).collect();
+            for (path, dir) in read_entries(sections, cursor, dwarf64)? {
+                let dir = dirs.get(dir as usize).map_or("", |dir| dir.as_str());
+                files.push(self.file(dir, &path));
+            }
+        } else {
+            // Directory 0 is the one of the compilation, which is where the paths start from.
+            dirs.push(String::new());
+            loop {
+                let dir = cursor.str()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(dir);
+            }
+            // File 0 is not used before version 5.
+            files.push(usize::MAX);
+            loop {
+                let path = cursor.str()?;
+                if path.is_empty() {
+                    break;
+                }
+                let dir = cursor.uleb()? as usize;
+                // The modification time and the length.
+                cursor.uleb()?;
+                cursor.uleb()?;
+                files.push(self.file(dirs.get(dir).map_or("", |dir| dir.as_str()), &path));
+            }
+        }
+
+        cursor.pos = program;
+        let mut row = Row::new();
+        // The rows of the current sequence, as addresses, files and lines.
+        let mut sequence: Vec<(u64, usize, u64)> = Vec::new();
+        while cursor.pos < end {
+            let opcode = cursor.u8()?;
+            if opcode >= opcode_base {
+                let adjusted = (opcode - opcode_base) as u64;
+                row.addr = row.addr.wrapping_add(adjusted / line_range * min_inst_length);
+                row.line = row.line.wrapping_add_signed(line_base + (adjusted % line_range) as i64);
+                sequence.push((row.addr, row.file, row.line));
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let len = cursor.uleb()? as usize;
+                    let next = cursor.pos + len;
+                    match cursor.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            self.add_sequence(&sequence, row.addr, &files);
+                            sequence.clear();
+                            row = Row::new();
+                        }
+                        DW_LNE_SET_ADDRESS => row.addr = cursor.uint(len.saturating_sub(1))?,
+                        DW_LNE_DEFINE_FILE => {
+                            let path = cursor.str()?;
+                            let dir = cursor.uleb()? as usize;
+                            files.push(self.file(dirs.get(dir).map_or("", |dir| dir.as_str()), &path));
+                        }
+                        _ => (),
+                    }
+                    cursor.pos = next;
+                }
+                DW_LNS_COPY => sequence.push((row.addr, row.file, row.line)),
+                DW_LNS_ADVANCE_PC => row.addr = row.addr.wrapping_add(cursor.uleb()? * min_inst_length),
+                DW_LNS_ADVANCE_LINE => row.line = row.line.wrapping_add_signed(cursor.sleb()?),
+                DW_LNS_SET_FILE => row.file = cursor.uleb()? as usize,
+                DW_LNS_CONST_ADD_PC => {
+                    row.addr = row.addr.wrapping_add((255 - opcode_base) as u64 / line_range * min_inst_length);
+                }
+                DW_LNS_FIXED_ADVANCE_PC => row.addr = row.addr.wrapping_add(cursor.u16()? as u64),
+                // The other opcodes change nothing the table is used for. Their operands are
+                // skipped by their number.
+                _ => {
+                    for _ in 0..opcode_lengths[opcode as usize - 1] {
+                        cursor.uleb()?;
+                    }
+                }
+            }
+        }
+        cursor.pos = end;
+        Ok(())
+    }
+
+    /// Return the index of the file at `path` in `dir`, adding it if it is new.
+    fn file(&mut self, dir: &str, path: &str) -> usize {
+        let path = match path.starts_with('/') || dir.is_empty() {
+            true => String::from(path),
+            false => format!("{}/{}", dir.trim_end_matches('/'), path),
+        };
+        match self.files.iter().position(|file| *file == path) {
+            Some(i) => i,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// Add the lines of a sequence, whose rows are in address order, and which ends at `end`.
+    fn add_sequence(&mut self, rows: &[(u64, usize, u64)], end: u64, files: &[usize]) {
+        for (i, &(addr, file, line)) in rows.iter().enumerate() {
+            let next = rows.get(i + 1).map_or(end, |&(next, _, _)| next);
+            match files.get(file) {
+                Some(&file) if file != usize::MAX && addr < next => {
+                    self.ranges.push(LineRange { range: addr..next, file, line });
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// The registers of the line number state machine that the table is made of.
+struct Row {
+    addr: u64,
+    file: usize,
+    line: u64,
+}
+
+impl Row {
+    fn new() -> Self {
+        Self { addr: 0, file: 1, line: 1 }
+    }
+}
+
+/// Read the directory or file entries of a version 5 header, as their paths and directory
+/// indices.
+fn read_entries(sections: &Sections, cursor: &mut Cursor, dwarf64: bool) -> io::Result<Vec<(String, u64)>> {
+    let format_count = cursor.u8()?;
+    let mut formats = Vec::new();
+    for _ in 0..format_count {
+        formats.push((cursor.uleb()?, cursor.uleb()?));
+    }
+    let count = cursor.uleb()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let (mut path, mut dir) = (String::new(), 0);
+        for &(content, form) in &formats {
+            let value = match form {
+                DW_FORM_STRING => Value::Str(cursor.str()?),
+                DW_FORM_LINE_STRP | DW_FORM_STRP => {
+                    let offset = match dwarf64 {
+                        true => cursor.u64()? as usize,
+                        false => cursor.u32()? as usize,
+                    };
+                    let strings = match form {
+                        DW_FORM_LINE_STRP => sections.debug_line_str,
+                        _ => sections.debug_str,
+                    };
+                    Value::Str(Cursor { data: strings, pos: offset }.str()?)
+                }
+                DW_FORM_UDATA => Value::Int(cursor.uleb()?),
+                DW_FORM_DATA1 => Value::Int(cursor.uint(1)?),
+                DW_FORM_DATA2 => Value::Int(cursor.uint(2)?),
+                DW_FORM_DATA4 => Value::Int(cursor.uint(4)?),
+                DW_FORM_DATA8 => Value::Int(cursor.uint(8)?),
+                DW_FORM_DATA16 => {
+                    cursor.bytes(16)?;
+                    Value::Int(0)
+                }
+                DW_FORM_BLOCK => {
+                    let len = cursor.uleb()? as usize;
+                    cursor.bytes(len)?;
+                    Value::Int(0)
+                }
+                _ => return Err(invalid(&format!("line table form {:#x} is not supported", form))),
+            };
+            match (content, value) {
+                (DW_LNCT_PATH, Value::Str(s)) => path = s,
+                (DW_LNCT_DIRECTORY_INDEX, Value::Int(i)) => dir = i,
+                _ => (),
+            }
+        }
+        entries.push((path, dir));
+    }
+    Ok(entries)
+}
+
+enum Value {
+    Str(String),
+    Int(u64),
+}
+
+/// Reads the little-endian values of a section.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn bytes(&mut self, len: usize) -> io::Result<&[u8]> {
+        let bytes = self.pos.checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| invalid("truncated line table"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        self.uint(8)
+    }
+
+    /// Read an unsigned integer of `len` bytes, up to 8.
+    fn uint(&mut self, len: usize) -> io::Result<u64> {
+        if len > 8 {
+            return Err(invalid("bad line table"));
+        }
+        Ok(self.bytes(len)?.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    fn uleb(&mut self) -> io::Result<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> io::Result<i64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Read a NUL-terminated string.
+    fn str(&mut self) -> io::Result<String> {
+        let rest = self.data.get(self.pos..).ok_or_else(|| invalid("truncated line table"))?;
+        let len = rest.iter().position(|&b| b == 0).ok_or_else(|| invalid("unterminated string in line table"))?;
+        self.pos += len + 1;
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_v4() {
+        // The line program llvm-mc writes for a file `a.s` of instructions on lines 4 to 14.
+        let debug_line = [
+            0x3a, 0x00, 0x00, 0x00, 0x04, 0x00, 0x1b, 0x00, 0x00, 0x00, 0x01, 0x01,
+            0x01, 0xfb, 0x0e, 0x0d, 0x00, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x01, 0x00, 0x61, 0x2e, 0x73, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x09, 0x02, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00,
+            0x15, 0x4c, 0x4b, 0x4b, 0x4b, 0x4c, 0x4b, 0x83, 0x4b, 0x02, 0x04, 0x00,
+            0x01, 0x01,
+        ];
+        let table = LineTable::parse(&Sections { debug_line: &debug_line, debug_line_str: &[], debug_str: &[] }).unwrap();
+        assert_eq!(table.files, vec!["a.s"]);
+        let lines: Vec<(Range<u64>, u64)> = table.ranges.iter().map(|r| (r.range.clone(), r.line)).collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[0], (0x8000_0000..0x8000_0004, 4));
+        assert_eq!(lines[6], (0x8000_0018..0x8000_0020, 12));
+        assert_eq!(lines[8], (0x8000_0024..0x8000_0028, 14));
+        assert!(table.ranges.iter().all(|r| r.file == 0));
+    }
+}
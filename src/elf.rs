@@ -0,0 +1,368 @@
+//! The elf module reads 64-bit little-endian ELF files.
+//! The format is described in the System V ABI: https://refspecs.linuxfoundation.org/elf/gabi4+/contents.html
+
+use std::io;
+use std::ops::Range;
+use crate::bus::Bus;
+use crate::dwarf::{LineTable, Sections};
+use crate::memory::SparseMemory;
+use crate::param::{DRAM_BASE, DRAM_END, PAGE_SIZE};
+
+// ELF identification.
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+pub const EM_RISCV: u16 = 243;
+
+// Program header types.
+const PT_LOAD: u32 = 1;
+
+// Section header types.
+const SHT_SYMTAB: u32 = 2;
+
+// Symbol types.
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+// Sizes of the on-disk structures.
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+pub const PHDR_ENT_SIZE: u64 = PHDR_SIZE as u64;
+
+/// A named address from the ELF symbol table.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    /// The size of the function or object, or 0 if it is not known.
+    pub size: u64,
+    /// Whether the symbol is a function or a label, rather than data.
+    pub code: bool,
+}
+
+/// The symbols of a program, sorted by address.
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|s| s.addr);
+        Self { symbols }
+    }
+
+    /// Return the address of the symbol called `name`.
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+
+    /// Return the function `addr` is in: the closest code symbol at or before it, if `addr` is
+    /// within its size.
+    pub fn function_at(&self, addr: u64) -> Option<&Symbol> {
+        let end = self.symbols.partition_point(|s| s.addr <= addr);
+        let symbol = self.symbols[..end].iter().rev().find(|s| s.code)?;
+        (symbol.size == 0 || addr < symbol.addr.saturating_add(symbol.size)).then_some(symbol)
+    }
+}
+
+struct Section {
+    /// The offset of the name in the section name string table.
+    name: usize,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+/// A loadable segment: `filesz` bytes at `offset` in the file are copied to `paddr` (or
+/// `vaddr` in user mode), and the rest of the `memsz` bytes are zero-filled.
+struct Segment {
+    offset: usize,
+    vaddr: u64,
+    paddr: u64,
+    filesz: usize,
+    memsz: u64,
+}
+
+pub struct Elf {
+    data: Vec<u8>,
+    /// Address of the first instruction.
+    pub entry: u64,
+    phoff: usize,
+    phnum: usize,
+    segments: Vec<Segment>,
+    sections: Vec<Section>,
+    /// The index of the section name string table.
+    shstrndx: usize,
+}
+
+impl Elf {
+    /// Parse the headers of an ELF file.
+    pub fn parse(data: Vec<u8>) -> io::Result<Elf> {
+        if !is_elf(&data) {
+            return Err(invalid("not an ELF file"));
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(invalid("not a 64-bit little-endian ELF file"));
+        }
+        let mut elf = Elf { data, entry: 0, phoff: 0, phnum: 0, segments: Vec::new(), sections: Vec::new(), shstrndx: 0 };
+        elf.entry = elf.u64(24)?;
+        let phoff = elf.u64(32)? as usize;
+        let phnum = elf.u16(56)? as usize;
+        (elf.phoff, elf.phnum) = (phoff, phnum);
+        for i in 0..phnum {
+            let ph = phoff + i * PHDR_SIZE;
+            if elf.u32(ph)? != PT_LOAD {
+                continue;
+            }
+            let segment = Segment {
+                offset: elf.u64(ph + 8)? as usize,
+                vaddr: elf.u64(ph + 16)?,
+                paddr: elf.u64(ph + 24)?,
+                filesz: elf.u64(ph + 32)? as usize,
+                memsz: elf.u64(ph + 40)?,
+            };
+            if (segment.filesz as u64) > segment.memsz {
+                return Err(invalid("segment is larger in the file than in memory"));
+            }
+            if segment.paddr.checked_add(segment.memsz).is_none() || segment.vaddr.checked_add(segment.memsz).is_none() {
+                return Err(invalid("segment wraps around the address space"));
+            }
+            elf.segments.push(segment);
+        }
+        let shoff = elf.u64(40)? as usize;
+        let shnum = elf.u16(60)? as usize;
+        for i in 0..shnum {
+            let sh = shoff + i * SHDR_SIZE;
+            let section = Section {
+                name: elf.u32(sh)? as usize,
+                kind: elf.u32(sh + 4)?,
+                offset: elf.u64(sh + 24)? as usize,
+                size: elf.u64(sh + 32)? as usize,
+                link: elf.u32(sh + 40)? as usize,
+            };
+            elf.sections.push(section);
+        }
+        elf.shstrndx = elf.u16(62)? as usize;
+        Ok(elf)
+    }
+
+    /// Check that the file is a RISC-V executable this emulator can run.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.u16(18)? != EM_RISCV {
+            return Err(invalid("not a RISC-V ELF file"));
+        }
+        if self.u16(16)? != ET_EXEC {
+            return Err(invalid("not an executable ELF file"));
+        }
+        Ok(())
+    }
+
+    /// Return the physical memory the loadable segments cover.
+    pub fn span(&self) -> Range<u64> {
+        let start = self.segments.iter().map(|s| s.paddr).min().unwrap_or(0);
+        let end = self.segments.iter().map(|s| s.paddr + s.memsz).max().unwrap_or(0);
+        start..end
+    }
+
+    /// Copy every loadable segment to its physical address, zero-filling the part that is
+    /// not in the file (e.g. bss).
+    pub fn load(&self, bus: &mut Bus) -> io::Result<()> {
+        for segment in &self.segments {
+            // Checked before the segment is allocated, as its size comes from the file.
+            if segment.paddr < DRAM_BASE || segment.paddr + segment.memsz > DRAM_END + 1 {
+                return Err(invalid(&format!("segment at {:#x} is outside of the dram", segment.paddr)));
+            }
+            let mut image = self.bytes(segment.offset, segment.filesz)?.to_vec();
+            image.resize(segment.memsz as usize, 0);
+            bus.store_bytes(segment.paddr, &image).map_err(|_| {
+                invalid(&format!("segment at {:#x} is outside of the dram", segment.paddr))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Return the virtual memory the loadable segments cover.
+    pub fn virtual_span(&self) -> Range<u64> {
+        let start = self.segments.iter().map(|s| s.vaddr).min().unwrap_or(0);
+        let end = self.segments.iter().map(|s| s.vaddr + s.memsz).max().unwrap_or(0);
+        start..end
+    }
+
+    /// Map every loadable segment at its virtual address in the address space of a user program.
+    pub fn load_virtual(&self, memory: &mut SparseMemory) -> io::Result<()> {
+        for segment in &self.segments {
+            let end = segment.vaddr.checked_add(segment.memsz)
+                .ok_or_else(|| invalid("segment wraps around the address space"))?;
+            // Segments may share a page, whose contents must be kept.
+            let start = match memory.is_mapped(segment.vaddr..segment.vaddr + 1) {
+                true => (segment.vaddr / PAGE_SIZE + 1) * PAGE_SIZE,
+                false => segment.vaddr,
+            };
+            if start < end {
+                memory.map(start..end);
+            }
+            memory.write(segment.vaddr, self.bytes(segment.offset, segment.filesz)?);
+        }
+        Ok(())
+    }
+
+    /// Return the address the program headers are loaded at and their number, if they are
+    /// part of a segment.
+    pub fn program_headers(&self) -> Option<(u64, u64)> {
+        let segment = self.segments.iter()
+            .find(|s| s.offset <= self.phoff && self.phoff < s.offset + s.filesz)?;
+        Some((segment.vaddr + (self.phoff - segment.offset) as u64, self.phnum as u64))
+    }
+
+    /// Read the symbol table. A stripped file yields an empty table.
+    pub fn symbols(&self) -> io::Result<SymbolTable> {
+        let mut symbols = Vec::new();
+        for symtab in self.sections.iter().filter(|s| s.kind == SHT_SYMTAB) {
+            let strtab = self.sections.get(symtab.link).ok_or_else(|| invalid("bad string table index"))?;
+            for i in 0..symtab.size / SYM_SIZE {
+                let sym = symtab.offset + i * SYM_SIZE;
+                let name = self.str(strtab, self.u32(sym)? as usize)?;
+                if name.is_empty() {
+                    continue;
+                }
+                let kind = self.bytes(sym + 4, 1)?[0] & 0xf;
+                symbols.push(Symbol {
+                    name,
+                    addr: self.u64(sym + 8)?,
+                    size: self.u64(sym + 16)?,
+                    code: kind == STT_FUNC || kind == STT_NOTYPE,
+                });
+            }
+        }
+        Ok(SymbolTable::new(symbols))
+    }
+
+    /// Read the line table of the DWARF debugging information. A file without it yields an
+    /// empty table.
+    pub fn lines(&self) -> io::Result<LineTable> {
+        let Some(debug_line) = self.section(".debug_line")? else {
+            return Ok(LineTable::default());
+        };
+        LineTable::parse(&Sections {
+            debug_line,
+            debug_line_str: self.section(".debug_line_str")?.unwrap_or_default(),
+            debug_str: self.section(".debug_str")?.unwrap_or_default(),
+        })
+    }
+
+    /// Return the contents of the section called `name`, if there is one.
+    fn section(&self, name: &str) -> io::Result<Option<&[u8]>> {
+        let Some(shstrtab) = self.sections.get(self.shstrndx) else { return Ok(None) };
+        for section in &self.sections {
+            if self.str(shstrtab, section.name)? == name {
+                return self.bytes(section.offset, section.size).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn bytes(&self, offset: usize, len: usize) -> io::Result<&[u8]> {
+        offset.checked_add(len)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| invalid("truncated ELF file"))
+    }
+
+    fn u16(&self, offset: usize) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into().unwrap()))
+    }
+
+    fn u64(&self, offset: usize) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8)?.try_into().unwrap()))
+    }
+
+    /// Read the NUL-terminated string at `index` in the string table `strtab`.
+    fn str(&self, strtab: &Section, index: usize) -> io::Result<String> {
+        let table = self.bytes(strtab.offset, strtab.size)?;
+        let s = table.get(index..).ok_or_else(|| invalid("bad string index"))?;
+        let end = s.iter().position(|&b| b == 0).unwrap_or(s.len());
+        Ok(String::from_utf8_lossy(&s[..end]).into_owned())
+    }
+}
+
+/// Return true if `data` starts with the ELF magic number.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.len() >= EHDR_SIZE && data[..4] == ELF_MAGIC
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Build an executable with a single segment of `code` at `addr` followed by `bss` zeros.
+#[cfg(test)]
+pub fn executable(machine: u16, addr: u64, code: &[u8], bss: u64) -> Vec<u8> {
+    let mut data = vec![0; EHDR_SIZE + PHDR_SIZE];
+    data[..4].copy_from_slice(&ELF_MAGIC);
+    data[4] = ELFCLASS64;
+    data[5] = ELFDATA2LSB;
+    data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    data[18..20].copy_from_slice(&machine.to_le_bytes());
+    data[24..32].copy_from_slice(&addr.to_le_bytes());
+    data[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+    data[56..58].copy_from_slice(&1u16.to_le_bytes());
+    let ph = EHDR_SIZE;
+    data[ph..ph + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+    data[ph + 8..ph + 16].copy_from_slice(&((EHDR_SIZE + PHDR_SIZE) as u64).to_le_bytes());
+    data[ph + 16..ph + 24].copy_from_slice(&addr.to_le_bytes());
+    data[ph + 24..ph + 32].copy_from_slice(&addr.to_le_bytes());
+    data[ph + 32..ph + 40].copy_from_slice(&(code.len() as u64).to_le_bytes());
+    data[ph + 40..ph + 48].copy_from_slice(&(code.len() as u64 + bss).to_le_bytes());
+    data.extend_from_slice(code);
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::param::DRAM_BASE;
+
+    #[test]
+    fn test_load() {
+        let addr = DRAM_BASE + 0x1000;
+        let elf = Elf::parse(executable(EM_RISCV, addr, &[0x13, 0, 0, 0], 8)).unwrap();
+        elf.validate().unwrap();
+        assert_eq!(elf.entry, addr);
+
+        let mut bus = Bus::new(Vec::new(), Vec::new());
+        bus.store(addr + 8, 32, 0xffff_ffff).unwrap();
+        elf.load(&mut bus).unwrap();
+        assert_eq!(bus.load(addr, 32).unwrap(), 0x13);
+        // The bss is zero-filled.
+        assert_eq!(bus.load(addr + 8, 32).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reject() {
+        let elf = Elf::parse(executable(62, DRAM_BASE, &[0; 4], 0)).unwrap();
+        assert!(elf.validate().is_err());
+        assert!(Elf::parse(vec![0; 64]).is_err());
+
+        let elf = Elf::parse(executable(EM_RISCV, 0x1000, &[0; 4], 0)).unwrap();
+        assert!(elf.load(&mut Bus::new(Vec::new(), Vec::new())).is_err());
+
+        // A segment larger than the dram is rejected before it is allocated.
+        let elf = Elf::parse(executable(EM_RISCV, DRAM_BASE, &[0; 4], 1 << 40)).unwrap();
+        assert!(elf.load(&mut Bus::new(Vec::new(), Vec::new())).is_err());
+        assert!(Elf::parse(executable(EM_RISCV, u64::MAX - 4, &[0; 4], 8)).is_err());
+
+        // So is a segment whose bytes are not all in the file.
+        let mut data = executable(EM_RISCV, DRAM_BASE, &[0; 8], 0);
+        data.truncate(data.len() - 4);
+        let elf = Elf::parse(data).unwrap();
+        assert!(elf.load(&mut Bus::new(Vec::new(), Vec::new())).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! The fdt module reads and writes flattened device trees (DTB files).
+//! The format is described in the Devicetree Specification, chapter 5:
+//! https://github.com/devicetree-org/devicetree-specification/releases
+
+use std::io;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+// Tokens of the structure block.
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub props: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Node {
+        Node { name: String::from(name), props: Vec::new(), children: Vec::new() }
+    }
+
+    /// Add the property called `name`, or replace its value.
+    pub fn set_prop(&mut self, name: &str, value: Vec<u8>) {
+        match self.props.iter_mut().find(|(n, _)| n == name) {
+            Some(prop) => prop.1 = value,
+            None => self.props.push((String::from(name), value)),
+        }
+    }
+
+    /// Set a property holding a 64-bit number as two cells.
+    pub fn set_u64(&mut self, name: &str, value: u64) {
+        self.set_prop(name, value.to_be_bytes().to_vec());
+    }
+
+    /// Set a property holding a list of 32-bit cells.
+    pub fn set_cells(&mut self, name: &str, cells: &[u32]) {
+        self.set_prop(name, cells.iter().flat_map(|c| c.to_be_bytes()).collect());
+    }
+
+    /// Set a property holding a NUL-terminated string.
+    pub fn set_str(&mut self, name: &str, value: &str) {
+        self.set_strs(name, &[value]);
+    }
+
+    /// Set a property holding a list of NUL-terminated strings.
+    pub fn set_strs(&mut self, name: &str, values: &[&str]) {
+        self.set_prop(name, values.iter().flat_map(|v| v.bytes().chain([0])).collect());
+    }
+
+    /// Set a property without a value, whose presence is the information.
+    pub fn set_flag(&mut self, name: &str) {
+        self.set_prop(name, Vec::new());
+    }
+
+    /// Return the child called `name`, adding it if it does not exist yet.
+    pub fn child_mut(&mut self, name: &str) -> &mut Node {
+        match self.children.iter().position(|c| c.name == name) {
+            Some(i) => &mut self.children[i],
+            None => {
+                self.children.push(Node::new(name));
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fdt {
+    pub root: Node,
+    /// Memory reservations as (address, size) pairs.
+    pub reserved: Vec<(u64, u64)>,
+    pub boot_cpuid: u32,
+}
+
+impl Fdt {
+    /// Parse a flattened device tree blob.
+    pub fn parse(data: &[u8]) -> io::Result<Fdt> {
+        let reader = Reader { data };
+        if reader.u32(0)? != FDT_MAGIC {
+            return Err(invalid("bad device tree magic"));
+        }
+        if reader.u32(24)? < FDT_LAST_COMP_VERSION {
+            return Err(invalid("unsupported device tree version"));
+        }
+        let off_struct = reader.u32(8)? as usize;
+        let off_strings = reader.u32(12)? as usize;
+        let off_rsvmap = reader.u32(16)? as usize;
+        let boot_cpuid = reader.u32(28)?;
+
+        let mut reserved = Vec::new();
+        for offset in (off_rsvmap..).step_by(16) {
+            let entry = (reader.u64(offset)?, reader.u64(offset + 8)?);
+            if entry == (0, 0) {
+                break;
+            }
+            reserved.push(entry);
+        }
+
+        // Walk the tokens, keeping the nodes that are not closed yet on a stack.
+        let mut stack: Vec<Node> = Vec::new();
+        let mut offset = off_struct;
+        let root = loop {
+            let token = reader.u32(offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = reader.str(offset)?;
+                    offset = align4(offset + name.len() + 1);
+                    stack.push(Node::new(&name));
+                }
+                FDT_END_NODE => {
+                    let node = stack.pop().ok_or_else(|| invalid("unbalanced device tree nodes"))?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => break node,
+                    }
+                }
+                FDT_PROP => {
+                    let len = reader.u32(offset)? as usize;
+                    let name = reader.str(off_strings + reader.u32(offset + 4)? as usize)?;
+                    let value = reader.bytes(offset + 8, len)?.to_vec();
+                    offset = align4(offset + 8 + len);
+                    let node = stack.last_mut().ok_or_else(|| invalid("property outside of a node"))?;
+                    node.props.push((name, value));
+                }
+                FDT_NOP => (),
+                _ => return Err(invalid("bad device tree token")),
+            }
+        };
+        Ok(Fdt { root, reserved, boot_cpuid })
+    }
+
+    /// Serialize the tree into a blob.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        write_node(&self.root, &mut structure, &mut strings);
+        push_u32(&mut structure, FDT_END);
+
+        let mut rsvmap = Vec::new();
+        for &(addr, size) in self.reserved.iter().chain([(0, 0)].iter()) {
+            rsvmap.extend_from_slice(&addr.to_be_bytes());
+            rsvmap.extend_from_slice(&size.to_be_bytes());
+        }
+
+        // The memory reservation block must be 8-byte aligned.
+        let off_rsvmap = FDT_HEADER_SIZE.next_multiple_of(8);
+        let off_struct = off_rsvmap + rsvmap.len();
+        let off_strings = off_struct + structure.len();
+        let total = off_strings + strings.len();
+
+        let mut blob = Vec::with_capacity(total);
+        for field in [
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid,
+            strings.len() as u32,
+            structure.len() as u32,
+        ] {
+            push_u32(&mut blob, field);
+        }
+        blob.resize(off_rsvmap, 0);
+        blob.extend_from_slice(&rsvmap);
+        blob.extend_from_slice(&structure);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+}
+
+fn write_node(node: &Node, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+    push_u32(structure, FDT_BEGIN_NODE);
+    structure.extend_from_slice(node.name.as_bytes());
+    structure.push(0);
+    structure.resize(align4(structure.len()), 0);
+    for (name, value) in &node.props {
+        push_u32(structure, FDT_PROP);
+        push_u32(structure, value.len() as u32);
+        push_u32(structure, string_offset(strings, name));
+        structure.extend_from_slice(value);
+        structure.resize(align4(structure.len()), 0);
+    }
+    for child in &node.children {
+        write_node(child, structure, strings);
+    }
+    push_u32(structure, FDT_END_NODE);
+}
+
+/// Return the offset of `name` in the strings block, adding it if needed.
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut offset = 0;
+    for s in strings.split(|&b| b == 0) {
+        if s == name.as_bytes() && offset < strings.len() {
+            return offset as u32;
+        }
+        offset += s.len() + 1;
+    }
+    let offset = strings.len();
+    strings.extend_from_slice(name.as_bytes());
+    strings.push(0);
+    offset as u32
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn align4(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+/// Bounds-checked big-endian reads from a blob.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> io::Result<&[u8]> {
+        offset.checked_add(len)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or_else(|| invalid("truncated device tree"))
+    }
+
+    fn u32(&self, offset: usize) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(offset, 4)?.try_into().unwrap()))
+    }
+
+    fn u64(&self, offset: usize) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(offset, 8)?.try_into().unwrap()))
+    }
+
+    fn str(&self, offset: usize) -> io::Result<String> {
+        let s = self.data.get(offset..).ok_or_else(|| invalid("truncated device tree"))?;
+        let end = s.iter().position(|&b| b == 0).ok_or_else(|| invalid("unterminated string"))?;
+        Ok(String::from_utf8_lossy(&s[..end]).into_owned())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut root = Node::new("");
+        root.set_prop("compatible", b"riscv-virtio\0".to_vec());
+        root.child_mut("chosen").set_u64("linux,initrd-start", 0x8400_0000);
+        root.child_mut("memory@80000000").set_prop("device_type", b"memory\0".to_vec());
+        let fdt = Fdt { root, reserved: vec![(0x8000_0000, 0x20_0000)], boot_cpuid: 0 };
+
+        let parsed = Fdt::parse(&fdt.to_bytes()).unwrap();
+        assert_eq!(parsed, fdt);
+        let chosen = parsed.root.children.iter().find(|c| c.name == "chosen").unwrap();
+        assert_eq!(chosen.props[0].1, vec![0, 0, 0, 0, 0x84, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        assert!(Fdt::parse(&[0; FDT_HEADER_SIZE]).is_err());
+    }
+}
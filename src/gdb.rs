@@ -0,0 +1,503 @@
+//! The gdb module serves the GDB remote serial protocol, so that gdb can attach to the guest with
+//! `target remote`. It takes one connection on a local TCP port or a Unix socket, and the hart
+//! starts stopped. gdb sees the integer registers, the pc, the CSRs and the privilege mode, and
+//! memory as the hart sees it. Breakpoints and watchpoints are kept by the emulator, so the code
+//! of the guest is never patched. With a timeline, gdb can also step and continue backwards.
+//! The protocol is described in https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+use crate::cpu::{CPU, Register, RVABI};
+use crate::csr::*;
+use crate::reverse::{Stop, Timeline};
+use crate::watchpoint::{Action, Hit, Watchpoint};
+
+// Signals reported to gdb.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// Register numbers of gdb for RISC-V: x0-x31, the pc, the floating-point registers, the CSRs
+// by their number, and the privilege mode.
+const PC_REGNUM: usize = 32;
+const FIRST_CSR_REGNUM: usize = 65;
+const PRIV_REGNUM: usize = 4161;
+
+// The character gdb sends to interrupt the running hart.
+const INTERRUPT: u8 = 0x03;
+// How many instructions run between checks for an interrupt.
+const POLL_INTERVAL: u64 = 0x1000;
+
+
+/// The connection to gdb.
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+pub struct Gdb {
+    /// The connection, until gdb detaches.
+    stream: Option<Stream>,
+    /// Whether packets are acknowledged, until gdb turns it off.
+    ack: bool,
+    breakpoints: HashSet<u64>,
+    /// Stop after the next instruction.
+    stepping: bool,
+    /// gdb resumed the hart and waits for it to stop.
+    running: bool,
+    /// The reply that describes the last stop.
+    last_stop: String,
+    /// Instructions run since the last check for an interrupt.
+    count: u64,
+}
+
+impl Gdb {
+    /// Wait for gdb to connect on `addr`, which is a TCP port on localhost or the path of a Unix
+    /// socket.
+    pub fn listen(addr: &str) -> io::Result<Gdb> {
+        eprintln!("Waiting for gdb on {}", addr);
+        let stream = match addr.parse::<u16>() {
+            Ok(port) => {
+                let (stream, _) = TcpListener::bind(("127.0.0.1", port))?.accept()?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            #[cfg(unix)]
+            Err(_) => Stream::Unix(UnixListener::bind(addr)?.accept()?.0),
+            #[cfg(not(unix))]
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets need a Unix host")),
+        };
+        Ok(Gdb {
+            stream: Some(stream),
+            ack: true,
+            breakpoints: HashSet::new(),
+            // The hart is stopped before its first instruction.
+            stepping: true,
+            running: false,
+            last_stop: format!("S{:02x}", SIGTRAP),
+            count: 0,
+        })
+    }
+
+    /// Stop the hart and serve gdb if it has to stop before the instruction at pc: after a step,
+    /// at a breakpoint, after an access to a watchpoint, or when gdb interrupts it.
+    /// gdb can go back in time on `timeline`, if given.
+    pub fn before_step(&mut self, cpu: &mut CPU, timeline: Option<&mut Timeline>) -> io::Result<()> {
+        if self.stream.is_none() {
+            return Ok(());
+        }
+        let reply = if let Some(hit) = cpu.watch_hit.take() {
+            watch_reply(&hit)
+        } else if self.stepping || self.breakpoints.contains(&cpu.pc) {
+            format!("S{:02x}", SIGTRAP)
+        } else if self.is_interrupted()? {
+            format!("S{:02x}", SIGINT)
+        } else {
+            return Ok(());
+        };
+        self.stop(cpu, timeline, reply)
+    }
+
+    /// Stop the hart after a fatal exception, so that gdb can look at what went wrong.
+    pub fn fault(&mut self, cpu: &mut CPU, timeline: Option<&mut Timeline>) -> io::Result<()> {
+        if self.stream.is_none() {
+            return Ok(());
+        }
+        self.stop(cpu, timeline, format!("S{:02x}", SIGSEGV))
+    }
+
+    /// Tell gdb that the guest exited with `code`.
+    pub fn exit(&mut self, code: i32) -> io::Result<()> {
+        if self.stream.is_some() && self.running {
+            self.send(&format!("W{:02x}", code as u8))?;
+        }
+        Ok(())
+    }
+
+    /// Return true if gdb sent an interrupt. The connection is only looked at every few
+    /// instructions.
+    fn is_interrupted(&mut self) -> io::Result<bool> {
+        self.count += 1;
+        if self.count < POLL_INTERVAL {
+            return Ok(false);
+        }
+        self.count = 0;
+        let stream = self.stream.as_mut().unwrap();
+        stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = stream.read(&mut byte);
+        stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => {
+                self.stream = None;
+                Ok(false)
+            }
+            Ok(_) => Ok(byte[0] == INTERRUPT),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Report the stop to gdb and serve its requests until it resumes the hart or detaches.
+    fn stop(&mut self, cpu: &mut CPU, mut timeline: Option<&mut Timeline>, reply: String) -> io::Result<()> {
+        self.stepping = false;
+        if self.running {
+            self.send(&reply)?;
+            self.running = false;
+        }
+        self.last_stop = reply;
+        loop {
+            let Some(packet) = self.receive()? else {
+                self.detach(cpu);
+                return Ok(());
+            };
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+            match command {
+                "c" | "s" => {
+                    if let Some(addr) = hex(args) {
+                        cpu.pc = addr;
+                        if let Some(timeline) = timeline.as_deref_mut() {
+                            timeline.diverge();
+                        }
+                    }
+                    self.stepping = command == "s";
+                    self.running = true;
+                    return Ok(());
+                }
+                "D" => {
+                    self.send("OK")?;
+                    self.detach(cpu);
+                    return Ok(());
+                }
+                "k" => {
+                    cpu.flush_outputs();
+                    process::exit(0)
+                }
+                _ if packet == "QStartNoAckMode" => {
+                    self.send("OK")?;
+                    self.ack = false;
+                }
+                "b" if timeline.is_some() && (args == "s" || args == "c") => {
+                    let timeline = timeline.as_deref_mut().unwrap();
+                    let stop = match args {
+                        "s" => timeline.reverse_step(cpu),
+                        _ => timeline.reverse_continue(cpu, &self.breakpoints),
+                    };
+                    let reply = match stop {
+                        Stop::Stepped | Stop::Breakpoint => format!("S{:02x}", SIGTRAP),
+                        Stop::Watchpoint(hit) => watch_reply(&hit),
+                        Stop::Start => format!("T{:02x}replaylog:begin;", SIGTRAP),
+                    };
+                    self.send(&reply)?;
+                    self.last_stop = reply;
+                }
+                _ => {
+                    // The guest goes another way once the debugger changes it.
+                    if let ("G" | "P" | "M", Some(timeline)) = (command, timeline.as_deref_mut()) {
+                        timeline.diverge();
+                    }
+                    let reply = self.reply(cpu, timeline.is_some(), command, args);
+                    self.send(&reply)?;
+                }
+            }
+        }
+    }
+
+    /// Return the reply to a request that leaves the hart stopped.
+    fn reply(&mut self, cpu: &mut CPU, reverse: bool, command: &str, args: &str) -> String {
+        let reply = match command {
+            "?" => Some(self.last_stop.clone()),
+            "q" => query(args, reverse),
+            // There is a single thread.
+            "H" | "T" => Some(String::from("OK")),
+            "g" => {
+                let regs = (0..=PC_REGNUM).map(|n| read_register(cpu, n).unwrap());
+                Some(regs.map(|value| encode(&value.to_le_bytes())).collect())
+            }
+            "G" => decode(args).and_then(|bytes| {
+                for (n, value) in bytes.chunks_exact(8).take(PC_REGNUM + 1).enumerate() {
+                    write_register(cpu, n, u64::from_le_bytes(value.try_into().unwrap()))?;
+                }
+                Some(String::from("OK"))
+            }),
+            "p" => hex(args)
+                .and_then(|n| read_register(cpu, n as usize))
+                .map(|value| encode(&value.to_le_bytes())),
+            "P" => args.split_once('=').and_then(|(n, value)| {
+                let value = decode(value).filter(|v| v.len() == 8)?;
+                write_register(cpu, hex(n)? as usize, u64::from_le_bytes(value.try_into().unwrap()))?;
+                Some(String::from("OK"))
+            }),
+            "m" => args.split_once(',').and_then(|(addr, len)| {
+                let mut buf = vec![0; hex(len)?.min(0x1000) as usize];
+                cpu.load_bytes(hex(addr)?, &mut buf).ok()?;
+                Some(encode(&buf))
+            }),
+            "M" => args.split_once(':').and_then(|(range, data)| {
+                let (addr, _) = range.split_once(',')?;
+                cpu.store_bytes(hex(addr)?, &decode(data)?).ok()?;
+                Some(String::from("OK"))
+            }),
+            "Z" | "z" => self.breakpoint(cpu, command == "Z", args),
+            // Anything else is not supported.
+            _ => return String::new(),
+        };
+        reply.unwrap_or_else(|| String::from("E01"))
+    }
+
+    /// Insert or remove the breakpoint or watchpoint in `args`, which is `type,addr,kind`.
+    fn breakpoint(&mut self, cpu: &mut CPU, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        let (kind, addr, len) = (fields.next()?, hex(fields.next()?)?, hex(fields.next()?)?);
+        let (read, write) = match kind {
+            // Software and hardware breakpoints are the same to the emulator.
+            "0" | "1" => {
+                match insert {
+                    true => self.breakpoints.insert(addr),
+                    false => self.breakpoints.remove(&addr),
+                };
+                return Some(String::from("OK"));
+            }
+            "2" => (false, true),
+            "3" => (true, false),
+            "4" => (true, true),
+            _ => return Some(String::new()),
+        };
+        let watchpoint = Watchpoint::new(addr, len, read, write);
+        match insert {
+            true => cpu.watchpoints.push(watchpoint),
+            false => cpu.watchpoints.retain(|w| *w != watchpoint),
+        }
+        Some(String::from("OK"))
+    }
+
+    /// Forget the breakpoints and watchpoints and let the hart run on its own.
+    fn detach(&mut self, cpu: &mut CPU) {
+        self.stream = None;
+        self.breakpoints.clear();
+        // The watchpoints of the command line stay.
+        cpu.watchpoints.retain(|w| w.action != Action::Stop);
+        cpu.watch_hit = None;
+    }
+
+    /// Read the next packet, acknowledging it, or return None if gdb disconnected.
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut packet = None;
+        loop {
+            let Some(byte) = self.read_byte()? else { return Ok(None) };
+            match (byte, &mut packet) {
+                (b'$', _) => packet = Some(Vec::new()),
+                (b'#', Some(data)) => {
+                    let mut checksum = [0; 2];
+                    for c in checksum.iter_mut() {
+                        *c = self.read_byte()?.unwrap_or(0);
+                    }
+                    let valid = hex(&String::from_utf8_lossy(&checksum)) == Some(sum(data) as u64);
+                    let data = std::mem::take(data);
+                    if self.ack {
+                        self.write(if valid { b"+" } else { b"-" })?;
+                    }
+                    if valid || !self.ack {
+                        return Ok(Some(data));
+                    }
+                    packet = None;
+                }
+                (_, Some(data)) => data.push(byte),
+                // Acknowledgements, and interrupts that came after the hart stopped.
+                (_, None) => (),
+            }
+        }
+    }
+
+    /// Send `data` as a packet, again until gdb acknowledges it.
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut body = Vec::new();
+        for &byte in data.as_bytes() {
+            match byte {
+                b'$' | b'#' | b'}' | b'*' => body.extend([b'}', byte ^ 0x20]),
+                _ => body.push(byte),
+            }
+        }
+        let packet = [b"$", &body[..], format!("#{:02x}", sum(&body)).as_bytes()].concat();
+        loop {
+            self.write(&packet)?;
+            if !self.ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.as_mut().unwrap().read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let stream = self.stream.as_mut().unwrap();
+        stream.write_all(data)?;
+        stream.flush()
+    }
+}
+
+/// Return the reply to the general query `args`. `reverse` tells if the hart can go backwards.
+fn query(args: &str, reverse: bool) -> Option<String> {
+    let reply = match args.split(':').next().unwrap() {
+        "Supported" if reverse => {
+            String::from("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+")
+        }
+        "Supported" => String::from("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+"),
+        "Attached" => String::from("1"),
+        "C" => String::from("QC1"),
+        "fThreadInfo" => String::from("m1"),
+        "sThreadInfo" => String::from("l"),
+        "Xfer" => {
+            // Xfer:features:read:target.xml:offset,length
+            let request = args.strip_prefix("Xfer:features:read:target.xml:")?;
+            let (offset, len) = request.split_once(',')?;
+            let xml = target_xml();
+            let start = (hex(offset)? as usize).min(xml.len());
+            let end = start.saturating_add(hex(len)? as usize).min(xml.len());
+            let more = if end < xml.len() { "m" } else { "l" };
+            format!("{}{}", more, &xml[start..end])
+        }
+        _ => String::new(),
+    };
+    Some(reply)
+}
+
+/// Return the stop reply for an access to a watchpoint.
+fn watch_reply(hit: &Hit) -> String {
+    let kind = match (hit.watchpoint.read, hit.watchpoint.write) {
+        (true, false) => "rwatch",
+        (false, true) => "watch",
+        _ => "awatch",
+    };
+    format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.addr)
+}
+
+/// Describe the registers to gdb.
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target version=\"1.0\"><architecture>riscv:rv64</architecture>",
+        "<feature name=\"org.gnu.gdb.riscv.cpu\">",
+    ));
+    for (n, name) in RVABI.iter().enumerate() {
+        xml += &format!("<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>", name, n);
+    }
+    xml += &format!("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>", PC_REGNUM);
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.csr\">";
+    for (name, csr) in CSR_NAMES {
+        xml += &format!("<reg name=\"{}\" bitsize=\"64\" regnum=\"{}\"/>", name, FIRST_CSR_REGNUM + csr);
+    }
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.virtual\">";
+    xml += &format!("<reg name=\"priv\" bitsize=\"64\" regnum=\"{}\"/>", PRIV_REGNUM);
+    xml + "</feature></target>"
+}
+
+/// Return the register of gdb number `n`.
+fn register(n: usize) -> Option<Register> {
+    match n {
+        0..=31 => Some(Register::X(n)),
+        PC_REGNUM => Some(Register::Pc),
+        PRIV_REGNUM => Some(Register::Priv),
+        _ => CSR_NAMES.iter().find(|(_, csr)| FIRST_CSR_REGNUM + csr == n).map(|(_, csr)| Register::Csr(*csr)),
+    }
+}
+
+fn read_register(cpu: &CPU, n: usize) -> Option<u64> {
+    register(n).map(|register| cpu.read_register(register))
+}
+
+fn write_register(cpu: &mut CPU, n: usize, value: u64) -> Option<()> {
+    cpu.write_register(register(n)?, value).then_some(())
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Machine;
+
+    #[test]
+    fn test_registers() {
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        write_register(&mut cpu, 10, 0x1234).unwrap();
+        write_register(&mut cpu, FIRST_CSR_REGNUM + MEPC, 0x8000_0000).unwrap();
+        assert_eq!(cpu.regs[10], 0x1234);
+        assert_eq!(read_register(&cpu, FIRST_CSR_REGNUM + MEPC), Some(0x8000_0000));
+        assert_eq!(read_register(&cpu, PRIV_REGNUM), Some(Machine));
+        assert!(write_register(&mut cpu, PRIV_REGNUM, 2).is_none());
+        assert_eq!(decode(&encode(&[0, 0x7f, 0xff])), Some(vec![0, 0x7f, 0xff]));
+        assert!(target_xml().contains("<reg name=\"satp\" bitsize=\"64\" regnum=\"449\"/>"));
+    }
+}
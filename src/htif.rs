@@ -0,0 +1,186 @@
+//! The htif module implements the Host-Target Interface used by Spike and the riscv-tests suite.
+//! The guest writes a command to the `tohost` word in the dram and the host answers in `fromhost`.
+//! A command is encoded as `device[63:56] | cmd[55:48] | payload[47:0]`.
+
+use std::io;
+use std::io::Write;
+use crate::bus::{exit_status, Shutdown};
+use crate::dram::Dram;
+use crate::param::{DRAM_BASE, DRAM_END};
+
+// Devices.
+const HTIF_DEV_SYSCALL: u64 = 0;
+const HTIF_DEV_CONSOLE: u64 = 1;
+
+// Console commands.
+const HTIF_CONSOLE_PUTCHAR: u64 = 1;
+
+// Syscalls proxied through the syscall device. The numbers are the ones of riscv-pk.
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const ENOSYS: i64 = 38;
+
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    /// Exit request of the guest, if it has not been handled yet.
+    request: Option<Shutdown>,
+    /// Whether the console output is dropped.
+    pub muted: bool,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>) -> Self {
+        Self { tohost, fromhost, request: None, muted: false }
+    }
+
+    pub fn tohost(&self) -> u64 {
+        self.tohost
+    }
+
+    /// Return true if a store of `size` bits at `addr` touches the tohost word. A word or a
+    /// store at the end of the address space goes up to its end.
+    pub fn is_tohost(&self, addr: u64, size: u64) -> bool {
+        self.tohost.checked_add(8).is_none_or(|end| addr < end)
+            && addr.checked_add(size / 8).is_none_or(|end| self.tohost < end)
+    }
+
+    /// Return the pending exit request and clear it.
+    pub fn take_request(&mut self) -> Option<Shutdown> {
+        self.request.take()
+    }
+
+    /// Execute the command the guest has written to tohost.
+    pub fn handle(&mut self, dram: &mut Dram) {
+        let value = match read(dram, self.tohost) {
+            Some(value) if value != 0 => value,
+            _ => return,
+        };
+        let device = value >> 56;
+        let cmd = (value >> 48) & 0xff;
+        let payload = value & 0xffff_ffff_ffff;
+
+        let response = match (device, cmd) {
+            // The lowest bit marks an exit, the remaining bits are the exit code.
+            // riscv-tests report the number of the failing test this way.
+            (HTIF_DEV_SYSCALL, 0) if payload & 1 == 1 => {
+                self.request = Some(Shutdown::PowerOff(exit_status(payload >> 1)));
+                None
+            }
+            (HTIF_DEV_SYSCALL, 0) => {
+                self.syscall(dram, payload);
+                Some(1)
+            }
+            (HTIF_DEV_CONSOLE, HTIF_CONSOLE_PUTCHAR) => {
+                if !self.muted {
+                    print!("{}", payload as u8 as char);
+                    io::stdout().flush().unwrap();
+                }
+                Some(0x100 | (payload & 0xff))
+            }
+            // Console input and unknown devices are not supported. The command is dropped.
+            _ => None,
+        };
+
+        // Acknowledge the command so that the guest can send the next one.
+        write(dram, self.tohost, 0);
+        if let (Some(fromhost), Some(response)) = (self.fromhost, response) {
+            write(dram, fromhost, (device << 56) | (cmd << 48) | response);
+        }
+    }
+
+    /// Execute a proxied syscall. `magic_mem` points to the syscall number and its arguments,
+    /// and the return value is written back to its first word.
+    fn syscall(&mut self, dram: &mut Dram, magic_mem: u64) {
+        let arg = |i: u64| read(dram, magic_mem + i * 8).unwrap_or(0);
+        let (n, a0, a1, a2) = (arg(0), arg(1), arg(2), arg(3));
+        let ret = match n {
+            SYS_WRITE => {
+                let bytes: Vec<u8> = (0..a2).map_while(|i| read_byte(dram, a1 + i)).collect();
+                let result = match a0 {
+                    1 | 2 if self.muted => Ok(()),
+                    1 => io::stdout().write_all(&bytes).and_then(|_| io::stdout().flush()),
+                    2 => io::stderr().write_all(&bytes),
+                    _ => Err(io::Error::from(io::ErrorKind::Unsupported)),
+                };
+                match result {
+                    Ok(_) => bytes.len() as i64,
+                    Err(_) => -ENOSYS,
+                }
+            }
+            SYS_EXIT => {
+                self.request = Some(Shutdown::PowerOff(exit_status(a0)));
+                0
+            }
+            _ => -ENOSYS,
+        };
+        write(dram, magic_mem, ret as u64);
+    }
+}
+
+fn in_dram(addr: u64, bytes: u64) -> bool {
+    addr >= DRAM_BASE && addr.checked_add(bytes - 1).is_some_and(|end| end <= DRAM_END)
+}
+
+fn read(dram: &Dram, addr: u64) -> Option<u64> {
+    if !in_dram(addr, 8) {
+        return None;
+    }
+    dram.load(addr, 64).ok()
+}
+
+fn read_byte(dram: &Dram, addr: u64) -> Option<u8> {
+    if !in_dram(addr, 1) {
+        return None;
+    }
+    dram.load(addr, 8).ok().map(|b| b as u8)
+}
+
+fn write(dram: &mut Dram, addr: u64, value: u64) {
+    if in_dram(addr, 8) {
+        dram.store(addr, 64, value).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exit() {
+        let tohost = DRAM_BASE + 0x1000;
+        let mut htif = Htif::new(tohost, None);
+        let mut dram = Dram::new(Vec::new());
+
+        // A pass, then the failures of tests 3 and 256.
+        for (payload, status) in [(0, 0), (3, 3), (256, 255)] {
+            write(&mut dram, tohost, (payload << 1) | 1);
+            htif.handle(&mut dram);
+            assert_eq!(htif.take_request(), Some(Shutdown::PowerOff(status)));
+            assert_eq!(read(&dram, tohost), Some(0));
+        }
+
+        // The exit syscall of riscv-pk, with the syscall number and a0 in the magic memory.
+        let magic_mem = DRAM_BASE + 0x2000;
+        write(&mut dram, magic_mem, SYS_EXIT);
+        write(&mut dram, magic_mem + 8, 512);
+        write(&mut dram, tohost, magic_mem);
+        htif.handle(&mut dram);
+        assert_eq!(htif.take_request(), Some(Shutdown::PowerOff(255)));
+    }
+
+    #[test]
+    fn test_is_tohost() {
+        let htif = Htif::new(DRAM_BASE + 0x1000, None);
+        assert!(htif.is_tohost(DRAM_BASE + 0x1000, 64));
+        assert!(htif.is_tohost(DRAM_BASE + 0x1004, 8));
+        assert!(htif.is_tohost(DRAM_BASE + 0xffc, 64));
+        assert!(!htif.is_tohost(DRAM_BASE + 0x1008, 64));
+        assert!(!htif.is_tohost(DRAM_BASE + 0xff8, 64));
+
+        let htif = Htif::new(u64::MAX - 3, None);
+        assert!(htif.is_tohost(u64::MAX, 8));
+        assert!(htif.is_tohost(u64::MAX - 7, 64));
+        assert!(!htif.is_tohost(u64::MAX - 11, 64));
+    }
+}
@@ -0,0 +1,334 @@
+//! The loader module places the program and the boot images given on the command line into memory.
+
+use std::fs;
+use std::io;
+use std::ops::Range;
+use crate::bus::Bus;
+use crate::config::{Config, ImageArg};
+use crate::cpu::CPU;
+use crate::devicetree;
+use crate::dwarf::LineTable;
+use crate::elf::{is_elf, Elf, SymbolTable};
+use crate::fdt::Fdt;
+use crate::rom::Rom;
+use crate::param::{DRAM_BASE, DRAM_END, DRAM_SIZE, PAGE_SIZE, ROM_BASE};
+
+/// Kernels are placed on a 2 MiB boundary, so that they can be mapped with megapages.
+const KERNEL_ALIGN: u64 = 0x20_0000;
+/// Where the magic numbers of a Linux `Image` header are, and what they read.
+/// The format is described in Documentation/arch/riscv/boot-image-header.rst of Linux.
+const IMAGE_MAGIC_OFFSET: usize = 0x30;
+const IMAGE_MAGIC: &[u8; 8] = b"RISCV\0\0\0";
+const IMAGE_MAGIC2_OFFSET: usize = 0x38;
+const IMAGE_MAGIC2: &[u8; 4] = b"RSC\x05";
+const IMAGE_HEADER_SIZE: usize = 0x40;
+/// The only flag of the header: the kernel is big-endian.
+const IMAGE_FLAG_BE: u64 = 1;
+
+/// The device tree is placed on a 2 MiB boundary at the end of the dram, like QEMU does.
+const FDT_ALIGN: u64 = 0x20_0000;
+
+pub enum Program {
+    /// A flat binary, copied to a given address.
+    Raw(Vec<u8>),
+    /// An ELF executable, loaded segment by segment.
+    Elf(Elf),
+    /// A Linux `Image`, copied to `text_offset` past the start of the dram. It takes
+    /// `image_size` bytes once running, which may be more than the file.
+    Image { code: Vec<u8>, text_offset: u64, image_size: u64 },
+}
+
+impl Program {
+    /// Read the program at `path`, detecting ELF files by their magic number.
+    pub fn open(path: &str) -> io::Result<Program> {
+        let data = fs::read(path)?;
+        if is_image(&data) {
+            return Program::image(data);
+        }
+        if !is_elf(&data) {
+            return Ok(Program::Raw(data));
+        }
+        let elf = Elf::parse(data)?;
+        elf.validate()?;
+        Ok(Program::Elf(elf))
+    }
+
+    /// Check the header of a Linux `Image`.
+    fn image(code: Vec<u8>) -> io::Result<Program> {
+        let u64_at = |offset: usize| u64::from_le_bytes(code[offset..offset + 8].try_into().unwrap());
+        let (text_offset, image_size, flags) = (u64_at(0x08), u64_at(0x10), u64_at(0x18));
+        if flags & IMAGE_FLAG_BE != 0 {
+            return Err(invalid("big-endian kernel images are not supported"));
+        }
+        if flags & !IMAGE_FLAG_BE != 0 {
+            return Err(invalid(&format!("unknown kernel image flags {:#x}", flags)));
+        }
+        Ok(Program::Image { code, text_offset, image_size })
+    }
+
+    /// Return the address the program asks to be loaded at, if it says so.
+    fn fixed_addr(&self) -> io::Result<Option<u64>> {
+        match self {
+            Program::Image { text_offset, .. } => DRAM_BASE.checked_add(*text_offset)
+                .map(Some)
+                .ok_or_else(|| invalid("kernel image does not fit in the dram")),
+            _ => Ok(None),
+        }
+    }
+
+    /// Return the memory the program occupies when a flat binary is loaded at `addr`.
+    fn span(&self, addr: u64) -> io::Result<Range<u64>> {
+        let size = match self {
+            Program::Raw(code) => code.len() as u64,
+            Program::Elf(elf) => return Ok(elf.span()),
+            // Old kernels leave the size of the image at zero.
+            Program::Image { code, image_size, .. } => (*image_size).max(code.len() as u64),
+        };
+        match addr.checked_add(size) {
+            Some(end) => Ok(addr..end),
+            None => Err(invalid("image does not fit in the dram")),
+        }
+    }
+
+    /// Copy the program into memory, a flat binary at `addr`, and return the address it starts at.
+    fn load(&self, bus: &mut Bus, addr: u64) -> io::Result<u64> {
+        match self {
+            Program::Raw(code) | Program::Image { code, .. } => {
+                bus.store_bytes(addr, code).map_err(|_| invalid("image does not fit in the dram"))?;
+                Ok(addr)
+            }
+            Program::Elf(elf) => {
+                elf.load(bus)?;
+                Ok(elf.entry)
+            }
+        }
+    }
+
+    /// Return the symbols of the program. A flat binary has none.
+    pub fn symbols(&self) -> io::Result<SymbolTable> {
+        match self {
+            Program::Raw(_) | Program::Image { .. } => Ok(SymbolTable::default()),
+            Program::Elf(elf) => elf.symbols(),
+        }
+    }
+
+    /// Return the line table of the program. A flat binary has none.
+    pub fn lines(&self) -> io::Result<LineTable> {
+        match self {
+            Program::Raw(_) | Program::Image { .. } => Ok(LineTable::default()),
+            Program::Elf(elf) => elf.lines(),
+        }
+    }
+}
+
+/// Everything that is placed in memory at boot.
+pub struct Boot {
+    program: Option<Program>,
+    kernel: Option<(Program, Option<u64>)>,
+    initrd: Option<(Vec<u8>, Option<u64>)>,
+    /// A device tree given on the command line. Without it, one is generated.
+    dtb: Option<(Fdt, Option<u64>)>,
+    bootargs: Option<String>,
+}
+
+impl Boot {
+    /// Read the program and the boot images given in `config`.
+    pub fn open(config: &Config) -> io::Result<Boot> {
+        let program = config.program.as_deref().map(Program::open).transpose()?;
+        let kernel = match &config.kernel {
+            Some(ImageArg { path, addr }) => {
+                let kernel = Program::open(path)?;
+                if addr.is_some() && matches!(kernel, Program::Elf(_)) {
+                    return Err(invalid("an ELF kernel is loaded at its own addresses"));
+                }
+                Some((kernel, *addr))
+            }
+            None => None,
+        };
+        let initrd = match &config.initrd {
+            Some(ImageArg { path, addr }) => Some((fs::read(path)?, *addr)),
+            None => None,
+        };
+        let dtb = match &config.dtb {
+            Some(ImageArg { path, addr }) => Some((Fdt::parse(&fs::read(path)?)?, *addr)),
+            None => None,
+        };
+        let bootargs = config.append.clone();
+        Ok(Boot { program, kernel, initrd, dtb, bootargs })
+    }
+
+    /// Return the symbols of the program, or of the kernel if there is no program.
+    pub fn symbols(&self) -> io::Result<SymbolTable> {
+        match (&self.program, &self.kernel) {
+            (Some(program), _) | (None, Some((program, _))) => program.symbols(),
+            (None, None) => Ok(SymbolTable::default()),
+        }
+    }
+
+    /// Return the line table of the program, or of the kernel if there is no program.
+    pub fn lines(&self) -> io::Result<LineTable> {
+        match (&self.program, &self.kernel) {
+            (Some(program), _) | (None, Some((program, _))) => program.lines(),
+            (None, None) => Ok(LineTable::default()),
+        }
+    }
+
+    /// Place every image in the dram and set the boot ROM up to jump to the program, or to the
+    /// kernel if there is no program. Images without an address follow the kernel, and the device
+    /// tree goes to the end of the dram. The ROM passes a0 = hartid and a1 = device tree.
+    /// Return the device tree blob handed to the hart.
+    pub fn load(&self, cpu: &mut CPU) -> io::Result<Vec<u8>> {
+        let mut layout = Layout { used: Vec::new() };
+        let mut entry = None;
+
+        if let Some(program) = &self.program {
+            let addr = program.fixed_addr()?.unwrap_or(DRAM_BASE);
+            layout.claim("program", program.span(addr)?)?;
+            entry = Some(program.load(&mut cpu.bus, addr)?);
+        }
+
+        let mut next = DRAM_BASE;
+        if let Some((kernel, addr)) = &self.kernel {
+            // A firmware expects the kernel at the next 2 MiB boundary after itself, unless the
+            // kernel says where it goes.
+            let addr = addr.or(kernel.fixed_addr()?).unwrap_or_else(|| align_up(layout.end(), KERNEL_ALIGN));
+            let span = kernel.span(addr)?;
+            layout.claim("kernel", span.clone())?;
+            let kernel_entry = kernel.load(&mut cpu.bus, addr)?;
+            entry = entry.or(Some(kernel_entry));
+            next = span.end;
+        }
+
+        let mut initrd_span = None;
+        if let Some((initrd, addr)) = &self.initrd {
+            // Keep the initrd well away from the kernel, which grows when it unpacks itself.
+            let addr = addr.unwrap_or_else(|| (DRAM_BASE + DRAM_SIZE / 2).max(align_up(next, PAGE_SIZE)));
+            let span = span("initrd", addr, initrd.len())?;
+            layout.claim("initrd", span.clone())?;
+            cpu.bus.store_bytes(addr, initrd).map_err(|_| invalid("initrd does not fit in the dram"))?;
+            initrd_span = Some(span);
+        }
+
+        let (mut fdt, addr) = match &self.dtb {
+            Some((fdt, addr)) => (fdt.clone(), *addr),
+            None => (devicetree::generate(&cpu.bus), None),
+        };
+        let chosen = fdt.root.child_mut("chosen");
+        if let Some(bootargs) = &self.bootargs {
+            chosen.set_str("bootargs", bootargs);
+        }
+        if let Some(span) = &initrd_span {
+            chosen.set_u64("linux,initrd-start", span.start);
+            chosen.set_u64("linux,initrd-end", span.end);
+        }
+        let blob = fdt.to_bytes();
+        let addr = addr.unwrap_or_else(|| align_down((DRAM_END + 1).saturating_sub(blob.len() as u64), FDT_ALIGN));
+        layout.claim("device tree", span("device tree", addr, blob.len())?)?;
+        cpu.bus.store_bytes(addr, &blob).map_err(|_| invalid("device tree does not fit in the dram"))?;
+
+        let entry = entry.ok_or_else(|| invalid("nothing to run"))?;
+        cpu.bus.rom = Rom::new(entry, addr);
+        cpu.pc = ROM_BASE;
+        Ok(blob)
+    }
+}
+
+/// The parts of the dram that are taken by images.
+struct Layout {
+    used: Vec<(&'static str, Range<u64>)>,
+}
+
+impl Layout {
+    /// Take `span` for the image called `name`, failing if it is not in the dram or overlaps
+    /// another image.
+    fn claim(&mut self, name: &'static str, span: Range<u64>) -> io::Result<()> {
+        if span.start < DRAM_BASE || span.end > DRAM_END + 1 {
+            return Err(invalid(&format!("{} at {:#x} does not fit in the dram", name, span.start)));
+        }
+        if let Some((other, _)) = self.used.iter().find(|(_, r)| r.start < span.end && span.start < r.end) {
+            return Err(invalid(&format!("{} at {:#x} overlaps the {}", name, span.start, other)));
+        }
+        self.used.push((name, span));
+        Ok(())
+    }
+
+    /// Return the end of the highest image.
+    fn end(&self) -> u64 {
+        self.used.iter().map(|(_, r)| r.end).max().unwrap_or(DRAM_BASE)
+    }
+}
+
+/// Return true if `data` starts with the header of a Linux `Image`.
+fn is_image(data: &[u8]) -> bool {
+    data.len() >= IMAGE_HEADER_SIZE
+        && (&data[IMAGE_MAGIC_OFFSET..IMAGE_MAGIC_OFFSET + 8] == IMAGE_MAGIC
+            || &data[IMAGE_MAGIC2_OFFSET..IMAGE_MAGIC2_OFFSET + 4] == IMAGE_MAGIC2)
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    addr.next_multiple_of(align)
+}
+
+fn align_down(addr: u64, align: u64) -> u64 {
+    addr - addr % align
+}
+
+/// Return the memory `len` bytes of `what` occupy at `addr`.
+fn span(what: &str, addr: u64, len: usize) -> io::Result<Range<u64>> {
+    match addr.checked_add(len as u64) {
+        Some(end) => Ok(addr..end),
+        None => Err(invalid(&format!("{} does not fit in the dram", what))),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(flags: u64) -> Vec<u8> {
+        let mut data = vec![0; IMAGE_HEADER_SIZE];
+        data[0x08..0x10].copy_from_slice(&0x20_0000u64.to_le_bytes());
+        data[0x10..0x18].copy_from_slice(&0x40_0000u64.to_le_bytes());
+        data[0x18..0x20].copy_from_slice(&flags.to_le_bytes());
+        data[IMAGE_MAGIC_OFFSET..IMAGE_MAGIC_OFFSET + 8].copy_from_slice(IMAGE_MAGIC);
+        data
+    }
+
+    #[test]
+    fn test_image() {
+        assert!(is_image(&image(0)));
+        let kernel = Program::image(image(0)).unwrap();
+        assert_eq!(kernel.fixed_addr().unwrap(), Some(DRAM_BASE + 0x20_0000));
+        assert_eq!(kernel.span(DRAM_BASE).unwrap(), DRAM_BASE..DRAM_BASE + 0x40_0000);
+        assert!(Program::image(image(IMAGE_FLAG_BE)).is_err());
+
+        // A header that puts the kernel past the end of the address space.
+        let mut data = image(0);
+        data[0x08..0x10].copy_from_slice(&u64::MAX.to_le_bytes());
+        data[0x10..0x18].copy_from_slice(&u64::MAX.to_le_bytes());
+        let kernel = Program::image(data).unwrap();
+        assert!(kernel.fixed_addr().is_err());
+        assert!(kernel.span(DRAM_BASE).is_err());
+    }
+
+    #[test]
+    fn test_initrd() {
+        let boot = |addr| Boot {
+            program: Some(Program::Raw(vec![0; 16])),
+            kernel: None,
+            initrd: Some((vec![0; 16], Some(addr))),
+            dtb: None,
+            bootargs: None,
+        };
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        assert!(boot(DRAM_BASE + 0x1000).load(&mut cpu).is_ok());
+        // Spans that wrap around the address space or leave the dram.
+        assert!(boot(u64::MAX - 8).load(&mut cpu).is_err());
+        assert!(boot(DRAM_END - 8).load(&mut cpu).is_err());
+        assert!(boot(DRAM_BASE + 8).load(&mut cpu).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! The lockstep module checks the emulator against a reference commit log, such as one written by
+//! Spike with `--log-commits`. Every retired instruction is compared with the next line of the
+//! reference, and the machine stops at the first instruction whose pc, privilege mode, bits,
+//! register and CSR writes or memory accesses differ.
+//!
+//! The comparison starts at the first instruction at the pc of the first line of the reference,
+//! so that a reference which leaves out the boot ROM, which differs between emulators, can be used.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Lines};
+use crate::bus::Shutdown;
+
+/// The number of lines of both traces shown before a divergence.
+const CONTEXT: usize = 8;
+/// The number of lines of the reference shown after a divergence.
+const LOOKAHEAD: usize = 3;
+
+/// A retired instruction, as a commit log line describes it.
+#[derive(Debug, PartialEq)]
+struct Commit {
+    mode: u64,
+    pc: u64,
+    inst: u64,
+    /// The registers and CSRs written, by name (e.g. `x5` or `c768_mstatus`), with their values.
+    writes: Vec<(String, u64)>,
+    /// The memory accessed, with the value written for a store.
+    mems: Vec<(u64, Option<u64>)>,
+}
+
+impl Commit {
+    /// Parse a commit log line. Return None for any other line, like the disassembly Spike
+    /// writes with `-l`.
+    fn parse(line: &str) -> Option<Commit> {
+        let (_, rest) = line.strip_prefix("core")?.split_once(':')?;
+        let mut tokens = rest.split_whitespace().peekable();
+        let mode = tokens.next()?.parse().ok()?;
+        let pc = hex(tokens.next()?)?;
+        let inst = hex(tokens.next()?.strip_prefix('(')?.strip_suffix(')')?)?;
+        let mut writes = Vec::new();
+        let mut mems = Vec::new();
+        while let Some(token) = tokens.next() {
+            if token == "mem" {
+                let addr = hex(tokens.next()?)?;
+                let value = match tokens.peek() {
+                    Some(value) if value.starts_with("0x") => Some(hex(tokens.next()?)?),
+                    _ => None,
+                };
+                mems.push((addr, value));
+            } else {
+                writes.push((String::from(token), hex(tokens.next()?)?));
+            }
+        }
+        Some(Commit { mode, pc, inst, writes, mems })
+    }
+
+    /// Describe how `self`, from the emulator, differs from the reference `expected`.
+    fn diff(&self, expected: &Commit) -> Option<String> {
+        if self.pc != expected.pc {
+            return Some(format!("pc is {:#x} but the reference is at {:#x}", self.pc, expected.pc));
+        }
+        if self.inst != expected.inst {
+            return Some(format!("instruction is {:#010x} but the reference has {:#010x}", self.inst, expected.inst));
+        }
+        if self.mode != expected.mode {
+            return Some(format!("privilege mode is {} but the reference runs in {}", self.mode, expected.mode));
+        }
+        for (name, value) in &expected.writes {
+            match self.writes.iter().find(|(n, _)| n == name) {
+                Some((_, v)) if v != value => {
+                    return Some(format!("{} is {:#x} but the reference wrote {:#x}", name, v, value));
+                }
+                Some(_) => (),
+                None => return Some(format!("{} is not written but the reference wrote {:#x}", name, value)),
+            }
+        }
+        if let Some((name, _)) = self.writes.iter().find(|(n, _)| !expected.writes.iter().any(|(e, _)| e == n)) {
+            return Some(format!("{} is written but not by the reference", name));
+        }
+        if self.mems != expected.mems {
+            return Some(String::from("memory accesses differ"));
+        }
+        None
+    }
+}
+
+fn hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+pub struct Lockstep {
+    reference: Lines<BufReader<File>>,
+    /// The next commit of the reference and its line number.
+    next: Option<(Commit, String, usize)>,
+    line_no: usize,
+    /// Set once the emulator reached the first instruction of the reference.
+    synced: bool,
+    /// The last lines of the emulator and of the reference that matched.
+    history: VecDeque<(String, String)>,
+    /// The number of instructions that matched.
+    matched: u64,
+    request: Option<Shutdown>,
+}
+
+impl Lockstep {
+    /// Open the reference commit log at `path`.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let reference = BufReader::new(File::open(path)?).lines();
+        let mut lockstep = Self {
+            reference,
+            next: None,
+            line_no: 0,
+            synced: false,
+            history: VecDeque::new(),
+            matched: 0,
+            request: None,
+        };
+        lockstep.advance()?;
+        Ok(lockstep)
+    }
+
+    /// Read the next commit of the reference.
+    fn advance(&mut self) -> io::Result<()> {
+        self.next = None;
+        for line in self.reference.by_ref() {
+            let line = line?;
+            self.line_no += 1;
+            if let Some(commit) = Commit::parse(&line) {
+                self.next = Some((commit, line, self.line_no));
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare the commit log `line` of the instruction that has just retired with the reference.
+    pub fn check(&mut self, line: &str) -> io::Result<()> {
+        if self.request.is_some() {
+            return Ok(());
+        }
+        let Some((expected, expected_line, line_no)) = self.next.take() else {
+            println!("Lockstep: the {} instructions of the reference matched", self.matched);
+            self.request = Some(Shutdown::PowerOff(0));
+            return Ok(());
+        };
+        let commit = Commit::parse(line).expect("the tracer writes commit lines");
+        if !self.synced && commit.pc != expected.pc {
+            self.next = Some((expected, expected_line, line_no));
+            return Ok(());
+        }
+        self.synced = true;
+        if let Some(reason) = commit.diff(&expected) {
+            self.report(&reason, line, &expected_line, line_no)?;
+            self.request = Some(Shutdown::PowerOff(1));
+            return Ok(());
+        }
+        self.matched += 1;
+        if self.history.len() == CONTEXT {
+            self.history.pop_front();
+        }
+        self.history.push_back((String::from(line), expected_line));
+        self.advance()
+    }
+
+    fn report(&mut self, reason: &str, line: &str, expected_line: &str, line_no: usize) -> io::Result<()> {
+        println!("{:-^80}", "lockstep divergence");
+        println!("After {} matching instructions, at line {} of the reference:", self.matched, line_no);
+        println!("{}\n", reason);
+        println!("emulator:");
+        for (line, _) in &self.history {
+            println!("  {}", line);
+        }
+        println!("> {}\n", line);
+        println!("reference:");
+        for (_, line) in &self.history {
+            println!("  {}", line);
+        }
+        println!("> {}", expected_line);
+        for _ in 0..LOOKAHEAD {
+            self.advance()?;
+            match &self.next {
+                Some((_, line, _)) => println!("  {}", line),
+                None => break,
+            }
+        }
+        println!();
+        Ok(())
+    }
+
+    /// Return the request to stop the machine, once the emulator diverged or the reference ended.
+    pub fn take_request(&mut self) -> Option<Shutdown> {
+        self.request.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_commit() {
+        let store = Commit::parse("core   0: 3 0x000000008000000c (0x0062a023) mem 0x0000000000100000 0x00073333").unwrap();
+        assert_eq!(store.mems, vec![(0x100000, Some(0x73333))]);
+        let load = Commit::parse("core   0: 3 0x0000000000001008 (0x0202b583) x11 0x0000000087e00000 mem 0x0000000000001020").unwrap();
+        assert_eq!(load.writes, vec![(String::from("x11"), 0x87e00000)]);
+        assert_eq!(load.mems, vec![(0x1020, None)]);
+        assert!(Commit::parse("core   0: 0x0000000000001000 (0x00000297) auipc   t0, 0x0").is_none());
+
+        let csr = Commit::parse("core   0: 1 0x0000000080000008 (0x10529073) c261_stvec 0x0000000080000054").unwrap();
+        let other = Commit::parse("core   0: 1 0x0000000080000008 (0x10529073) c261_stvec 0x0000000080000050").unwrap();
+        assert_eq!(csr.diff(&csr), None);
+        assert_eq!(other.diff(&csr).unwrap(), "c261_stvec is 0x80000050 but the reference wrote 0x80000054");
+    }
+}
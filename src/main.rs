@@ -9,60 +9,216 @@ mod clint;
 mod uart;
 mod interrupt;
 mod virtio;
+mod syscon;
+mod htif;
+mod elf;
+mod dwarf;
+mod config;
+mod coverage;
+mod crash;
+mod semihosting;
+mod rtc;
+mod loader;
+mod fdt;
+mod devicetree;
+mod rom;
+mod sbi;
+mod memory;
+#[cfg(unix)]
+mod syscall;
+mod watchpoint;
+mod gdb;
+mod monitor;
+mod disasm;
+mod trace;
+mod lockstep;
+mod profile;
+mod snapshot;
+mod stats;
+mod replay;
+mod reverse;
 
-use std::{env, io};
+use std::{env, fs, io, process};
 use std::fs::File;
 use std::io::Read;
+use crate::bus::Shutdown;
+use crate::config::{Config, USAGE};
+use crate::coverage::Coverage;
 use crate::cpu::CPU;
+use crate::elf::Elf;
+use crate::gdb::Gdb;
+use crate::htif::Htif;
+use crate::loader::Boot;
+use crate::monitor::Monitor;
+use crate::profile::Profiler;
+use crate::rtc::Rtc;
+use crate::replay::EventLog;
+use crate::reverse::Timeline;
+use crate::sbi::Sbi;
+use crate::semihosting::Semihosting;
+use crate::snapshot::Snapshotter;
+use crate::stats::Stats;
+#[cfg(unix)]
+use crate::syscall::Syscalls;
+use crate::trace::Tracer;
 
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
+    let config = match Config::parse(&args[1..]) {
+        Ok(config) => config,
+        Err(e) => panic!("{}\n{}", e, USAGE),
+    };
 
-    if (args.len() != 2) && (args.len() != 3) {
-        panic!("Usage: R-RISCV <filename> <(option) image>");
-    }
-    let mut file = File::open(&args[1])?;
-    let mut binary = Vec::new();
-    file.read_to_end(&mut binary)?;
+    let boot = Boot::open(&config)?;
 
     let mut disk_image = Vec::new();
-    if args.len() == 3 {
-        let mut file = File::open(&args[2])?;
+    if let Some(disk) = &config.disk {
+        let mut file = File::open(disk)?;
         file.read_to_end(&mut disk_image)?;
     }
 
-    let mut cpu = CPU::new(binary, disk_image);
-    loop {
-        let instr = match cpu.fetch() {
-            Ok(instr) => instr,
-            Err(e) => {
-                cpu.handle_exception(e);
-                if e.is_fatal() {
-                    println!("{}", e);
-                    break;
+    let mut cpu = CPU::new(Vec::new(), disk_image);
+
+    let symbols = match &config.symbols {
+        Some(path) => Elf::parse(fs::read(path)?)?.symbols()?,
+        None => boot.symbols()?,
+    };
+    if let Some(abi) = config.user {
+        // A program run in user mode gets an address space of its own instead of the machine.
+        #[cfg(unix)]
+        Syscalls::start(&mut cpu, abi, &config.args[0], &config.args)?;
+        // Config::parse takes --user and --pk only on a Unix host.
+        #[cfg(not(unix))]
+        unreachable!("{:?} user mode without a Unix host", abi);
+    } else {
+        // Find the HTIF words, either given directly or through the symbols of the program.
+        let tohost = config.tohost.or_else(|| symbols.lookup("tohost"));
+        let fromhost = config.fromhost.or_else(|| symbols.lookup("fromhost"));
+        if let Some(tohost) = tohost {
+            cpu.bus.set_htif(Htif::new(tohost, fromhost));
+        }
+        // The generated device tree describes the devices, so they are set up before loading.
+        let dtb = boot.load(&mut cpu)?;
+        if let Some(path) = &config.dump_dtb {
+            return fs::write(path, dtb);
+        }
+        if config.sbi {
+            cpu.sbi = Some(Sbi::new());
+            Sbi::boot(&mut cpu);
+        }
+    }
+    cpu.bus.rtc = Rtc::new(config.rtc);
+    cpu.watchpoints = config.watchpoints.clone();
+    if let Some(path) = &config.restore {
+        snapshot::restore(&mut cpu, path)?;
+    }
+    if let Some(root) = &config.semihosting {
+        let cmdline = config.semihosting_cmdline.clone()
+            .or_else(|| config.program.clone())
+            .unwrap_or_default();
+        cpu.semihosting = Some(Semihosting::new(root.into(), cmdline));
+    }
+    if config.trace.is_some() || config.lockstep.is_some() {
+        let mut tracer = Tracer::default();
+        if let Some(path) = &config.trace {
+            tracer.write_to(path, config.trace_filter.clone())?;
+        }
+        if let Some(path) = &config.lockstep {
+            tracer.check_against(path)?;
+        }
+        cpu.tracer = Some(tracer);
+    }
+    if let Some(path) = &config.coverage {
+        let lines = match &config.symbols {
+            Some(elf) => Elf::parse(fs::read(elf)?)?.lines()?,
+            None => boot.lines()?,
+        };
+        cpu.coverage = Some(Coverage::new(path.clone(), lines));
+    }
+    if let Some(path) = &config.stats {
+        cpu.stats = Some(Stats::new(path.clone()));
+    }
+    if let Some(path) = &config.profile {
+        cpu.profiler = Some(Profiler::new(path.clone(), symbols.clone(), config.profile_top, config.profile_stacks));
+    }
+    // Recording starts after a restore, so that a snapshot and a log replay together.
+    let mut events = match (&config.record, &config.replay) {
+        (Some(path), _) => Some(EventLog::record(&mut cpu, path)?),
+        (None, Some(path)) => Some(EventLog::replay(&mut cpu, path)?),
+        (None, None) => None,
+    };
+    let mut snapshotter = config.snapshot.clone().map(|path| Snapshotter::new(path, config.snapshot_at));
+    let mut timeline = config.reverse.then(|| Timeline::new(&mut cpu));
+    let mut gdb = match &config.gdb {
+        Some(addr) => Some(Gdb::listen(addr)?),
+        None => None,
+    };
+    let mut monitor = match config.monitor {
+        true => Some(Monitor::new(symbols.clone(), config.paused)),
+        false => None,
+    };
+    if monitor.is_some() {
+        cpu.bus.uart.enable_escape();
+    }
+    let fault = loop {
+        if let Some(snapshotter) = &mut snapshotter {
+            snapshotter.before_step(&cpu)?;
+        }
+        if let Some(monitor) = &mut monitor {
+            monitor.before_step(&mut cpu);
+        }
+        // gdb sees the hart before a stop or restart the guest asked for, so that it can catch
+        // the access that asked for it.
+        if let Some(gdb) = &mut gdb {
+            gdb.before_step(&mut cpu, timeline.as_mut())?;
+        }
+        match cpu.shutdown() {
+            Some(Shutdown::PowerOff(code)) => {
+                if let Some(gdb) = &mut gdb {
+                    gdb.exit(code)?;
                 }
-                continue;
+                cpu.flush_outputs();
+                process::exit(code)
             }
-        };
-        match cpu.execute(instr) {
-            // Break the loop if an error occurs.
-            Ok(new_pc) => cpu.pc = new_pc,
-            Err(e) => {
-                cpu.handle_exception(e);
-                if e.is_fatal() {
-                    println!("{}", e);
-                    break;
+            Some(Shutdown::Reboot) => {
+                cpu.reset();
+                boot.load(&mut cpu)?;
+                if cpu.sbi.is_some() {
+                    Sbi::boot(&mut cpu);
+                }
+                if timeline.is_some() {
+                    timeline = Some(Timeline::new(&mut cpu));
                 }
+                continue;
             }
-        };
-        match cpu.check_pending_interrupt() {
-            Some(interrupt) => cpu.handle_interrupt(interrupt),
-            None => ()
+            None => (),
         }
-    }
+        if let Some(events) = &mut events {
+            events.before_step(&mut cpu)?;
+        }
+        if let Some(timeline) = &mut timeline {
+            timeline.before_step(&mut cpu);
+        }
+        let result = cpu.step();
+        if let Some(events) = &mut events {
+            events.after_step(&mut cpu)?;
+        }
+        if let Some(timeline) = &mut timeline {
+            timeline.after_step(&mut cpu);
+        }
+        if let Err(e) = result {
+            println!("{}", e);
+            if let Some(gdb) = &mut gdb {
+                gdb.fault(&mut cpu, timeline.as_mut())?;
+            }
+            break e;
+        }
+    };
+    cpu.flush_outputs();
     cpu.dump_registers();
     cpu.dump_csrs();
     cpu.dump_pc();
+    crash::report(&mut cpu, fault, &symbols);
 
     Ok(())
 }
@@ -0,0 +1,156 @@
+//! The memory module provides the flat address space of a program run in user mode. It covers
+//! the whole 64-bit range with no devices in it. Pages are allocated when they are first touched,
+//! and only inside the regions the program has mapped, so that stray accesses still fault.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use crate::exception::Exception;
+use crate::exception::Exception::{LoadAccessFault, StoreAMOAccessFault};
+use crate::param::PAGE_SIZE;
+
+pub struct SparseMemory {
+    /// Contents of the pages that have been touched, by address.
+    pages: HashMap<u64, Box<[u8]>>,
+    /// The mapped regions, page-aligned and not overlapping.
+    regions: Vec<Range<u64>>,
+}
+
+impl SparseMemory {
+    pub fn new() -> Self {
+        Self { pages: HashMap::new(), regions: Vec::new() }
+    }
+
+    /// Map `range`, rounded out to whole pages. Anything that was mapped there before is
+    /// replaced by zeros.
+    pub fn map(&mut self, range: Range<u64>) {
+        let range = page_range(range);
+        self.unmap(range.clone());
+        if !range.is_empty() {
+            self.regions.push(range);
+        }
+    }
+
+    /// Unmap `range`, rounded out to whole pages.
+    pub fn unmap(&mut self, range: Range<u64>) {
+        let range = page_range(range);
+        let mut regions = Vec::new();
+        for region in self.regions.drain(..) {
+            if region.end <= range.start || range.end <= region.start {
+                regions.push(region);
+                continue;
+            }
+            if region.start < range.start {
+                regions.push(region.start..range.start);
+            }
+            if range.end < region.end {
+                regions.push(range.end..region.end);
+            }
+        }
+        self.regions = regions;
+        self.pages.retain(|page, _| !range.contains(page));
+    }
+
+    /// Return true if any byte of `range` is mapped.
+    pub fn overlaps(&self, range: Range<u64>) -> bool {
+        let range = page_range(range);
+        self.regions.iter().any(|r| r.start < range.end && range.start < r.end)
+    }
+
+    /// Return true if every byte of `range` is mapped.
+    pub fn is_mapped(&self, range: Range<u64>) -> bool {
+        let range = page_range(range);
+        (range.start..range.end).step_by(PAGE_SIZE as usize)
+            .all(|page| self.pages.contains_key(&page) || self.regions.iter().any(|r| r.contains(&page)))
+    }
+
+    /// Return `len` bytes at `addr` if they are mapped and within one page.
+    fn access(&mut self, addr: u64, len: usize) -> Option<&mut [u8]> {
+        let offset = (addr % PAGE_SIZE) as usize;
+        if offset + len > PAGE_SIZE as usize {
+            return None;
+        }
+        let page = addr - offset as u64;
+        if !self.pages.contains_key(&page) {
+            if !self.regions.iter().any(|r| r.contains(&page)) {
+                return None;
+            }
+            self.pages.insert(page, vec![0; PAGE_SIZE as usize].into_boxed_slice());
+        }
+        self.pages.get_mut(&page).map(|p| &mut p[offset..offset + len])
+    }
+
+    /// Copy the bytes at `addr` into `buf`. Return false if any of them is not mapped.
+    pub fn read(&mut self, addr: u64, buf: &mut [u8]) -> bool {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = addr.wrapping_add(done as u64);
+            let len = (buf.len() - done).min((PAGE_SIZE - at % PAGE_SIZE) as usize);
+            match self.access(at, len) {
+                Some(bytes) => buf[done..done + len].copy_from_slice(bytes),
+                None => return false,
+            }
+            done += len;
+        }
+        true
+    }
+
+    /// Copy `data` to `addr`. Return false if any of the bytes is not mapped, in which case
+    /// nothing is written.
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> bool {
+        if !self.is_mapped(addr..addr.wrapping_add(data.len() as u64)) {
+            return false;
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let at = addr + done as u64;
+            let len = (data.len() - done).min((PAGE_SIZE - at % PAGE_SIZE) as usize);
+            self.access(at, len).unwrap().copy_from_slice(&data[done..done + len]);
+            done += len;
+        }
+        true
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let mut bytes = [0; 8];
+        let len = size as usize / 8;
+        if !matches!(size, 8 | 16 | 32 | 64) || !self.read(addr, &mut bytes[..len]) {
+            return Err(LoadAccessFault(addr));
+        }
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let len = size as usize / 8;
+        if !matches!(size, 8 | 16 | 32 | 64) || !self.write(addr, &value.to_le_bytes()[..len]) {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        Ok(())
+    }
+}
+
+/// Round `range` out to whole pages.
+fn page_range(range: Range<u64>) -> Range<u64> {
+    let start = range.start - range.start % PAGE_SIZE;
+    let end = range.end.checked_next_multiple_of(PAGE_SIZE).unwrap_or(u64::MAX - PAGE_SIZE + 1);
+    start..end.max(start)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_map() {
+        let mut memory = SparseMemory::new();
+        assert!(memory.load(0x1000, 8).is_err());
+        memory.map(0x1000..0x3000);
+        memory.store(0x1ffc, 64, 0x1122_3344_5566_7788).unwrap();
+        assert_eq!(memory.load(0x1ffc, 64).unwrap(), 0x1122_3344_5566_7788);
+        memory.unmap(0x2000..0x3000);
+        assert!(memory.load(0x2000, 8).is_err());
+        assert_eq!(memory.load(0x1ffc, 32).unwrap(), 0x5566_7788);
+        assert!(!memory.write(0x1fff, &[0, 0]));
+        assert!(memory.overlaps(0x1800..0x1900));
+        assert!(!memory.overlaps(0x2000..0x4000));
+    }
+}
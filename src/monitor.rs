@@ -0,0 +1,268 @@
+//! The monitor module is an interactive console to inspect and control the machine, like the
+//! monitor of QEMU. It opens when Ctrl-A c is typed on the console, at a breakpoint, after an
+//! access to a watchpoint of the command line that pauses, or before the first instruction if the
+//! machine starts paused. The guest is stopped while it is open, and the console input goes to
+//! the monitor until the guest resumes.
+
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::process;
+use crate::config::parse_u64;
+use crate::cpu::{Register, CPU};
+use crate::disasm::disassemble;
+use crate::elf::SymbolTable;
+
+const HELP: &str = "Commands:
+    c, continue              resume the guest
+    s, step [n]              execute n instructions, 1 by default
+    b, break <addr>          stop before the instruction at <addr>
+    d, delete <addr>         remove a breakpoint
+    info breakpoints         list the breakpoints
+    info registers           print the registers, the CSRs and the pc
+    info <device>            print the state of the plic, clint, uart or virtio device
+    p, print <reg>           print a register, e.g. a0, x10, pc, priv or mstatus
+    set <reg> <value>        change a register
+    x <addr> [len]           examine the memory at a virtual address
+    xp <addr> [len]          examine the dram at a physical address
+    w <addr> <value> [size]  write a value of 1, 2, 4 or 8 bytes at a virtual address
+    dis [addr] [n]           list the instructions at <addr>, or around the pc
+    q, quit                  exit the emulator
+An address is a number, a symbol of the program or a register.";
+
+// Defaults of the examine and list commands.
+const EXAMINE_LEN: u64 = 64;
+const LIST_COUNT: u64 = 8;
+
+pub struct Monitor {
+    symbols: SymbolTable,
+    breakpoints: BTreeSet<u64>,
+    /// Instructions left to execute before opening again, when stepping.
+    steps: Option<u64>,
+}
+
+impl Monitor {
+    /// Create a monitor that finds the addresses of symbols in `symbols`. If `paused` is set, it
+    /// opens before the first instruction.
+    pub fn new(symbols: SymbolTable, paused: bool) -> Self {
+        Self { symbols, breakpoints: BTreeSet::new(), steps: paused.then_some(0) }
+    }
+
+    /// Open the monitor if it is due before the instruction at pc.
+    pub fn before_step(&mut self, cpu: &mut CPU) {
+        let stepped = match self.steps {
+            Some(0) => true,
+            Some(n) => {
+                self.steps = Some(n - 1);
+                false
+            }
+            None => false,
+        };
+        // A watchpoint that pauses has logged the access already.
+        let watched = std::mem::take(&mut cpu.watch_pause);
+        if cpu.bus.uart.take_escape() || stepped || watched {
+            self.run(cpu);
+        } else if self.breakpoints.contains(&cpu.pc) {
+            println!("Breakpoint at {:#x}", cpu.pc);
+            self.run(cpu);
+        }
+    }
+
+    /// Serve commands until the guest resumes.
+    fn run(&mut self, cpu: &mut CPU) {
+        self.steps = None;
+        self.list(cpu, cpu.pc, 1);
+        loop {
+            print!("(monitor) ");
+            io::stdout().flush().unwrap();
+            // Without more input, there is nothing left to do but to let the guest run.
+            let Some(line) = cpu.bus.uart.read_line() else { break };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match self.command(cpu, &words) {
+                Ok(true) => break,
+                Ok(false) => (),
+                Err(e) => println!("{}", e),
+            }
+        }
+        cpu.bus.uart.release_console();
+    }
+
+    /// Run a command. Return true if the guest resumes.
+    fn command(&mut self, cpu: &mut CPU, words: &[&str]) -> Result<bool, String> {
+        match words {
+            [] => (),
+            ["h" | "help"] => println!("{}", HELP),
+            ["c" | "continue"] => return Ok(true),
+            ["s" | "step", count @ ..] => {
+                let count = match count {
+                    [] => 1,
+                    [count] => parse_u64(count)?,
+                    _ => return Err(String::from("Usage: step [n]")),
+                };
+                self.steps = Some(count.max(1) - 1);
+                return Ok(true);
+            }
+            ["b" | "break", at] => {
+                let addr = self.addr(cpu, at)?;
+                self.breakpoints.insert(addr);
+                println!("Breakpoint at {:#x}", addr);
+            }
+            ["d" | "delete", at] => {
+                let addr = self.addr(cpu, at)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("No breakpoint at {:#x}", addr));
+                }
+            }
+            ["info", "b" | "breakpoints"] => {
+                for addr in &self.breakpoints {
+                    println!("{:#x}", addr);
+                }
+            }
+            ["info", "r" | "registers"] => {
+                cpu.dump_registers();
+                cpu.dump_csrs();
+                cpu.dump_pc();
+            }
+            ["info", device] => {
+                if !cpu.bus.dump_device(device) {
+                    return Err(format!("Unknown device {}", device));
+                }
+            }
+            ["p" | "print", name] => println!("{} = {:#x}", name, cpu.read_register(register(name)?)),
+            ["set", name, value] => {
+                let value = self.addr(cpu, value)?;
+                if !cpu.write_register(register(name)?, value) {
+                    return Err(format!("Invalid value {:#x} for {}", value, name));
+                }
+            }
+            [command @ ("x" | "xp"), at, len @ ..] => {
+                let addr = self.addr(cpu, at)?;
+                let len = match len {
+                    [] => EXAMINE_LEN,
+                    [len] => parse_u64(len)?,
+                    _ => return Err(format!("Usage: {} <addr> [len]", command)),
+                };
+                let mut buf = vec![0; len.min(0x10000) as usize];
+                // Physical reads stay in the dram, since reading a device would change its state.
+                let result = match *command {
+                    "x" => cpu.load_bytes(addr, &mut buf),
+                    _ => cpu.bus.load_bytes(addr, &mut buf),
+                };
+                result.map_err(|e| format!("Cannot access memory: {}", e))?;
+                hexdump(addr, &buf);
+            }
+            ["w", at, value, size @ ..] => {
+                let (addr, value) = (self.addr(cpu, at)?, self.addr(cpu, value)?);
+                let size = match size {
+                    [] => 8,
+                    [size] => parse_u64(size)?,
+                    _ => return Err(String::from("Usage: w <addr> <value> [size]")),
+                };
+                if ![1, 2, 4, 8].contains(&size) {
+                    return Err(format!("Invalid size {}", size));
+                }
+                cpu.store_bytes(addr, &value.to_le_bytes()[..size as usize])
+                    .map_err(|e| format!("Cannot access memory: {}", e))?;
+            }
+            ["dis", args @ ..] => {
+                let (addr, count) = match args {
+                    [] => (cpu.pc.saturating_sub(LIST_COUNT / 2 * 4), LIST_COUNT),
+                    [at] => (self.addr(cpu, at)?, LIST_COUNT),
+                    [at, count] => (self.addr(cpu, at)?, parse_u64(count)?),
+                    _ => return Err(String::from("Usage: dis [addr] [n]")),
+                };
+                self.list(cpu, addr, count);
+            }
+            ["q" | "quit"] => {
+                cpu.flush_outputs();
+                process::exit(0)
+            }
+            _ => return Err(format!("Unknown command {}, try help", words.join(" "))),
+        }
+        Ok(false)
+    }
+
+    /// Return the address `at` stands for: a number, a symbol or the value of a register.
+    fn addr(&self, cpu: &CPU, at: &str) -> Result<u64, String> {
+        parse_u64(at)
+            .or_else(|e| self.symbols.lookup(at).ok_or(e))
+            .or_else(|e| register(at).map(|r| cpu.read_register(r)).map_err(|_| e))
+    }
+
+    /// Print `count` instructions from `addr`, and mark the one at pc.
+    fn list(&self, cpu: &mut CPU, addr: u64, count: u64) {
+        for i in 0..count {
+            let addr = addr.wrapping_add(i * 4);
+            let marker = if addr == cpu.pc { "=>" } else { "  " };
+            let mut inst = [0; 4];
+            match cpu.load_bytes(addr, &mut inst) {
+                Ok(()) => {
+                    let inst = u32::from_le_bytes(inst);
+                    println!("{} {:#018x}: {:08x}  {}", marker, addr, inst, disassemble(inst as u64, addr));
+                }
+                Err(e) => {
+                    println!("{} {:#018x}: {}", marker, addr, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn register(name: &str) -> Result<Register, String> {
+    Register::by_name(name).ok_or_else(|| format!("Unknown register {}", name))
+}
+
+/// Print `bytes` at `addr`, 16 to a line, in hex and as text.
+pub fn hexdump(addr: u64, bytes: &[u8]) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = line.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        println!("{:#018x}: {:<47}  {}", addr.wrapping_add(i as u64 * 16), hex.join(" "), text);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::Symbol;
+    use crate::param::{DRAM_BASE, UART_BASE};
+
+    #[test]
+    fn test_commands() {
+        let symbols = vec![Symbol { name: String::from("main"), addr: DRAM_BASE + 0x100, size: 4, code: true }];
+        let mut monitor = Monitor::new(SymbolTable::new(symbols), false);
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+
+        assert_eq!(monitor.command(&mut cpu, &["b", "main"]), Ok(false));
+        assert_eq!(monitor.command(&mut cpu, &["break", "0x80000200"]), Ok(false));
+        assert!(monitor.breakpoints.contains(&(DRAM_BASE + 0x100)));
+        assert_eq!(monitor.command(&mut cpu, &["d", "0x80000200"]), Ok(false));
+        assert!(monitor.command(&mut cpu, &["d", "0x80000200"]).is_err());
+        assert_eq!(monitor.breakpoints.len(), 1);
+
+        assert_eq!(monitor.command(&mut cpu, &["set", "a0", "main"]), Ok(false));
+        assert_eq!(monitor.command(&mut cpu, &["set", "x11", "a0"]), Ok(false));
+        assert_eq!(monitor.command(&mut cpu, &["set", "mepc", "0x10"]), Ok(false));
+        assert_eq!(monitor.command(&mut cpu, &["set", "zero", "1"]), Ok(false));
+        assert_eq!((cpu.regs[10], cpu.regs[11], cpu.regs[0]), (DRAM_BASE + 0x100, DRAM_BASE + 0x100, 0));
+        assert_eq!(cpu.read_register(Register::Csr(crate::csr::MEPC)), 0x10);
+        assert!(monitor.command(&mut cpu, &["set", "priv", "2"]).is_err());
+        assert!(monitor.command(&mut cpu, &["set", "x32", "1"]).is_err());
+        assert!(monitor.command(&mut cpu, &["p", "nothing"]).is_err());
+
+        assert_eq!(monitor.command(&mut cpu, &["w", "0x80001000", "0x1234", "2"]), Ok(false));
+        assert_eq!(cpu.bus.load(DRAM_BASE + 0x1000, 64).unwrap(), 0x1234);
+        assert!(monitor.command(&mut cpu, &["w", "0x80001000", "1", "3"]).is_err());
+        assert_eq!(monitor.command(&mut cpu, &["xp", "0x80001000", "16"]), Ok(false));
+        // A device is not read, since the read would change its state.
+        assert!(monitor.command(&mut cpu, &["xp", &UART_BASE.to_string()]).is_err());
+        assert!(monitor.command(&mut cpu, &["xp", "0x80001000", "1", "2"]).is_err());
+
+        assert_eq!(monitor.command(&mut cpu, &["s", "3"]), Ok(true));
+        assert_eq!(monitor.steps, Some(2));
+        assert_eq!(monitor.command(&mut cpu, &["c"]), Ok(true));
+        assert!(monitor.command(&mut cpu, &["frobnicate"]).is_err());
+    }
+}
@@ -2,6 +2,44 @@ pub const DRAM_BASE: u64 = 0x8000_0000;
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 pub const DRAM_END: u64 = DRAM_SIZE + DRAM_BASE - 1;
 
+// The address which the boot ROM starts. The hart starts executing here, like on QEMU's virt machine.
+pub const ROM_BASE: u64 = 0x1000;
+pub const ROM_SIZE: u64 = 0xf000;
+pub const ROM_END: u64 = ROM_BASE + ROM_SIZE - 1;
+
+// The ISA string reported to the guest in the device tree.
+pub const RISCV_ISA: &str = "rv64ima_zicsr";
+
+// The address which the SiFive test device starts. It doubles as the syscon that the syscon-poweroff and
+// syscon-reboot drivers write to, so a guest can stop or restart the machine.
+pub const SYSCON_BASE: u64 = 0x10_0000;
+pub const SYSCON_SIZE: u64 = 0x1000;
+pub const SYSCON_END: u64 = SYSCON_BASE + SYSCON_SIZE - 1;
+
+// Values written to the test device. The upper 16 bits of a fail value hold the exit code.
+pub const SYSCON_FAIL: u64 = 0x3333;
+pub const SYSCON_PASS: u64 = 0x5555;
+pub const SYSCON_RESET: u64 = 0x7777;
+
+// The address which the goldfish real-time clock starts. Time is counted in nanoseconds since 1970.
+pub const RTC_BASE: u64 = 0x10_1000;
+pub const RTC_SIZE: u64 = 0x1000;
+pub const RTC_END: u64 = RTC_BASE + RTC_SIZE - 1;
+// rtc interrupt request
+pub const RTC_IRQ: u64 = 11;
+
+// Reading the low half latches the high half of the time.
+pub const RTC_TIME_LOW: u64 = RTC_BASE;
+pub const RTC_TIME_HIGH: u64 = RTC_BASE + 0x04;
+// Writing the low half arms the alarm.
+pub const RTC_ALARM_LOW: u64 = RTC_BASE + 0x08;
+pub const RTC_ALARM_HIGH: u64 = RTC_BASE + 0x0c;
+pub const RTC_IRQ_ENABLED: u64 = RTC_BASE + 0x10;
+pub const RTC_CLEAR_ALARM: u64 = RTC_BASE + 0x14;
+// 1 if the alarm is armed.
+pub const RTC_ALARM_STATUS: u64 = RTC_BASE + 0x18;
+pub const RTC_CLEAR_INTERRUPT: u64 = RTC_BASE + 0x1c;
+
 // The address which the core-local interruptor (CLINT) starts. It contains the timer and
 // generates per-hart software interrupts and timer interrupts.
 pub const CLINT_BASE: u64 = 0x200_0000;
@@ -11,11 +49,16 @@ pub const CLINT_END: u64 = CLINT_BASE + CLINT_SIZE - 1;
 pub const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
 pub const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
 
+// The frequency of mtime, reported to the guest in the device tree.
+pub const TIMEBASE_FREQ: u64 = 10_000_000;
+
 // The address which the platform-level interrupt controller (PLIC) starts. The PLIC connects all external interrupts in the
 // system to all hart contexts in the system, via the external interrupt source in each hart.
 pub const PLIC_BASE: u64 = 0xc00_0000;
 pub const PLIC_SIZE: u64 = 0x4000000;
 pub const PLIC_END: u64 = PLIC_BASE + PLIC_SIZE - 1;
+// The number of interrupt sources of the PLIC.
+pub const PLIC_NDEV: u64 = 0x35;
 
 pub const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
 pub const PLIC_SENABLE: u64 = PLIC_BASE + 0x2000;
@@ -28,6 +71,8 @@ pub const UART_SIZE: u64 = 0x100;
 pub const UART_END: u64 = UART_BASE + UART_SIZE - 1;
 // uart interrupt request
 pub const UART_IRQ: u64 = 10;
+// The input clock of the 16550, reported to the guest in the device tree.
+pub const UART_CLOCK_FREQ: u64 = 0x384000;
 // Receive holding register (for input bytes).
 pub const UART_RHR: u64 = 0;
 // Transmit holding register (for output bytes).
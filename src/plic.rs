@@ -1,6 +1,8 @@
+use std::io;
 use crate::exception::Exception;
 use crate::exception::Exception::{LoadAccessFault, StoreAMOAccessFault};
 use crate::param::*;
+use crate::snapshot::{Reader, Writer};
 
 pub struct PLIC {
     pending: u64,
@@ -13,6 +15,28 @@ impl PLIC {
     pub fn new() -> Self {
         Self { pending: 0, senable: 0, spriority: 0, sclaim: 0 }
     }
+
+    pub fn dump(&self) {
+        println!("{:-^80}", "plic");
+        println!(
+            "pending = {:<#18x}  senable = {:<#18x}  spriority = {:<#18x}  sclaim = {:<#18x}\n",
+            self.pending, self.senable, self.spriority, self.sclaim,
+        );
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        for value in [self.pending, self.senable, self.spriority, self.sclaim] {
+            w.u64(value);
+        }
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        for value in [&mut self.pending, &mut self.senable, &mut self.spriority, &mut self.sclaim] {
+            *value = r.u64()?;
+        }
+        Ok(())
+    }
+
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
         if size != 32 {
             return Err(LoadAccessFault(addr));
@@ -0,0 +1,243 @@
+//! The profile module counts the instructions the guest retires, by pc, to find where its time
+//! goes. The counts are attributed to the functions of the program's symbol table, and optionally
+//! to call stacks reconstructed from the calls and returns of the calling convention: a `jal` or
+//! `jalr` that links through ra or t0 is a call, and a `jalr` through one of them that does not
+//! link is a return.
+//!
+//! The profile is written as folded stacks, one stack and its count per line, which tools like
+//! flamegraph.pl and speedscope read, and the functions that retired the most instructions are
+//! printed when the emulator exits:
+//!
+//! ```text
+//! _start;main;memcpy 52311
+//! ```
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use crate::elf::SymbolTable;
+
+/// How deep the reconstructed call stacks go. Deeper calls are counted in their caller, which
+/// bounds the profile of a guest whose calls and returns do not pair up, like a kernel that
+/// switches stacks.
+const MAX_DEPTH: usize = 128;
+
+/// Return true if `reg` holds a return address by the calling convention.
+fn is_link(reg: u64) -> bool {
+    reg == 1 || reg == 5
+}
+
+pub struct Profiler {
+    path: String,
+    symbols: SymbolTable,
+    /// The number of functions printed at exit.
+    top: usize,
+    /// Whether the call stacks are reconstructed.
+    stacks: bool,
+    /// The frames of the call stacks seen, as their caller and the pc of the call. The first
+    /// frame is the root, where the guest starts.
+    frames: Vec<(usize, u64)>,
+    /// The frames by their caller and the pc of the call.
+    callees: HashMap<(usize, u64), usize>,
+    /// The current frame and its depth.
+    frame: usize,
+    depth: usize,
+    /// The instructions retired, by frame and pc.
+    counts: HashMap<(usize, u64), u64>,
+}
+
+impl Profiler {
+    /// Profile into the file at `path`, with the functions of `symbols`, and print the `top`
+    /// functions at exit. The call stacks are reconstructed if `stacks` is set.
+    pub fn new(path: String, symbols: SymbolTable, top: usize, stacks: bool) -> Self {
+        Self {
+            path,
+            symbols,
+            top,
+            stacks,
+            frames: vec![(0, 0)],
+            callees: HashMap::new(),
+            frame: 0,
+            depth: 0,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Count the instruction `inst` at `pc` that has just retired, and follow the call or return
+    /// it makes.
+    pub fn retire(&mut self, pc: u64, inst: u64) {
+        *self.counts.entry((self.frame, pc)).or_insert(0) += 1;
+        if !self.stacks {
+            return;
+        }
+        let rd = (inst >> 7) & 0x1f;
+        let rs1 = (inst >> 15) & 0x1f;
+        match inst & 0x7f {
+            // jal
+            0x6f if is_link(rd) => self.call(pc),
+            // jalr
+            0x67 => match (is_link(rd), is_link(rs1)) {
+                (true, false) => self.call(pc),
+                (false, true) => self.ret(),
+                (true, true) if rd != rs1 => {
+                    self.ret();
+                    self.call(pc);
+                }
+                (true, true) => self.call(pc),
+                (false, false) => (),
+            },
+            _ => (),
+        }
+    }
+
+    /// Enter the frame of a call made at `pc`.
+    fn call(&mut self, pc: u64) {
+        if self.depth == MAX_DEPTH {
+            return;
+        }
+        let next = self.frames.len();
+        let frame = *self.callees.entry((self.frame, pc)).or_insert(next);
+        if frame == next {
+            self.frames.push((self.frame, pc));
+        }
+        self.frame = frame;
+        self.depth += 1;
+    }
+
+    fn ret(&mut self) {
+        if self.depth == 0 {
+            return;
+        }
+        self.frame = self.frames[self.frame].0;
+        self.depth -= 1;
+    }
+
+    /// Return the name of the function `addr` is in.
+    fn function(&self, addr: u64) -> String {
+        match self.symbols.function_at(addr) {
+            Some(symbol) => symbol.name.clone(),
+            None => String::from("[unknown]"),
+        }
+    }
+
+    /// Return the functions of the stack of `frame`, from the root, ending with the function
+    /// `pc` is in. The callers are the functions the calls were made from.
+    fn stack(&self, mut frame: usize, pc: u64) -> Vec<String> {
+        let mut stack = vec![self.function(pc)];
+        while frame != 0 {
+            let (caller, call) = self.frames[frame];
+            stack.push(self.function(call));
+            frame = caller;
+        }
+        stack.reverse();
+        stack
+    }
+
+    /// Write the folded stacks and print the functions that retired the most instructions.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        // Instructions retired in each function, and in it or the functions it called.
+        let mut own: HashMap<String, u64> = HashMap::new();
+        let mut total: HashMap<String, u64> = HashMap::new();
+        for (&(frame, pc), &count) in &self.counts {
+            let stack = self.stack(frame, pc);
+            *own.entry(stack.last().unwrap().clone()).or_insert(0) += count;
+            // A recursive function counts once.
+            let mut seen = stack.clone();
+            seen.sort();
+            seen.dedup();
+            for name in seen {
+                *total.entry(name).or_insert(0) += count;
+            }
+            *folded.entry(stack.join(";")).or_insert(0) += count;
+        }
+
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        let mut lines: Vec<_> = folded.into_iter().collect();
+        lines.sort();
+        for (stack, count) in lines {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        out.flush()?;
+
+        let retired: u64 = own.values().sum();
+        let mut functions: Vec<_> = own.into_iter().collect();
+        functions.sort_by(|(a, x), (b, y)| y.cmp(x).then(a.cmp(b)));
+        let percent = |count: u64| 100.0 * count as f64 / retired.max(1) as f64;
+        println!("{:-^80}", "profile");
+        println!("{} instructions retired in {} functions, written to {}", retired, functions.len(), self.path);
+        match self.stacks {
+            true => println!("{:>12} {:>6} {:>12} {:>6}  function", "self", "%", "total", "%"),
+            false => println!("{:>12} {:>6}  function", "self", "%"),
+        }
+        for (name, count) in functions.iter().take(self.top) {
+            match self.stacks {
+                true => {
+                    let total = total[name];
+                    println!("{:>12} {:>6.2} {:>12} {:>6.2}  {}", count, percent(*count), total, percent(total), name);
+                }
+                false => println!("{:>12} {:>6.2}  {}", count, percent(*count), name),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::Symbol;
+
+    const NOP: u64 = 0x00000013;
+    // jal ra, 0
+    const CALL: u64 = 0x000000ef;
+    // jalr ra, 0(a5)
+    const CALL_REG: u64 = 0x000780e7;
+    // jalr zero, 0(ra)
+    const RET: u64 = 0x00008067;
+
+    fn profiler(name: &str) -> Profiler {
+        let path = std::env::temp_dir().join(format!("r-riscv-{}-{}", name, std::process::id()));
+        let symbol = |name: &str, addr| Symbol { name: String::from(name), addr, size: 0x100, code: true };
+        let symbols = SymbolTable::new(vec![symbol("main", 0x2000), symbol("_start", 0x1000), symbol("leaf", 0x3000)]);
+        Profiler::new(path.to_str().unwrap().to_string(), symbols, 20, true)
+    }
+
+    #[test]
+    fn test_stacks() {
+        let mut profiler = profiler("profile-stacks");
+        for _ in 0..2 {
+            profiler.retire(0x1000, NOP);
+            profiler.retire(0x1004, CALL);
+            profiler.retire(0x2000, CALL_REG);
+            profiler.retire(0x3000, NOP);
+            profiler.retire(0x3004, RET);
+            profiler.retire(0x2004, RET);
+        }
+        profiler.retire(0x1008, NOP);
+        // The same calls lead to the same frames.
+        assert_eq!(profiler.frames.len(), 3);
+        assert_eq!((profiler.frame, profiler.depth), (0, 0));
+
+        profiler.finish().unwrap();
+        let folded = std::fs::read_to_string(&profiler.path).unwrap();
+        std::fs::remove_file(&profiler.path).unwrap();
+        assert_eq!(folded, "_start 5\n_start;main 4\n_start;main;leaf 4\n");
+    }
+
+    #[test]
+    fn test_depth() {
+        let mut profiler = profiler("profile-depth");
+        for _ in 0..MAX_DEPTH + 10 {
+            profiler.retire(0x2000, CALL);
+        }
+        // The calls past the bound are counted in the deepest frame.
+        assert_eq!(profiler.depth, MAX_DEPTH);
+        assert_eq!(profiler.frames.len(), MAX_DEPTH + 1);
+        assert_eq!(profiler.counts[&(MAX_DEPTH, 0x2000)], 10);
+        for _ in 0..MAX_DEPTH + 10 {
+            profiler.retire(0x2004, RET);
+        }
+        assert_eq!((profiler.frame, profiler.depth), (0, 0));
+    }
+}
@@ -0,0 +1,195 @@
+//! The replay module makes runs reproducible. A recorded run logs the nondeterministic inputs of
+//! the guest, the console input and the times read from the host clock, and a replayed run feeds
+//! the same inputs back at the same points instead of taking them from the host.
+//!
+//! An event is keyed by the number of retired instructions, and by the number of steps taken
+//! since the last instruction retired, as a step that traps retires nothing. Console input reaches
+//! the guest between steps, and the times are those read during the step. The log is text, one
+//! event per line:
+//!
+//! ```text
+//! 1523 0 uart 0x61
+//! 1600 1 time 1700000000123456789
+//! ```
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use crate::cpu::CPU;
+
+const HEADER: &str = "R-RISCV event log 1";
+
+/// A nondeterministic input of the guest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// A byte of console input.
+    Uart(u8),
+    /// A time read from the host clock, in nanoseconds since 1970.
+    Time(u64),
+}
+
+enum Mode {
+    /// The log is not buffered, so that it is complete even if the emulator is killed.
+    Record(File),
+    Replay(VecDeque<((u64, u64), Event)>),
+}
+
+pub struct EventLog {
+    mode: Mode,
+    /// The key of the next step: the retired instructions and the steps since the last one.
+    key: (u64, u64),
+}
+
+impl EventLog {
+    /// Record the inputs of the guest to the file at `path`.
+    pub fn record(cpu: &mut CPU, path: &str) -> io::Result<Self> {
+        let mut out = File::create(path)?;
+        writeln!(out, "{}", HEADER)?;
+        cpu.bus.uart.defer_input();
+        cpu.bus.rtc.record_host_times();
+        Ok(Self { mode: Mode::Record(out), key: first_key(cpu) })
+    }
+
+    /// Replay the inputs recorded in the file at `path`. The console input of the host is ignored.
+    pub fn replay(cpu: &mut CPU, path: &str) -> io::Result<Self> {
+        let log = fs::read_to_string(path)?;
+        let mut lines = log.lines().enumerate();
+        if lines.next().map(|(_, line)| line) != Some(HEADER) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an event log"));
+        }
+        let events = lines
+            .map(|(i, line)| {
+                parse(line).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("invalid event at line {}: {}", i + 1, line))
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        cpu.bus.uart.defer_input();
+        cpu.bus.rtc.replay_host_times();
+        Ok(Self { mode: Mode::Replay(events), key: first_key(cpu) })
+    }
+
+    /// Hand the inputs due before the next step to the guest.
+    pub fn before_step(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        self.key = match self.key {
+            (instret, steps) if instret == cpu.instret => (instret, steps + 1),
+            _ => (cpu.instret, 0),
+        };
+        match &mut self.mode {
+            Mode::Record(out) => {
+                if let Some(byte) = cpu.bus.uart.take_input() {
+                    cpu.bus.uart.inject(byte);
+                    write_event(out, self.key, Event::Uart(byte))?;
+                }
+            }
+            Mode::Replay(events) => {
+                while let Some(&(key, event)) = events.front() {
+                    if key != self.key {
+                        break;
+                    }
+                    events.pop_front();
+                    match event {
+                        Event::Uart(byte) => cpu.bus.uart.inject(byte),
+                        Event::Time(time) => cpu.bus.rtc.push_host_time(time),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Log the inputs the guest took during the step.
+    pub fn after_step(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        if let Mode::Record(out) = &mut self.mode {
+            for time in cpu.bus.rtc.take_host_times() {
+                write_event(out, self.key, Event::Time(time))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Return a key that the first step does not follow, so that it starts counting steps from 0.
+fn first_key(cpu: &CPU) -> (u64, u64) {
+    (cpu.instret.wrapping_sub(1), 0)
+}
+
+fn write_event(out: &mut File, (instret, steps): (u64, u64), event: Event) -> io::Result<()> {
+    match event {
+        Event::Uart(byte) => writeln!(out, "{} {} uart {:#04x}", instret, steps, byte),
+        Event::Time(time) => writeln!(out, "{} {} time {}", instret, steps, time),
+    }
+}
+
+fn parse(line: &str) -> Option<((u64, u64), Event)> {
+    let mut fields = line.split_whitespace();
+    let key = (fields.next()?.parse().ok()?, fields.next()?.parse().ok()?);
+    let event = match (fields.next()?, fields.next()?) {
+        ("uart", byte) => Event::Uart(u8::from_str_radix(byte.strip_prefix("0x")?, 16).ok()?),
+        ("time", time) => Event::Time(time.parse().ok()?),
+        _ => return None,
+    };
+    match fields.next() {
+        Some(_) => None,
+        None => Some((key, event)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::param::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("1523 0 uart 0x61"), Some(((1523, 0), Event::Uart(0x61))));
+        assert_eq!(parse("1600 1 time 1700000000123456789"), Some(((1600, 1), Event::Time(1700000000123456789))));
+        assert_eq!(parse("1523 0 uart 61"), None);
+        assert_eq!(parse("1523 0 uart 0x100"), None);
+        assert_eq!(parse("1600 time 1700000000"), None);
+        assert_eq!(parse("1600 1 time 1700000000 1"), None);
+        assert_eq!(parse("1600 1 disk 0"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    /// Run steps that read the time every other step, with an alarm armed that is checked every
+    /// step, and return the times read.
+    fn run(cpu: &mut CPU, log: &mut EventLog) -> Vec<u64> {
+        let mut times = Vec::new();
+        for step in 0..8 {
+            log.before_step(cpu).unwrap();
+            if step == 0 {
+                cpu.bus.rtc.store(RTC_ALARM_HIGH, 32, 0xffff_ffff).unwrap();
+                cpu.bus.rtc.store(RTC_ALARM_LOW, 32, 0).unwrap();
+            }
+            if step % 2 == 1 {
+                times.push(cpu.bus.rtc.load(RTC_TIME_LOW, 32).unwrap());
+            }
+            assert!(!cpu.bus.rtc.is_interrupting());
+            cpu.bus.tick();
+            log.after_step(cpu).unwrap();
+            cpu.instret += 1;
+        }
+        times
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let path = std::env::temp_dir().join(format!("r-riscv-replay-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        let mut log = EventLog::record(&mut cpu, path).unwrap();
+        let recorded = run(&mut cpu, &mut log);
+        // The alarm is armed and the time read four times, and nothing else reads the clock.
+        let events = fs::read_to_string(path).unwrap();
+        let times: Vec<_> = events.lines().filter_map(parse).filter(|(_, e)| matches!(e, Event::Time(_))).collect();
+        assert_eq!(times.len(), 5);
+        assert_eq!(times[1].0, (1, 0));
+
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        let mut log = EventLog::replay(&mut cpu, path).unwrap();
+        assert_eq!(run(&mut cpu, &mut log), recorded);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! The reverse module lets gdb run the guest backwards. The machine is checkpointed every few
+//! steps: the hart, the CSRs and the devices are saved in memory, and the dram pages are saved
+//! before they first change, so that a checkpoint only costs the pages written since the last
+//! one. Going back to a step restores the checkpoint before it and executes the guest again up
+//! to the step. The console input and the host times the guest took are kept, and given back when
+//! the steps are executed again, so that they do the same.
+//!
+//! gdb steps back with `reverse-stepi` and runs back to the previous breakpoint or watchpoint hit
+//! with `reverse-continue`. The last write to an address is found with `watch` on it and
+//! `reverse-continue`, which stops before the instruction that wrote it.
+
+use std::collections::{BTreeMap, HashSet};
+use crate::cpu::CPU;
+use crate::dram::Journal;
+use crate::replay::Event;
+use crate::snapshot;
+use crate::watchpoint::{Action, Hit};
+
+/// The number of steps between checkpoints.
+const CHECKPOINT_INTERVAL: u64 = 100_000;
+/// The number of checkpoints kept. The oldest ones are dropped, which limits how far back the
+/// guest can go.
+const MAX_CHECKPOINTS: usize = 1000;
+
+struct Checkpoint {
+    /// The step the checkpoint was taken before.
+    position: u64,
+    /// The machine but the dram.
+    state: Vec<u8>,
+    /// The dram pages written until the next checkpoint, as they were at this one.
+    undo: Journal,
+}
+
+/// Where going backwards stopped.
+pub enum Stop {
+    Stepped,
+    Breakpoint,
+    Watchpoint(Hit),
+    /// The oldest checkpoint, before which the guest cannot go.
+    Start,
+}
+
+pub struct Timeline {
+    /// The number of steps taken.
+    position: u64,
+    /// The first step that was never taken. The steps before it are executed again with the
+    /// inputs they took.
+    live: u64,
+    checkpoints: Vec<Checkpoint>,
+    /// The inputs the guest took, by step.
+    inputs: BTreeMap<u64, Vec<Event>>,
+}
+
+impl Timeline {
+    /// Start keeping the history of `cpu`, from its current state.
+    pub fn new(cpu: &mut CPU) -> Self {
+        cpu.bus.uart.defer_input();
+        Self { position: 0, live: 0, checkpoints: Vec::new(), inputs: BTreeMap::new() }
+    }
+
+    /// Take a checkpoint if one is due, and hand the inputs of the next step to the guest.
+    pub fn before_step(&mut self, cpu: &mut CPU) {
+        let taken = self.checkpoints.last().is_some_and(|c| c.position == self.position);
+        if self.position.is_multiple_of(CHECKPOINT_INTERVAL) && !taken {
+            self.checkpoint(cpu);
+        }
+        if self.position < self.live {
+            cpu.bus.rtc.replay_host_times();
+            for event in self.inputs.get(&self.position).into_iter().flatten() {
+                match *event {
+                    Event::Uart(byte) => cpu.bus.uart.inject(byte),
+                    Event::Time(time) => cpu.bus.rtc.push_host_time(time),
+                }
+            }
+        } else {
+            cpu.bus.rtc.record_host_times();
+            if let Some(byte) = cpu.bus.uart.take_input() {
+                cpu.bus.uart.inject(byte);
+                self.inputs.entry(self.position).or_default().push(Event::Uart(byte));
+            }
+        }
+    }
+
+    /// Keep the host times the guest read during the step.
+    pub fn after_step(&mut self, cpu: &mut CPU) {
+        if self.position >= self.live {
+            let times = cpu.bus.rtc.take_host_times();
+            if !times.is_empty() {
+                self.inputs.entry(self.position).or_default().extend(times.into_iter().map(Event::Time));
+            }
+            self.live = self.position + 1;
+        }
+        self.position += 1;
+    }
+
+    /// Forget the steps after this one, because the debugger changed the machine and the guest
+    /// will take another way.
+    pub fn diverge(&mut self) {
+        self.live = self.position;
+        self.inputs.split_off(&self.position);
+    }
+
+    /// Go back one step.
+    pub fn reverse_step(&mut self, cpu: &mut CPU) -> Stop {
+        if self.position == self.start() {
+            return Stop::Start;
+        }
+        self.rewind(cpu, self.position - 1);
+        Stop::Stepped
+    }
+
+    /// Go back to the last step before this one that is at one of `breakpoints` or sets off a
+    /// watchpoint, or to the start if there is none.
+    pub fn reverse_continue(&mut self, cpu: &mut CPU, breakpoints: &HashSet<u64>) -> Stop {
+        // The steps are executed again one checkpoint at a time, from the latest.
+        let mut end = self.position;
+        while let Some(k) = self.checkpoints.iter().rposition(|c| c.position < end) {
+            let start = self.checkpoints[k].position;
+            self.rewind(cpu, start);
+            if let Some((position, hit)) = self.execute_to(cpu, end, breakpoints) {
+                self.rewind(cpu, position);
+                return match hit {
+                    Some(hit) => Stop::Watchpoint(hit),
+                    None => Stop::Breakpoint,
+                };
+            }
+            end = start;
+        }
+        let start = self.start();
+        if self.position != start {
+            self.rewind(cpu, start);
+        }
+        Stop::Start
+    }
+
+    /// Return the oldest step the guest can go back to.
+    fn start(&self) -> u64 {
+        self.checkpoints.first().map_or(self.position, |c| c.position)
+    }
+
+    fn checkpoint(&mut self, cpu: &mut CPU) {
+        let undo = cpu.bus.dram.take_journal();
+        if let Some(last) = self.checkpoints.last_mut() {
+            last.undo = undo;
+        }
+        let state = snapshot::checkpoint(cpu);
+        self.checkpoints.push(Checkpoint { position: self.position, state, undo: Journal::new() });
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.checkpoints.remove(0);
+            // The steps before the oldest checkpoint are never executed again.
+            self.inputs = self.inputs.split_off(&self.checkpoints[0].position);
+        }
+    }
+
+    /// Go back to the step at `position`, which is not before the start. The checkpoints after it
+    /// are dropped, as the dram can only be undone.
+    fn rewind(&mut self, cpu: &mut CPU, position: u64) {
+        let k = self.checkpoints.partition_point(|c| c.position <= position) - 1;
+        let journal = cpu.bus.dram.take_journal();
+        cpu.bus.dram.undo(journal);
+        for checkpoint in self.checkpoints.drain(k + 1..).rev() {
+            cpu.bus.dram.undo(checkpoint.undo);
+        }
+        let checkpoint = &mut self.checkpoints[k];
+        cpu.bus.dram.undo(std::mem::take(&mut checkpoint.undo));
+        snapshot::rewind(cpu, &checkpoint.state);
+        self.position = checkpoint.position;
+        // The requests to stop the machine came from the steps that were undone.
+        while cpu.shutdown().is_some() {}
+        cpu.watch_hit = None;
+        self.execute_to(cpu, position, &HashSet::new());
+    }
+
+    /// Execute the guest again up to the step at `position`, without tracing, profiling,
+    /// counting or covering it or printing its output or the accesses it logged. Return the last
+    /// step on the way that is at one of `breakpoints` or sets off a watchpoint, with the hit.
+    fn execute_to(&mut self, cpu: &mut CPU, position: u64, breakpoints: &HashSet<u64>) -> Option<(u64, Option<Hit>)> {
+        let tracer = cpu.tracer.take();
+        let profiler = cpu.profiler.take();
+        let stats = cpu.stats.take();
+        let coverage = cpu.coverage.take();
+        let watchpoints = cpu.watchpoints.clone();
+        cpu.watchpoints.retain(|w| w.action == Action::Stop);
+        cpu.bus.mute_console(true);
+        let mut stop = None;
+        while self.position < position {
+            if breakpoints.contains(&cpu.pc) {
+                stop = Some((self.position, None));
+            }
+            self.before_step(cpu);
+            let result = cpu.step();
+            self.after_step(cpu);
+            if let Some(hit) = cpu.watch_hit.take() {
+                stop = Some((self.position - 1, Some(hit)));
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+        cpu.bus.mute_console(false);
+        cpu.tracer = tracer;
+        cpu.profiler = profiler;
+        cpu.stats = stats;
+        cpu.coverage = coverage;
+        cpu.watchpoints = watchpoints;
+        stop
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::param::DRAM_BASE;
+    use crate::watchpoint::Watchpoint;
+
+    // A loop that counts in t0 and stores the count to DRAM_BASE + 256.
+    const PROGRAM: [u32; 4] = [
+        0x00000317, // auipc t1, 0
+        0x00128293, // addi t0, t0, 1
+        0x10533023, // sd t0, 256(t1)
+        0xff9ff06f, // j -8
+    ];
+    const COUNTER: u64 = DRAM_BASE + 256;
+
+    /// The pc, the registers and the counter in memory.
+    fn state(cpu: &mut CPU) -> (u64, [u64; 32], u64) {
+        (cpu.pc, cpu.regs, cpu.bus.load(COUNTER, 64).unwrap())
+    }
+
+    #[test]
+    fn test_reverse() {
+        let code = PROGRAM.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let mut cpu = CPU::new(code, Vec::new());
+        cpu.pc = DRAM_BASE;
+        let mut timeline = Timeline::new(&mut cpu);
+        let mut states = Vec::new();
+        for _ in 0..30 {
+            states.push(state(&mut cpu));
+            timeline.before_step(&mut cpu);
+            cpu.step().unwrap();
+            timeline.after_step(&mut cpu);
+        }
+        let end = state(&mut cpu);
+
+        assert!(matches!(timeline.reverse_step(&mut cpu), Stop::Stepped));
+        assert_eq!(state(&mut cpu), states[29]);
+
+        // The stores are at the steps 2, 5, 8 and so on.
+        let breakpoints = HashSet::from([DRAM_BASE + 8]);
+        assert!(matches!(timeline.reverse_continue(&mut cpu, &breakpoints), Stop::Breakpoint));
+        assert_eq!(state(&mut cpu), states[26]);
+
+        cpu.watchpoints.push(Watchpoint::new(COUNTER, 8, false, true));
+        match timeline.reverse_continue(&mut cpu, &HashSet::new()) {
+            Stop::Watchpoint(hit) => assert_eq!(hit.addr, COUNTER),
+            _ => panic!("expected a watchpoint hit"),
+        }
+        assert_eq!(state(&mut cpu), states[23]);
+
+        assert!(matches!(timeline.reverse_continue(&mut cpu, &HashSet::new()), Stop::Watchpoint(_)));
+        assert_eq!(state(&mut cpu), states[20]);
+        cpu.watchpoints.clear();
+        assert!(matches!(timeline.reverse_continue(&mut cpu, &HashSet::new()), Stop::Start));
+        assert_eq!(state(&mut cpu), states[0]);
+        assert!(matches!(timeline.reverse_step(&mut cpu), Stop::Start));
+
+        // Going forwards again takes the same steps.
+        for _ in 0..30 {
+            timeline.before_step(&mut cpu);
+            cpu.step().unwrap();
+            timeline.after_step(&mut cpu);
+        }
+        assert_eq!(state(&mut cpu), end);
+    }
+}
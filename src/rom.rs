@@ -0,0 +1,92 @@
+//! The rom module emulates the read-only boot ROM at the reset vector. Like the one of QEMU's virt
+//! machine, it loads the hart id into a0 and the device tree address into a1, then jumps to the
+//! firmware.
+
+use std::io;
+use crate::exception::Exception;
+use crate::exception::Exception::{LoadAccessFault, StoreAMOAccessFault};
+use crate::param::*;
+use crate::snapshot::{Reader, Writer};
+
+/// The reset vector. The entry point and the device tree address follow the code.
+const RESET_VEC: [u32; 6] = [
+    0x00000297, // auipc t0, 0
+    0xf1402573, // csrr  a0, mhartid
+    0x0202b583, // ld    a1, 32(t0)
+    0x0182b283, // ld    t0, 24(t0)
+    0x00028067, // jr    t0
+    0x00000000,
+];
+
+pub struct Rom {
+    rom: Vec<u8>,
+    entry: u64,
+    fdt: u64,
+}
+
+impl Rom {
+    /// Create a ROM that starts the firmware at `entry` with the device tree at `fdt`.
+    pub fn new(entry: u64, fdt: u64) -> Self {
+        let mut rom: Vec<u8> = RESET_VEC.iter().flat_map(|i| i.to_le_bytes()).collect();
+        rom.extend_from_slice(&entry.to_le_bytes());
+        rom.extend_from_slice(&fdt.to_le_bytes());
+        Self { rom, entry, fdt }
+    }
+
+    /// Return the address the ROM jumps to.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Return the address of the device tree passed in a1.
+    pub fn fdt(&self) -> u64 {
+        self.fdt
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        w.u64(self.entry);
+        w.u64(self.fdt);
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        *self = Rom::new(r.u64()?, r.u64()?);
+        Ok(())
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let len = match size {
+            8 | 16 | 32 | 64 => size as usize / 8,
+            _ => return Err(LoadAccessFault(addr)),
+        };
+        // The rest of the ROM reads as zeros.
+        let index = (addr - ROM_BASE) as usize;
+        let value = (0..len).fold(0, |value, i| {
+            value | (*self.rom.get(index + i).unwrap_or(&0) as u64) << (i * 8)
+        });
+        Ok(value)
+    }
+
+    pub fn store(&self, addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+        Err(StoreAMOAccessFault(addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_reset() {
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        let (entry, fdt) = (DRAM_BASE + 0x20_0000, DRAM_END + 1 - 0x10_0000);
+        cpu.bus.rom = Rom::new(entry, fdt);
+        cpu.pc = ROM_BASE;
+        cpu.regs[10] = 0xdead;
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, entry);
+        assert_eq!((cpu.regs[10], cpu.regs[11]), (0, fdt));
+    }
+}
@@ -0,0 +1,265 @@
+//! The rtc module emulates the goldfish real-time clock, so that guests boot with the right wall
+//! clock time. Its alarm raises an interrupt through the PLIC.
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::exception::Exception;
+use crate::exception::Exception::{LoadAccessFault, StoreAMOAccessFault};
+use crate::param::*;
+use crate::snapshot::{Reader, Writer};
+
+/// Nanoseconds a fixed clock advances per executed instruction.
+const NS_PER_TICK: u64 = 100;
+
+/// Where the time of the rtc comes from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RtcClock {
+    /// Follow the wall clock of the host.
+    Host,
+    /// Start at the given seconds since 1970 and advance with the executed instructions, so
+    /// that every run sees the same times.
+    Fixed(u64),
+}
+
+/// What becomes of the times read from the host clock, for a recorded run.
+enum HostTimes {
+    Live,
+    /// The times read, to be logged.
+    Record(Vec<u64>),
+    /// The times to return instead of reading the clock.
+    Replay(VecDeque<u64>),
+}
+
+pub struct Rtc {
+    clock: RtcClock,
+    host_times: HostTimes,
+    /// Instructions executed so far, which drive a fixed clock.
+    ticks: u64,
+    /// Difference between the guest time and the clock, set when the guest writes the time.
+    offset: u64,
+    /// High half of the time, latched by a read of the low half.
+    time_high: u32,
+    /// High half of the alarm, used when the low half is written.
+    alarm_high: u32,
+    /// Time of the armed alarm.
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    /// The last time read from the host clock, and the ticks when it was read.
+    last_read: (u64, u64),
+}
+
+impl Rtc {
+    pub fn new(clock: RtcClock) -> Self {
+        Self { clock, host_times: HostTimes::Live, ticks: 0, offset: 0, time_high: 0, alarm_high: 0, alarm: None, irq_enabled: false, last_read: (0, 0) }
+    }
+
+    /// Advance a fixed clock by one instruction.
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    /// Save the registers. The clock comes from the command line.
+    pub fn save(&self, w: &mut Writer) {
+        w.u64(self.ticks);
+        w.u64(self.offset);
+        w.u32(self.time_high);
+        w.u32(self.alarm_high);
+        w.option(self.alarm);
+        w.bool(self.irq_enabled);
+        w.u64(self.last_read.0);
+        w.u64(self.last_read.1);
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.ticks = r.u64()?;
+        self.offset = r.u64()?;
+        self.time_high = r.u32()?;
+        self.alarm_high = r.u32()?;
+        self.alarm = r.option()?;
+        self.irq_enabled = r.bool()?;
+        self.last_read = (r.u64()?, r.u64()?);
+        Ok(())
+    }
+
+    /// Keep the times read from the host clock, to be taken with `take_host_times`.
+    pub fn record_host_times(&mut self) {
+        self.host_times = HostTimes::Record(Vec::new());
+    }
+
+    /// Return the times read from the host clock since the last call.
+    pub fn take_host_times(&mut self) -> Vec<u64> {
+        match &mut self.host_times {
+            HostTimes::Record(times) => std::mem::take(times),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Return the times given by `push_host_time` instead of reading the host clock.
+    pub fn replay_host_times(&mut self) {
+        self.host_times = HostTimes::Replay(VecDeque::new());
+    }
+
+    /// Return `time` the next time the host clock is read.
+    pub fn push_host_time(&mut self, time: u64) {
+        if let HostTimes::Replay(times) = &mut self.host_times {
+            times.push_back(time);
+        }
+    }
+
+    /// Return the time of the host in nanoseconds since 1970, and keep it as the last read. A
+    /// replayed run that reads the clock more often than the recorded one gets the real time.
+    fn host_time(&mut self) -> u64 {
+        let time = match &mut self.host_times {
+            HostTimes::Live => host_clock(),
+            HostTimes::Record(times) => {
+                let time = host_clock();
+                times.push(time);
+                time
+            }
+            HostTimes::Replay(times) => times.pop_front().unwrap_or_else(host_clock),
+        };
+        self.last_read = (time, self.ticks);
+        time
+    }
+
+    /// Return the guest time in nanoseconds since 1970, as the guest reads it.
+    fn now(&mut self) -> u64 {
+        match self.clock {
+            RtcClock::Host => self.host_time().wrapping_add(self.offset),
+            RtcClock::Fixed(_) => self.time(),
+        }
+    }
+
+    /// Return the guest time the alarm is checked against. In a recorded or replayed run, only
+    /// the guest reads the host clock, so that the log holds the times it saw rather than one per
+    /// instruction, and the time goes on from the last read at the rate of a fixed clock.
+    fn time(&self) -> u64 {
+        let clock = match (self.clock, &self.host_times) {
+            (RtcClock::Host, HostTimes::Live) => host_clock(),
+            (RtcClock::Host, _) => {
+                let (time, ticks) = self.last_read;
+                time.wrapping_add((self.ticks - ticks) * NS_PER_TICK)
+            }
+            (RtcClock::Fixed(epoch), _) => (epoch * 1_000_000_000).wrapping_add(self.ticks.wrapping_mul(NS_PER_TICK)),
+        };
+        clock.wrapping_add(self.offset)
+    }
+
+    /// Return true if the alarm went off. The alarm is disarmed by firing.
+    pub fn is_interrupting(&mut self) -> bool {
+        let Some(alarm) = self.alarm else { return false };
+        if self.time() < alarm {
+            return false;
+        }
+        self.alarm = None;
+        self.irq_enabled
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(LoadAccessFault(addr));
+        }
+        match addr {
+            RTC_TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                Ok(now & 0xffff_ffff)
+            }
+            RTC_TIME_HIGH => Ok(self.time_high as u64),
+            RTC_IRQ_ENABLED => Ok(self.irq_enabled as u64),
+            RTC_ALARM_STATUS => Ok(self.alarm.is_some() as u64),
+            _ => Ok(0),
+        }
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        let value = value & 0xffff_ffff;
+        match addr {
+            // Setting the time writes the high half first, then the low half.
+            RTC_TIME_HIGH => self.time_high = value as u32,
+            RTC_TIME_LOW => {
+                let time = ((self.time_high as u64) << 32) | value;
+                self.offset = self.offset.wrapping_add(time.wrapping_sub(self.now()));
+            }
+            RTC_ALARM_HIGH => self.alarm_high = value as u32,
+            RTC_ALARM_LOW => {
+                // The alarm is checked against a time that goes on from the one it is armed at.
+                self.now();
+                self.alarm = Some(((self.alarm_high as u64) << 32) | value);
+            }
+            RTC_IRQ_ENABLED => self.irq_enabled = value & 1 == 1,
+            RTC_CLEAR_ALARM => self.alarm = None,
+            // The interrupt is signalled once when the alarm fires, so there is nothing to clear.
+            RTC_CLEAR_INTERRUPT => (),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Return the time of the host clock in nanoseconds since 1970.
+fn host_clock() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ticks(rtc: &mut Rtc, n: u64) {
+        for _ in 0..n {
+            rtc.tick();
+        }
+    }
+
+    #[test]
+    fn test_time() {
+        let mut rtc = Rtc::new(RtcClock::Fixed(1));
+        ticks(&mut rtc, 3);
+        assert_eq!(rtc.load(RTC_TIME_LOW, 32).unwrap(), 1_000_000_300);
+        assert_eq!(rtc.load(RTC_TIME_HIGH, 32).unwrap(), 0);
+        assert!(rtc.load(RTC_TIME_LOW, 64).is_err());
+
+        // The high half is the one of the last read of the low half.
+        rtc.store(RTC_TIME_HIGH, 32, 1).unwrap();
+        rtc.store(RTC_TIME_LOW, 32, 0xffff_ff00).unwrap();
+        assert_eq!(rtc.load(RTC_TIME_LOW, 32).unwrap(), 0xffff_ff00);
+        ticks(&mut rtc, 10);
+        assert_eq!(rtc.load(RTC_TIME_HIGH, 32).unwrap(), 1);
+        assert_eq!(rtc.load(RTC_TIME_LOW, 32).unwrap(), 0x2e8);
+        assert_eq!(rtc.load(RTC_TIME_HIGH, 32).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_alarm() {
+        let mut rtc = Rtc::new(RtcClock::Fixed(0));
+        rtc.store(RTC_IRQ_ENABLED, 32, 1).unwrap();
+        rtc.store(RTC_ALARM_HIGH, 32, 0).unwrap();
+        rtc.store(RTC_ALARM_LOW, 32, 500).unwrap();
+        assert_eq!(rtc.load(RTC_ALARM_STATUS, 32).unwrap(), 1);
+        ticks(&mut rtc, 4);
+        assert!(!rtc.is_interrupting());
+        // The interrupt is raised once, when the alarm fires, which disarms it.
+        ticks(&mut rtc, 1);
+        assert!(rtc.is_interrupting());
+        assert!(!rtc.is_interrupting());
+        assert_eq!(rtc.load(RTC_ALARM_STATUS, 32).unwrap(), 0);
+        rtc.store(RTC_CLEAR_INTERRUPT, 32, 1).unwrap();
+
+        // A cleared alarm does not fire.
+        rtc.store(RTC_ALARM_LOW, 32, 1000).unwrap();
+        rtc.store(RTC_CLEAR_ALARM, 32, 1).unwrap();
+        ticks(&mut rtc, 10);
+        assert!(!rtc.is_interrupting());
+
+        // Without the interrupt enabled, the alarm fires silently.
+        rtc.store(RTC_IRQ_ENABLED, 32, 0).unwrap();
+        rtc.store(RTC_ALARM_LOW, 32, 1000).unwrap();
+        assert!(!rtc.is_interrupting());
+        assert_eq!(rtc.load(RTC_ALARM_STATUS, 32).unwrap(), 0);
+    }
+}
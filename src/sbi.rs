@@ -0,0 +1,296 @@
+//! The sbi module provides the supervisor binary interface in place of a firmware like OpenSBI,
+//! so that S-mode kernels boot directly. The kernel calls it with `ecall`: a7 selects the
+//! extension, a6 the function, and the result is returned as (error, value) in a0 and a1.
+//! The calls are described in https://github.com/riscv-non-isa/riscv-sbi-doc
+
+use std::io;
+use std::io::Write;
+use crate::bus::Shutdown;
+use crate::cpu::{CPU, Supervisor};
+use crate::csr::{MEDELEG, MHARTID, MIDELEG, MIP, MASK_SSIP, MASK_STIP, MASK_SEIP};
+use crate::param::{MASK_UART_LSR_RX, UART_BASE, UART_LSR, UART_RHR};
+use crate::snapshot::{Reader, Writer};
+
+// The version of the spec that is implemented, 1.0.
+const SBI_SPEC_VERSION: i64 = 1 << 24;
+// Not a registered implementation id.
+const SBI_IMPL_ID: i64 = 0xfff;
+const SBI_IMPL_VERSION: i64 = 1;
+
+// Extension ids.
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x54494d45;
+const EXT_IPI: u64 = 0x735049;
+const EXT_RFENCE: u64 = 0x52464e43;
+const EXT_HSM: u64 = 0x48534d;
+const EXT_SRST: u64 = 0x53525354;
+// Legacy extensions, which are whole calls without a function id.
+const LEGACY_SET_TIMER: u64 = 0;
+const LEGACY_CONSOLE_PUTCHAR: u64 = 1;
+const LEGACY_CONSOLE_GETCHAR: u64 = 2;
+const LEGACY_CLEAR_IPI: u64 = 3;
+const LEGACY_SEND_IPI: u64 = 4;
+const LEGACY_REMOTE_FENCE_I: u64 = 5;
+const LEGACY_REMOTE_SFENCE_VMA: u64 = 6;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: u64 = 7;
+const LEGACY_SHUTDOWN: u64 = 8;
+
+// Error codes.
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_FAILED: i64 = -1;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// States of a hart in the HSM extension.
+const HSM_STARTED: i64 = 0;
+
+// Types of a system reset.
+const SRST_SHUTDOWN: u64 = 0;
+const SRST_COLD_REBOOT: u64 = 1;
+const SRST_WARM_REBOOT: u64 = 2;
+
+// Exceptions handled by the kernel rather than the SBI: misaligned fetches, access faults,
+// illegal instructions, breakpoints, environment calls from U-mode and page faults. OpenSBI
+// delegates some of them and redirects the others to the kernel, which comes to the same, as
+// there is nothing for a firmware to emulate here.
+const DELEGATED_EXCEPTIONS: u64 = (1 << 0) | (1 << 1) | (1 << 2) | (1 << 3) | (1 << 5) | (1 << 7)
+    | (1 << 8) | (1 << 12) | (1 << 13) | (1 << 15);
+const DELEGATED_INTERRUPTS: u64 = MASK_SSIP | MASK_STIP | MASK_SEIP;
+
+pub struct Sbi {
+    /// The time at which the kernel wants a timer interrupt.
+    timer: Option<u64>,
+    /// Shutdown request of the kernel, if it has not been handled yet.
+    request: Option<Shutdown>,
+}
+
+impl Sbi {
+    pub fn new() -> Self {
+        Self { timer: None, request: None }
+    }
+
+    /// Do what a firmware does before it jumps to the kernel: delegate the traps to S-mode and
+    /// start the kernel in S-mode with a0 = hartid and a1 = device tree.
+    pub fn boot(cpu: &mut CPU) {
+        cpu.csr.store(MEDELEG, DELEGATED_EXCEPTIONS);
+        cpu.csr.store(MIDELEG, DELEGATED_INTERRUPTS);
+        cpu.regs[10] = cpu.csr.load(MHARTID);
+        cpu.regs[11] = cpu.bus.rom.fdt();
+        cpu.pc = cpu.bus.rom.entry();
+        cpu.mode = Supervisor;
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        w.option(self.timer);
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.timer = r.option()?;
+        Ok(())
+    }
+
+    /// Return the pending shutdown request and clear it.
+    pub fn take_request(&mut self) -> Option<Shutdown> {
+        self.request.take()
+    }
+
+    /// Return true if the timer went off at `mtime`. The timer is disarmed by firing.
+    pub fn is_timer_interrupting(&mut self, mtime: u64) -> bool {
+        match self.timer {
+            Some(timer) if mtime >= timer => {
+                self.timer = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Serve the call of the kernel and return to the instruction after the `ecall`.
+    pub fn call(&mut self, cpu: &mut CPU) {
+        let (ext, fid) = (cpu.regs[17], cpu.regs[16]);
+        if ext <= LEGACY_SHUTDOWN {
+            // Legacy calls only return a value in a0.
+            cpu.regs[10] = self.legacy(cpu, ext) as u64;
+        } else {
+            let (error, value) = self.extension(cpu, ext, fid);
+            cpu.regs[10] = error as u64;
+            cpu.regs[11] = value as u64;
+        }
+        cpu.pc += 4;
+    }
+
+    fn extension(&mut self, cpu: &mut CPU, ext: u64, fid: u64) -> (i64, i64) {
+        let (a0, a1) = (cpu.regs[10], cpu.regs[11]);
+        match (ext, fid) {
+            (EXT_BASE, 0) => (SBI_SUCCESS, SBI_SPEC_VERSION),
+            (EXT_BASE, 1) => (SBI_SUCCESS, SBI_IMPL_ID),
+            (EXT_BASE, 2) => (SBI_SUCCESS, SBI_IMPL_VERSION),
+            (EXT_BASE, 3) => (SBI_SUCCESS, is_supported(a0) as i64),
+            // mvendorid, marchid and mimpid are all zero.
+            (EXT_BASE, 4..=6) => (SBI_SUCCESS, 0),
+            (EXT_TIME, 0) => {
+                self.set_timer(cpu, a0);
+                (SBI_SUCCESS, 0)
+            }
+            (EXT_IPI, 0) => {
+                // The hart mask is relative to a base hart id, where -1 stands for all harts.
+                if a1 == u64::MAX || (a1 == 0 && a0 & 1 == 1) {
+                    cpu.csr.store(MIP, cpu.csr.load(MIP) | MASK_SSIP);
+                }
+                (SBI_SUCCESS, 0)
+            }
+            // There is a single hart and no TLB, so there is nothing to fence.
+            (EXT_RFENCE, 0..=6) => (SBI_SUCCESS, 0),
+            // The only hart is the one that is already running.
+            (EXT_HSM, 0) if a0 == 0 => (SBI_ERR_ALREADY_AVAILABLE, 0),
+            (EXT_HSM, 1) => (SBI_ERR_FAILED, 0),
+            (EXT_HSM, 2) if a0 == 0 => (SBI_SUCCESS, HSM_STARTED),
+            (EXT_HSM, 0 | 2) => (SBI_ERR_INVALID_PARAM, 0),
+            // A retentive suspend returns at the next interrupt, like wfi.
+            (EXT_HSM, 3) if a0 == 0 => (SBI_SUCCESS, 0),
+            (EXT_SRST, 0) => {
+                self.request = match a0 {
+                    SRST_SHUTDOWN => Some(Shutdown::PowerOff((a1 != 0) as i32)),
+                    SRST_COLD_REBOOT | SRST_WARM_REBOOT => Some(Shutdown::Reboot),
+                    _ => return (SBI_ERR_INVALID_PARAM, 0),
+                };
+                (SBI_SUCCESS, 0)
+            }
+            _ => (SBI_ERR_NOT_SUPPORTED, 0),
+        }
+    }
+
+    fn legacy(&mut self, cpu: &mut CPU, ext: u64) -> i64 {
+        match ext {
+            LEGACY_SET_TIMER => self.set_timer(cpu, cpu.regs[10]),
+            LEGACY_CONSOLE_PUTCHAR if cpu.bus.uart.is_muted() => (),
+            LEGACY_CONSOLE_PUTCHAR => {
+                io::stdout().write_all(&[cpu.regs[10] as u8]).unwrap();
+                io::stdout().flush().unwrap();
+            }
+            LEGACY_CONSOLE_GETCHAR => {
+                // Take the character from the uart, which reads the console of the host.
+                let lsr = cpu.bus.load(UART_BASE + UART_LSR, 8).unwrap() as u8;
+                if lsr & MASK_UART_LSR_RX == 0 {
+                    return -1;
+                }
+                return cpu.bus.load(UART_BASE + UART_RHR, 8).unwrap() as i64;
+            }
+            LEGACY_CLEAR_IPI => cpu.csr.store(MIP, cpu.csr.load(MIP) & !MASK_SSIP),
+            LEGACY_SEND_IPI => cpu.csr.store(MIP, cpu.csr.load(MIP) | MASK_SSIP),
+            LEGACY_REMOTE_FENCE_I | LEGACY_REMOTE_SFENCE_VMA | LEGACY_REMOTE_SFENCE_VMA_ASID => (),
+            LEGACY_SHUTDOWN => self.request = Some(Shutdown::PowerOff(0)),
+            _ => unreachable!(),
+        }
+        0
+    }
+
+    /// Arm the timer for `time`, which also clears a pending timer interrupt.
+    fn set_timer(&mut self, cpu: &mut CPU, time: u64) {
+        self.timer = Some(time);
+        cpu.csr.store(MIP, cpu.csr.load(MIP) & !MASK_STIP);
+    }
+}
+
+fn is_supported(ext: u64) -> bool {
+    matches!(ext, EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST) || ext <= LEGACY_SHUTDOWN
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::User;
+    use crate::csr::{SCAUSE, SEPC, SSTATUS, STVEC, TIME};
+    use crate::param::DRAM_BASE;
+
+    /// Make the SBI call of `ext` and `fid` with `args`, and return a0 and a1.
+    fn ecall(cpu: &mut CPU, ext: u64, fid: u64, args: &[u64]) -> (i64, i64) {
+        (cpu.regs[17], cpu.regs[16]) = (ext, fid);
+        cpu.regs[10..10 + args.len()].copy_from_slice(args);
+        let pc = cpu.pc;
+        let mut sbi = cpu.sbi.take().unwrap();
+        sbi.call(cpu);
+        cpu.sbi = Some(sbi);
+        assert_eq!(cpu.pc, pc + 4);
+        (cpu.regs[10] as i64, cpu.regs[11] as i64)
+    }
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        cpu.sbi = Some(Sbi::new());
+        Sbi::boot(&mut cpu);
+        cpu
+    }
+
+    #[test]
+    fn test_base() {
+        let mut cpu = cpu();
+        assert_eq!(cpu.mode, Supervisor);
+        assert_eq!(ecall(&mut cpu, EXT_BASE, 0, &[]), (SBI_SUCCESS, SBI_SPEC_VERSION));
+        assert_eq!(ecall(&mut cpu, EXT_BASE, 3, &[EXT_TIME]), (SBI_SUCCESS, 1));
+        assert_eq!(ecall(&mut cpu, EXT_BASE, 3, &[LEGACY_CONSOLE_PUTCHAR]), (SBI_SUCCESS, 1));
+        assert_eq!(ecall(&mut cpu, EXT_BASE, 3, &[0x12345678]), (SBI_SUCCESS, 0));
+        assert_eq!(ecall(&mut cpu, 0x12345678, 0, &[]).0, SBI_ERR_NOT_SUPPORTED);
+        cpu.bus.uart.set_muted(true);
+        assert_eq!(ecall(&mut cpu, LEGACY_CONSOLE_PUTCHAR, 0, &[b'a' as u64]).0, 0);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut cpu = cpu();
+        cpu.csr.store(TIME, 100);
+        assert_eq!(ecall(&mut cpu, EXT_TIME, 0, &[200]).0, SBI_SUCCESS);
+        cpu.check_pending_interrupt();
+        assert_eq!(cpu.csr.load(MIP) & MASK_STIP, 0);
+
+        // The interrupt is pending even while the kernel has interrupts disabled.
+        cpu.csr.store(SSTATUS, 0);
+        cpu.csr.store(TIME, 200);
+        assert!(cpu.check_pending_interrupt().is_none());
+        assert_ne!(cpu.csr.load(MIP) & MASK_STIP, 0);
+
+        // Setting the timer again clears it.
+        ecall(&mut cpu, LEGACY_SET_TIMER, 0, &[300]);
+        assert_eq!(cpu.csr.load(MIP) & MASK_STIP, 0);
+    }
+
+    #[test]
+    fn test_delegation() {
+        let mut cpu = cpu();
+        // The zero instruction is illegal, and the kernel takes it from a process.
+        (cpu.pc, cpu.mode) = (DRAM_BASE, User);
+        cpu.csr.store(STVEC, DRAM_BASE + 0x100);
+        assert!(cpu.step().is_ok());
+        assert_eq!((cpu.pc, cpu.mode), (DRAM_BASE + 0x100, Supervisor));
+        assert_eq!(cpu.csr.load(SCAUSE), 2);
+        assert_eq!(cpu.csr.load(SEPC), DRAM_BASE);
+        assert_eq!(cpu.instret, 0);
+    }
+
+    #[test]
+    fn test_retire() {
+        let mut cpu = cpu();
+        // An ecall the SBI serves retires like any other instruction.
+        cpu.store_bytes(DRAM_BASE, &0x00000073u32.to_le_bytes()).unwrap();
+        cpu.pc = DRAM_BASE;
+        (cpu.regs[17], cpu.regs[16]) = (EXT_BASE, 0);
+        assert!(cpu.step().is_ok());
+        assert_eq!((cpu.pc, cpu.mode), (DRAM_BASE + 4, Supervisor));
+        assert_eq!(cpu.regs[10] as i64, SBI_SUCCESS);
+        assert_eq!(cpu.instret, 1);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut cpu = cpu();
+        ecall(&mut cpu, LEGACY_SHUTDOWN, 0, &[]);
+        assert_eq!(cpu.shutdown(), Some(Shutdown::PowerOff(0)));
+        assert_eq!(ecall(&mut cpu, EXT_SRST, 0, &[SRST_SHUTDOWN, 1]).0, SBI_SUCCESS);
+        assert_eq!(cpu.shutdown(), Some(Shutdown::PowerOff(1)));
+        ecall(&mut cpu, EXT_SRST, 0, &[SRST_COLD_REBOOT, 0]);
+        assert_eq!(cpu.shutdown(), Some(Shutdown::Reboot));
+        assert_eq!(ecall(&mut cpu, EXT_SRST, 0, &[7, 0]).0, SBI_ERR_INVALID_PARAM);
+        assert_eq!(cpu.shutdown(), None);
+    }
+}
@@ -0,0 +1,405 @@
+//! The semihosting module lets bare-metal programs use the console and files of the host.
+//! The RISC-V semihosting spec reuses the ARM operations: https://github.com/riscv-non-isa/riscv-semihosting
+//!
+//! A call is an `ebreak` between the marker instructions `slli x0, x0, 0x1f` and `srai x0, x0, 7`.
+//! The operation number is in a0 and a1 points to a block of XLEN-sized parameters. The result is
+//! returned in a0.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use crate::bus::Shutdown;
+use crate::cpu::{AccessType, CPU};
+
+// The marker instructions around the ebreak.
+const SEMIHOSTING_ENTRY: u64 = 0x01f01013; // slli x0, x0, 0x1f
+const SEMIHOSTING_EXIT: u64 = 0x40705013; // srai x0, x0, 7
+
+// Operation numbers.
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_FLEN: u64 = 0x0c;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+// The reason given to SYS_EXIT by a program that returns normally.
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+// The file name that refers to the console.
+const CONSOLE: &str = ":tt";
+
+// The longest file name the guest can open.
+const MAX_NAME_LEN: u64 = 4096;
+// The most bytes copied between the guest and a file at once.
+const CHUNK_SIZE: u64 = 1 << 20;
+
+// errno values reported by SYS_ERRNO.
+const EBADF: i32 = 9;
+const EFAULT: i32 = 14;
+const EACCES: i32 = 13;
+const EIO: i32 = 5;
+const ENAMETOOLONG: i32 = 36;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct Semihosting {
+    /// Host directory that holds all files the guest can open.
+    root: PathBuf,
+    /// Command line returned by SYS_GET_CMDLINE.
+    cmdline: String,
+    files: HashMap<u64, Handle>,
+    next_handle: u64,
+    /// errno of the last failed operation.
+    errno: i32,
+    /// Start of the program, used by SYS_CLOCK.
+    start: Instant,
+    /// Exit request of the guest, if it has not been handled yet.
+    request: Option<Shutdown>,
+}
+
+impl Semihosting {
+    pub fn new(root: PathBuf, cmdline: String) -> Self {
+        Self {
+            root,
+            cmdline,
+            files: HashMap::new(),
+            next_handle: 1,
+            errno: 0,
+            start: Instant::now(),
+            request: None,
+        }
+    }
+
+    /// Return the pending exit request and clear it.
+    pub fn take_request(&mut self) -> Option<Shutdown> {
+        self.request.take()
+    }
+
+    /// Return true if the ebreak at pc is wrapped in the semihosting marker instructions.
+    pub fn is_call(cpu: &mut CPU) -> bool {
+        let pc = cpu.pc;
+        let mut inst_at = |addr: u64| {
+            cpu.translate(addr, AccessType::Instruction)
+                .and_then(|p_addr| cpu.bus.load(p_addr, 32))
+                .ok()
+        };
+        inst_at(pc.wrapping_sub(4)) == Some(SEMIHOSTING_ENTRY)
+            && inst_at(pc.wrapping_add(4)) == Some(SEMIHOSTING_EXIT)
+    }
+
+    /// Execute the operation requested in a0/a1 and put the result in a0.
+    pub fn call(&mut self, cpu: &mut CPU) {
+        let op = cpu.regs[10];
+        let block = cpu.regs[11];
+        let result = match self.operation(cpu, op, block) {
+            Ok(value) => value,
+            Err(errno) => {
+                self.errno = errno;
+                -1
+            }
+        };
+        cpu.regs[10] = result as u64;
+    }
+
+    fn operation(&mut self, cpu: &mut CPU, op: u64, block: u64) -> Result<i64, i32> {
+        let arg = |cpu: &mut CPU, i: u64| read_u64(cpu, block.wrapping_add(i * 8));
+        match op {
+            SYS_OPEN => {
+                let (name, mode, len) = (arg(cpu, 0)?, arg(cpu, 1)?, arg(cpu, 2)?);
+                if len > MAX_NAME_LEN {
+                    return Err(ENAMETOOLONG);
+                }
+                let name = String::from_utf8_lossy(&read_bytes(cpu, name, len)?).into_owned();
+                let handle = self.open(&name, mode)?;
+                let fd = self.next_handle;
+                self.next_handle += 1;
+                self.files.insert(fd, handle);
+                Ok(fd as i64)
+            }
+            SYS_CLOSE => {
+                let fd = arg(cpu, 0)?;
+                self.files.remove(&fd).ok_or(EBADF)?;
+                Ok(0)
+            }
+            SYS_WRITEC => {
+                let c = read_bytes(cpu, block, 1)?;
+                write_console(&c);
+                Ok(0)
+            }
+            SYS_WRITE0 => {
+                let mut s = Vec::new();
+                loop {
+                    let c = read_bytes(cpu, block.wrapping_add(s.len() as u64), 1)?[0];
+                    if c == 0 {
+                        break;
+                    }
+                    s.push(c);
+                }
+                write_console(&s);
+                Ok(0)
+            }
+            SYS_WRITE => {
+                let (fd, buf, len) = (arg(cpu, 0)?, arg(cpu, 1)?, arg(cpu, 2)?);
+                let handle = self.files.get_mut(&fd).ok_or(EBADF)?;
+                let mut written = 0;
+                while written < len {
+                    let data = read_bytes(cpu, buf.wrapping_add(written), (len - written).min(CHUNK_SIZE))?;
+                    let n = match handle {
+                        Handle::Stdin => return Err(EBADF),
+                        Handle::Stdout => io::stdout().write_all(&data).and_then(|_| io::stdout().flush()).map(|_| data.len()),
+                        Handle::Stderr => io::stderr().write_all(&data).map(|_| data.len()),
+                        Handle::File(file) => file.write(&data),
+                    }.map_err(errno)?;
+                    written += n as u64;
+                    if n < data.len() {
+                        break;
+                    }
+                }
+                // The number of bytes that were not written.
+                Ok((len - written) as i64)
+            }
+            SYS_READ => {
+                let (fd, buf, len) = (arg(cpu, 0)?, arg(cpu, 1)?, arg(cpu, 2)?);
+                let handle = self.files.get_mut(&fd).ok_or(EBADF)?;
+                let mut data = vec![0; len.min(CHUNK_SIZE) as usize];
+                let mut read = 0;
+                // A short read, at the end of a file or of a line of the console, ends the call.
+                while read < len {
+                    let chunk = &mut data[..(len - read).min(CHUNK_SIZE) as usize];
+                    let n = match handle {
+                        Handle::Stdin => io::stdin().read(chunk),
+                        Handle::File(file) => file.read(chunk),
+                        _ => return Err(EBADF),
+                    }.map_err(errno)?;
+                    write_bytes(cpu, buf.wrapping_add(read), &chunk[..n])?;
+                    read += n as u64;
+                    if n < chunk.len() {
+                        break;
+                    }
+                }
+                // The number of bytes that were not read.
+                Ok((len - read) as i64)
+            }
+            SYS_ISTTY => {
+                let fd = arg(cpu, 0)?;
+                match self.files.get(&fd).ok_or(EBADF)? {
+                    Handle::File(_) => Ok(0),
+                    _ => Ok(1),
+                }
+            }
+            SYS_SEEK => {
+                let (fd, pos) = (arg(cpu, 0)?, arg(cpu, 1)?);
+                match self.files.get_mut(&fd).ok_or(EBADF)? {
+                    Handle::File(file) => file.seek(SeekFrom::Start(pos)).map_err(errno)?,
+                    _ => return Err(EBADF),
+                };
+                Ok(0)
+            }
+            SYS_FLEN => {
+                let fd = arg(cpu, 0)?;
+                match self.files.get(&fd).ok_or(EBADF)? {
+                    Handle::File(file) => Ok(file.metadata().map_err(errno)?.len() as i64),
+                    _ => Err(EBADF),
+                }
+            }
+            SYS_CLOCK => Ok((self.start.elapsed().as_millis() / 10) as i64),
+            SYS_TIME => Ok(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64),
+            SYS_ERRNO => Ok(self.errno as i64),
+            SYS_GET_CMDLINE => {
+                // The block holds a buffer and its size. The size is updated to the length
+                // of the command line, which must fit with its terminating NUL.
+                let (buf, len) = (arg(cpu, 0)?, arg(cpu, 1)?);
+                let mut cmdline = self.cmdline.clone().into_bytes();
+                if cmdline.len() as u64 >= len {
+                    return Err(EFAULT);
+                }
+                let cmdline_len = cmdline.len() as u64;
+                cmdline.push(0);
+                write_bytes(cpu, buf, &cmdline)?;
+                write_bytes(cpu, block.wrapping_add(8), &cmdline_len.to_le_bytes())?;
+                Ok(0)
+            }
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                // On RV64 the block holds the reason and the exit code.
+                let (reason, code) = (arg(cpu, 0)?, arg(cpu, 1)?);
+                let code = if reason == ADP_STOPPED_APPLICATION_EXIT { code as i32 } else { 1 };
+                self.request = Some(Shutdown::PowerOff(code));
+                Ok(0)
+            }
+            _ => {
+                println!("Unsupported semihosting operation {:#x}", op);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Open `name` inside the sandbox directory. `mode` is the index of an fopen mode string
+    /// in "r", "rb", "r+", "r+b", "w", "wb", "w+", "w+b", "a", "ab", "a+", "a+b".
+    fn open(&self, name: &str, mode: u64) -> Result<Handle, i32> {
+        if name == CONSOLE {
+            return match mode {
+                0..=3 => Ok(Handle::Stdin),
+                4..=7 => Ok(Handle::Stdout),
+                _ => Ok(Handle::Stderr),
+            };
+        }
+        let path = self.sandboxed(name).ok_or(EACCES)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            0 | 1 => options.read(true),
+            2 | 3 => options.read(true).write(true),
+            4 | 5 => options.write(true).create(true).truncate(true),
+            6 | 7 => options.read(true).write(true).create(true).truncate(true),
+            8 | 9 => options.append(true).create(true),
+            10 | 11 => options.read(true).append(true).create(true),
+            _ => return Err(EACCES),
+        };
+        options.open(path).map(Handle::File).map_err(errno)
+    }
+
+    /// Map a guest file name into the sandbox directory. Absolute names, names that climb out of
+    /// the directory and names that lead out of it through symbolic links are rejected. A file
+    /// that does not exist yet has to be in a directory of the sandbox, and must not be a dangling
+    /// symbolic link, which creating the file would follow.
+    fn sandboxed(&self, name: &str) -> Option<PathBuf> {
+        let name = Path::new(name);
+        if !name.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return None;
+        }
+        let root = self.root.canonicalize().ok()?;
+        let path = self.root.join(name);
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(_) if path.symlink_metadata().is_ok() => return None,
+            Err(_) => path.parent()?.canonicalize().ok()?.join(path.file_name()?),
+        };
+        path.starts_with(&root).then_some(path)
+    }
+}
+
+fn write_console(data: &[u8]) {
+    io::stdout().write_all(data).unwrap();
+    io::stdout().flush().unwrap();
+}
+
+// The memory of the guest is copied without counting, tracing or watching the accesses, which the
+// host makes rather than the guest. `len` is at most CHUNK_SIZE or MAX_NAME_LEN.
+fn read_bytes(cpu: &mut CPU, addr: u64, len: u64) -> Result<Vec<u8>, i32> {
+    let mut data = vec![0; len as usize];
+    cpu.load_bytes(addr, &mut data).map_err(|_| EFAULT)?;
+    Ok(data)
+}
+
+fn read_u64(cpu: &mut CPU, addr: u64) -> Result<u64, i32> {
+    let mut bytes = [0; 8];
+    cpu.load_bytes(addr, &mut bytes).map_err(|_| EFAULT)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn write_bytes(cpu: &mut CPU, addr: u64, data: &[u8]) -> Result<(), i32> {
+    cpu.store_bytes(addr, data).map_err(|_| EFAULT)
+}
+
+fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use crate::param::DRAM_BASE;
+
+    /// Return an empty directory for the test `name`.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("r-riscv-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Run the operation `op` with the parameters `args`, and return a0.
+    fn call(sh: &mut Semihosting, cpu: &mut CPU, op: u64, args: &[u64]) -> i64 {
+        let block = DRAM_BASE + 0x1000;
+        let bytes: Vec<u8> = args.iter().flat_map(|arg| arg.to_le_bytes()).collect();
+        cpu.store_bytes(block, &bytes).unwrap();
+        cpu.regs[10] = op;
+        cpu.regs[11] = block;
+        sh.call(cpu);
+        cpu.regs[10] as i64
+    }
+
+    #[test]
+    fn test_files() {
+        let root = scratch("semihosting-files");
+        let mut sh = Semihosting::new(root.clone(), String::new());
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        let (name, buf) = (DRAM_BASE + 0x2000, DRAM_BASE + 0x3000);
+        cpu.store_bytes(name, b"out.txt").unwrap();
+        cpu.store_bytes(buf, b"hello").unwrap();
+
+        let fd = call(&mut sh, &mut cpu, SYS_OPEN, &[name, 4, 7]) as u64;
+        assert_ne!(fd as i64, -1);
+        assert_eq!(call(&mut sh, &mut cpu, SYS_WRITE, &[fd, buf, 5]), 0);
+        assert_eq!(call(&mut sh, &mut cpu, SYS_CLOSE, &[fd]), 0);
+        assert_eq!(fs::read(root.join("out.txt")).unwrap(), b"hello");
+
+        // A read of a length the host could not allocate stops at the end of the file.
+        let fd = call(&mut sh, &mut cpu, SYS_OPEN, &[name, 0, 7]) as u64;
+        assert_ne!(fd as i64, -1);
+        let dest = DRAM_BASE + 0x4000;
+        assert_eq!(call(&mut sh, &mut cpu, SYS_READ, &[fd, dest, 1 << 62]), (1 << 62) - 5);
+        let mut data = [0; 5];
+        cpu.load_bytes(dest, &mut data).unwrap();
+        assert_eq!(&data, b"hello");
+        assert_eq!(call(&mut sh, &mut cpu, SYS_FLEN, &[fd]), 5);
+        assert_eq!(call(&mut sh, &mut cpu, SYS_CLOSE, &[fd]), 0);
+        assert_eq!(fs::read(root.join("out.txt")).unwrap(), b"hello");
+
+        assert_eq!(call(&mut sh, &mut cpu, SYS_OPEN, &[name, 0, 1 << 40]), -1);
+        assert_eq!(call(&mut sh, &mut cpu, SYS_ERRNO, &[]), ENAMETOOLONG as i64);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sandbox() {
+        let dir = scratch("semihosting-sandbox");
+        let (root, outside) = (dir.join("root"), dir.join("outside"));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret"), root.join("file")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("dir")).unwrap();
+        std::os::unix::fs::symlink(outside.join("new.txt"), root.join("dangling")).unwrap();
+        let sh = Semihosting::new(root.clone(), String::new());
+
+        assert!(sh.sandboxed("sub/new.txt").is_some());
+        assert!(sh.sandboxed("./new.txt").is_some());
+        assert!(sh.sandboxed("../outside/secret").is_none());
+        assert!(sh.sandboxed("/etc/passwd").is_none());
+        assert!(sh.sandboxed("file").is_none());
+        assert!(sh.sandboxed("dir/secret").is_none());
+        assert!(sh.sandboxed("dir/new.txt").is_none());
+        assert!(sh.sandboxed("dangling").is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
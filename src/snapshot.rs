@@ -0,0 +1,305 @@
+//! The snapshot module saves the whole machine to a file and restores it, so that a booted guest
+//! can be checkpointed once and resumed many times. A snapshot holds the hart, the CSRs, the dram
+//! and the state of the devices. The dram is saved sparse, leaving out the pages of zeros, and
+//! each page is run-length encoded. The disk is saved as an overlay of the sectors the guest wrote,
+//! so a snapshot is restored on top of the same disk image it was taken with.
+//!
+//! A snapshot is taken before a given instruction, or when the emulator receives SIGUSR1.
+
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::cpu::CPU;
+use crate::sbi::Sbi;
+
+const MAGIC: &[u8; 8] = b"RRVSNAP\0";
+/// The version of the format, to be bumped when it changes.
+const VERSION: u32 = 2;
+
+// The number of SIGUSR1 differs between the Unix systems.
+#[cfg(target_os = "linux")]
+const SIGUSR1: i32 = 10;
+#[cfg(not(target_os = "linux"))]
+const SIGUSR1: i32 = 30;
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+/// Set by the signal handler, and cleared once the snapshot is taken.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request(_signum: i32) {
+    REQUESTED.store(true, Ordering::Release);
+}
+
+/// Takes the snapshots asked for on the command line.
+pub struct Snapshotter {
+    path: String,
+    /// The number of retired instructions to take a snapshot at.
+    at: Option<u64>,
+}
+
+impl Snapshotter {
+    /// Save snapshots to `path`, before the instruction numbered `at` if given, and whenever
+    /// the emulator receives SIGUSR1.
+    pub fn new(path: String, at: Option<u64>) -> Self {
+        unsafe {
+            signal(SIGUSR1, request);
+        }
+        Self { path, at }
+    }
+
+    /// Take a snapshot if one is due before the next instruction.
+    pub fn before_step(&mut self, cpu: &CPU) -> io::Result<()> {
+        let due = self.at == Some(cpu.instret);
+        if REQUESTED.swap(false, Ordering::Acquire) || due {
+            save(cpu, &self.path)?;
+            println!("Saved a snapshot to {} at instruction {}", self.path, cpu.instret);
+        }
+        Ok(())
+    }
+}
+
+/// Save the machine to the file at `path`.
+pub fn save(cpu: &CPU, path: &str) -> io::Result<()> {
+    if cpu.bus.memory.is_some() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "programs run in user mode cannot be saved"));
+    }
+    let mut w = Writer::default();
+    w.bytes(MAGIC);
+    w.u32(VERSION);
+    write_machine(cpu, &mut w, true);
+    fs::write(path, w.buf)
+}
+
+/// Restore the machine from the file at `path`. The machine has to be set up like the one that
+/// was saved, with the same disk image.
+pub fn restore(cpu: &mut CPU, path: &str) -> io::Result<()> {
+    let buf = fs::read(path)?;
+    let mut r = Reader { buf: &buf, pos: 0 };
+    if r.bytes(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a snapshot"));
+    }
+    let version = r.u32()?;
+    if version != VERSION {
+        return Err(invalid(&format!("snapshot version {} is not supported", version)));
+    }
+    read_machine(cpu, &mut r, true)?;
+    if r.pos != buf.len() {
+        return Err(invalid("trailing data in snapshot"));
+    }
+    Ok(())
+}
+
+/// Return the state of the machine but the dram, which is journaled instead, for a checkpoint
+/// kept in memory.
+pub fn checkpoint(cpu: &CPU) -> Vec<u8> {
+    let mut w = Writer::default();
+    write_machine(cpu, &mut w, false);
+    w.buf
+}
+
+/// Go back to the state of `checkpoint`.
+pub fn rewind(cpu: &mut CPU, checkpoint: &[u8]) {
+    read_machine(cpu, &mut Reader { buf: checkpoint, pos: 0 }, false).expect("checkpoints are valid");
+}
+
+fn write_machine(cpu: &CPU, w: &mut Writer, dram: bool) {
+    for reg in cpu.regs {
+        w.u64(reg);
+    }
+    w.u64(cpu.pc);
+    w.u64(cpu.mode);
+    w.bool(cpu.enable_paging);
+    w.u64(cpu.page_table);
+    w.u64(cpu.instret);
+    cpu.csr.save(w);
+    if dram {
+        cpu.bus.dram.save(w);
+    }
+    cpu.bus.save(w);
+    match &cpu.sbi {
+        Some(sbi) => {
+            w.bool(true);
+            sbi.save(w);
+        }
+        None => w.bool(false),
+    }
+}
+
+fn read_machine(cpu: &mut CPU, r: &mut Reader, dram: bool) -> io::Result<()> {
+    for reg in cpu.regs.iter_mut() {
+        *reg = r.u64()?;
+    }
+    cpu.pc = r.u64()?;
+    cpu.mode = r.u64()?;
+    cpu.enable_paging = r.bool()?;
+    cpu.page_table = r.u64()?;
+    cpu.instret = r.u64()?;
+    cpu.csr.restore(r)?;
+    if dram {
+        cpu.bus.dram.restore(r)?;
+    }
+    cpu.bus.restore(r)?;
+    if r.bool()? {
+        cpu.sbi.get_or_insert_with(Sbi::new).restore(r)?;
+    }
+    Ok(())
+}
+
+pub fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Serializes the state, in little endian.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn option(&mut self, value: Option<u64>) {
+        self.bool(value.is_some());
+        self.u64(value.unwrap_or(0));
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Write `bytes` run-length encoded, preceded by the length of the encoding.
+    pub fn compressed(&mut self, bytes: &[u8]) {
+        let packed = compress(bytes);
+        self.u32(packed.len() as u32);
+        self.bytes(&packed);
+    }
+}
+
+/// Deserializes what a `Writer` wrote.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    pub fn bytes(&mut self, len: usize) -> io::Result<&[u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + len).ok_or_else(|| invalid("truncated snapshot"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn option(&mut self) -> io::Result<Option<u64>> {
+        let some = self.bool()?;
+        let value = self.u64()?;
+        Ok(some.then_some(value))
+    }
+
+    /// Read what `Writer::compressed` wrote into `buf`, which has the size of the original.
+    pub fn compressed(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let len = self.u32()? as usize;
+        decompress(self.bytes(len)?, buf)
+    }
+}
+
+/// Encode `bytes` as runs: a count byte below 0x80 is followed by that many plus one literal
+/// bytes, and a count byte of 0x80 or more by one byte repeated the count minus 0x7e times.
+fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let run = bytes[i..].iter().take(129).take_while(|&&b| b == bytes[i]).count();
+        if run >= 3 {
+            out.push((run + 0x7e) as u8);
+            out.push(bytes[i]);
+            i += run;
+            continue;
+        }
+        // Literals go up to the next run of three.
+        let start = i;
+        while i < bytes.len() && i - start < 128 {
+            if i + 2 < bytes.len() && bytes[i] == bytes[i + 1] && bytes[i] == bytes[i + 2] {
+                break;
+            }
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&bytes[start..i]);
+    }
+    out
+}
+
+fn decompress(packed: &[u8], buf: &mut [u8]) -> io::Result<()> {
+    let mut packed = packed.iter();
+    let mut pos = 0;
+    while let Some(&count) = packed.next() {
+        let len = match count {
+            0..=0x7f => count as usize + 1,
+            _ => count as usize - 0x7e,
+        };
+        let out = buf.get_mut(pos..pos + len).ok_or_else(|| invalid("corrupt page in snapshot"))?;
+        if count < 0x80 {
+            for byte in out {
+                *byte = *packed.next().ok_or_else(|| invalid("corrupt page in snapshot"))?;
+            }
+        } else {
+            out.fill(*packed.next().ok_or_else(|| invalid("corrupt page in snapshot"))?);
+        }
+        pos += len;
+    }
+    match pos == buf.len() {
+        true => Ok(()),
+        false => Err(invalid("corrupt page in snapshot")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        let mut page = vec![0; 4096];
+        page[100..110].copy_from_slice(b"0123456789");
+        page[200..203].fill(7);
+        page[4000] = 1;
+        let packed = compress(&page);
+        assert!(packed.len() < 100);
+        let mut out = vec![0xff; 4096];
+        decompress(&packed, &mut out).unwrap();
+        assert_eq!(out, page);
+
+        let noise: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut out = vec![0; 1000];
+        decompress(&compress(&noise), &mut out).unwrap();
+        assert_eq!(out, noise);
+    }
+}
@@ -0,0 +1,169 @@
+//! The stats module counts what the guest executes, to see the instruction mix a compiler
+//! produces and which instructions a test suite covers. It counts the instructions by mnemonic,
+//! the conditional branches taken and not taken, the loads and stores by size, and the traps by
+//! cause, and lists the instructions `CPU::execute` decodes that never ran. The counts are written
+//! as JSON when the emulator exits:
+//!
+//! ```text
+//! {
+//!   "retired": 11711,
+//!   "executed": {"addi": 5312, "bne": 5000, ...},
+//!   "branches": {"bne": {"taken": 4950, "not_taken": 50, "taken_ratio": 0.99}, ...},
+//!   "loads": {"1": 0, "2": 0, "4": 0, "8": 50},
+//!   "stores": {"1": 0, "2": 0, "4": 1, "8": 50},
+//!   "exceptions": {"11": 3},
+//!   "interrupts": {"7": 1},
+//!   "never_executed": ["lb", "lh", ...]
+//! }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use crate::disasm::{mnemonic, MNEMONICS};
+use crate::interrupt::MASK_INTERRUPT_BIT;
+
+// The names of the conditional branches, by funct3.
+const BRANCHES: [&str; 8] = ["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"];
+
+pub struct Stats {
+    path: String,
+    /// The instructions decoded, by their bits, whether they retired or trapped.
+    executed: HashMap<u32, u64>,
+    retired: u64,
+    /// The conditional branches taken and not taken, by funct3.
+    branches: [(u64, u64); 8],
+    /// The loads and stores of 1, 2, 4 and 8 bytes.
+    loads: [u64; 4],
+    stores: [u64; 4],
+    /// The traps, by cause.
+    exceptions: BTreeMap<u64, u64>,
+    interrupts: BTreeMap<u64, u64>,
+}
+
+impl Stats {
+    /// Write the statistics to the file at `path` at exit.
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            executed: HashMap::new(),
+            retired: 0,
+            branches: [(0, 0); 8],
+            loads: [0; 4],
+            stores: [0; 4],
+            exceptions: BTreeMap::new(),
+            interrupts: BTreeMap::new(),
+        }
+    }
+
+    /// Count `inst`, which is about to be executed.
+    pub fn decode(&mut self, inst: u64) {
+        *self.executed.entry(inst as u32).or_insert(0) += 1;
+    }
+
+    /// Count `inst` at `pc`, which has just retired and goes on at `next_pc`.
+    pub fn retire(&mut self, inst: u64, pc: u64, next_pc: u64) {
+        self.retired += 1;
+        if inst & 0x7f == 0x63 {
+            let (taken, not_taken) = &mut self.branches[((inst >> 12) & 0x7) as usize];
+            match next_pc == pc.wrapping_add(4) {
+                true => *not_taken += 1,
+                false => *taken += 1,
+            }
+        }
+    }
+
+    /// Count a load, or a store if `write` is set, of `size` bits.
+    pub fn access(&mut self, size: u64, write: bool) {
+        let i = (size / 8).trailing_zeros() as usize;
+        match write {
+            true => self.stores[i] += 1,
+            false => self.loads[i] += 1,
+        }
+    }
+
+    /// Count a trap with the cause `code`, an interrupt if its interrupt bit is set.
+    pub fn trap(&mut self, code: u64) {
+        let traps = match code & MASK_INTERRUPT_BIT {
+            0 => &mut self.exceptions,
+            _ => &mut self.interrupts,
+        };
+        *traps.entry(code & !MASK_INTERRUPT_BIT).or_insert(0) += 1;
+    }
+
+    /// Write the statistics as JSON.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut executed: BTreeMap<&str, u64> = BTreeMap::new();
+        for (&inst, &count) in &self.executed {
+            *executed.entry(mnemonic(inst as u64).unwrap_or("unknown")).or_insert(0) += count;
+        }
+        let never: Vec<String> = MNEMONICS.iter()
+            .filter(|name| !executed.contains_key(*name))
+            .map(|name| format!("\"{}\"", name))
+            .collect();
+        let branches = BRANCHES.iter().zip(self.branches).filter(|(name, _)| !name.is_empty()).map(|(name, (taken, not_taken))| {
+            let ratio = match taken + not_taken {
+                0 => 0.0,
+                total => taken as f64 / total as f64,
+            };
+            (name.to_string(), format!("{{\"taken\": {}, \"not_taken\": {}, \"taken_ratio\": {:.4}}}", taken, not_taken, ratio))
+        });
+        let sizes = |counts: &[u64; 4]| object(counts.iter().enumerate().map(|(i, n)| ((1 << i).to_string(), n.to_string())));
+        let causes = |traps: &BTreeMap<u64, u64>| object(traps.iter().map(|(code, n)| (code.to_string(), n.to_string())));
+
+        let mut out = BufWriter::new(File::create(&self.path)?);
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"retired\": {},", self.retired)?;
+        writeln!(out, "  \"executed\": {},", object(executed.iter().map(|(name, n)| (name.to_string(), n.to_string()))))?;
+        writeln!(out, "  \"branches\": {},", object(branches))?;
+        writeln!(out, "  \"loads\": {},", sizes(&self.loads))?;
+        writeln!(out, "  \"stores\": {},", sizes(&self.stores))?;
+        writeln!(out, "  \"exceptions\": {},", causes(&self.exceptions))?;
+        writeln!(out, "  \"interrupts\": {},", causes(&self.interrupts))?;
+        writeln!(out, "  \"never_executed\": [{}]", never.join(", "))?;
+        writeln!(out, "}}")?;
+        out.flush()
+    }
+}
+
+/// Return a JSON object of the keys and the values, which are already JSON.
+fn object(fields: impl Iterator<Item = (String, String)>) -> String {
+    let fields: Vec<String> = fields.map(|(key, value)| format!("\"{}\": {}", key, value)).collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let path = std::env::temp_dir().join(format!("r-riscv-stats-{}", std::process::id()));
+        let mut stats = Stats::new(path.to_str().unwrap().to_string());
+        // bne zero, zero, 16 falls through, beq zero, zero, 16 branches, and so does a jal.
+        for (inst, next_pc) in [(0x00001863, 0x1004), (0x00000863, 0x1010), (0x00000863, 0x1004), (0x0100006f, 0x1010)] {
+            stats.decode(inst);
+            stats.retire(inst, 0x1000, next_pc);
+        }
+        assert_eq!(stats.branches[0], (1, 1));
+        assert_eq!(stats.branches[1], (0, 1));
+        assert_eq!(stats.retired, 4);
+
+        for (size, write) in [(8, false), (16, true), (32, false), (64, true), (64, true)] {
+            stats.access(size, write);
+        }
+        assert_eq!(stats.loads, [1, 0, 1, 0]);
+        assert_eq!(stats.stores, [0, 1, 0, 2]);
+
+        stats.finish().unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(json.contains("\"executed\": {\"beq\": 2, \"bne\": 1, \"jal\": 1},"));
+        assert!(json.contains("\"beq\": {\"taken\": 1, \"not_taken\": 1, \"taken_ratio\": 0.5000}"));
+        assert!(json.contains("\"loads\": {\"1\": 1, \"2\": 0, \"4\": 1, \"8\": 0},"));
+        assert!(json.contains("\"stores\": {\"1\": 0, \"2\": 1, \"4\": 0, \"8\": 2},"));
+        let never = json.lines().find(|line| line.contains("never_executed")).unwrap();
+        assert!(never.contains("\"lb\"") && never.contains("\"bltu\""));
+        assert!(!never.contains("\"beq\"") && !never.contains("\"jal\""));
+    }
+}
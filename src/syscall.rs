@@ -0,0 +1,977 @@
+//! The syscall module runs statically linked Linux programs without a kernel. The program gets a
+//! flat address space and starts in U-mode, and its `ecall`s are served by the emulator with the
+//! files and the clock of the host. The calling convention is the one of Linux on RISC-V: a7
+//! holds the syscall number, a0-a5 the arguments, and a0 the result or a negated errno.
+//!
+//! Programs built against newlib for riscv-pk use the same convention and mostly the same
+//! numbers, plus a few older calls that take a path without a directory. Those are served by
+//! turning them into their *at counterparts.
+//!
+//! The files of the program are the files of the host, with their Unix permissions and metadata,
+//! so user mode needs a Unix host.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt};
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use crate::bus::Shutdown;
+use crate::config::Abi;
+use crate::cpu::{CPU, User};
+use crate::elf::{Elf, PHDR_ENT_SIZE};
+use crate::memory::SparseMemory;
+use crate::param::PAGE_SIZE;
+
+// The layout of the address space. The heap follows the program, mappings without a fixed
+// address grow up from MMAP_BASE, and the stack grows down from STACK_TOP.
+const MMAP_BASE: u64 = 0x20_0000_0000;
+const STACK_TOP: u64 = 0x40_0000_0000;
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+// The end of the mappings without a fixed address, below the stack.
+const MMAP_END: u64 = STACK_TOP - STACK_SIZE;
+
+// The most bytes a call copies between the program and the host at once. Larger reads and
+// writes are short, as the program has to expect anyway.
+const MAX_COPY: u64 = 1 << 20;
+// The most buffers readv and writev take.
+const IOV_MAX: u64 = 1024;
+
+// Syscall numbers, from include/uapi/asm-generic/unistd.h of Linux.
+const SYS_GETCWD: u64 = 17;
+const SYS_DUP: u64 = 23;
+const SYS_DUP3: u64 = 24;
+const SYS_FCNTL: u64 = 25;
+const SYS_IOCTL: u64 = 29;
+const SYS_MKDIRAT: u64 = 34;
+const SYS_UNLINKAT: u64 = 35;
+const SYS_LINKAT: u64 = 37;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_PREAD64: u64 = 67;
+const SYS_PWRITE64: u64 = 68;
+const SYS_READLINKAT: u64 = 78;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX: u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_SCHED_YIELD: u64 = 124;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_TIMES: u64 = 153;
+const SYS_UNAME: u64 = 160;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_GETRANDOM: u64 = 278;
+// Syscall numbers only known to riscv-pk, from pk/syscall.h.
+const SYS_OPEN: u64 = 1024;
+const SYS_LINK: u64 = 1025;
+const SYS_UNLINK: u64 = 1026;
+const SYS_MKDIR: u64 = 1030;
+const SYS_ACCESS: u64 = 1033;
+const SYS_STAT: u64 = 1038;
+const SYS_LSTAT: u64 = 1039;
+const SYS_TIME: u64 = 1062;
+
+// Error numbers.
+const EPERM: i64 = 1;
+const EBADF: i64 = 9;
+const EIO: i64 = 5;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const EEXIST: i64 = 17;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ERANGE: i64 = 34;
+const ENOSYS: i64 = 38;
+
+// Flags of openat.
+const AT_FDCWD: u64 = -100i64 as u64;
+const O_ACCMODE: u64 = 0o3;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+// Flags of the *at calls.
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_REMOVEDIR: u64 = 0x200;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+// Commands of fcntl and ioctl.
+const F_DUPFD: u64 = 0;
+const F_GETFD: u64 = 1;
+const F_SETFD: u64 = 2;
+const F_GETFL: u64 = 3;
+const F_SETFL: u64 = 4;
+const F_DUPFD_CLOEXEC: u64 = 1030;
+const TCGETS: u64 = 0x5401;
+const TIOCGWINSZ: u64 = 0x5413;
+const TERMIOS_SIZE: usize = 36;
+
+// Flags of mmap.
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_FIXED_NOREPLACE: u64 = 0x100000;
+
+const CLOCK_REALTIME: u64 = 0;
+// Clock ticks per second of times, also announced as AT_CLKTCK.
+const CLK_TCK: u64 = 100;
+const RLIMIT_STACK: u64 = 3;
+const RLIM_INFINITY: u64 = u64::MAX;
+const S_IFCHR: u32 = 0o020000;
+const SIGACTION_SIZE: usize = 24;
+
+// Entries of the auxiliary vector.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+// The extensions of the hart, one bit per letter from 'a' at bit 0: rv64ima.
+const HWCAP: u64 = 1 | (1 << (b'i' - b'a')) | (1 << (b'm' - b'a'));
+
+/// An open file of the program.
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+impl Handle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Handle::Stdin => io::stdin().read(buf),
+            Handle::File(file) => file.read(buf),
+            _ => Err(io::Error::from_raw_os_error(EBADF as i32)),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Handle::Stdout => io::stdout().write_all(data).and_then(|_| io::stdout().flush()).map(|_| data.len()),
+            Handle::Stderr => io::stderr().write_all(data).map(|_| data.len()),
+            Handle::File(file) => file.write(data),
+            Handle::Stdin => Err(io::Error::from_raw_os_error(EBADF as i32)),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Handle> {
+        Ok(match self {
+            Handle::Stdin => Handle::Stdin,
+            Handle::Stdout => Handle::Stdout,
+            Handle::Stderr => Handle::Stderr,
+            Handle::File(file) => Handle::File(file.try_clone()?),
+        })
+    }
+
+    fn is_terminal(&self) -> bool {
+        match self {
+            Handle::Stdin => io::stdin().is_terminal(),
+            Handle::Stdout => io::stdout().is_terminal(),
+            Handle::Stderr => io::stderr().is_terminal(),
+            Handle::File(_) => false,
+        }
+    }
+
+    fn stat(&self) -> io::Result<Stat> {
+        match self {
+            Handle::File(file) => Ok(Stat::from(&file.metadata()?)),
+            _ => Ok(Stat { mode: S_IFCHR | 0o620, nlink: 1, blksize: 1024, ..Stat::default() }),
+        }
+    }
+}
+
+/// The struct stat of asm-generic.
+#[derive(Default)]
+struct Stat {
+    dev: u64,
+    ino: u64,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    size: i64,
+    blksize: i32,
+    blocks: i64,
+    atime: (i64, i64),
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl Stat {
+    fn from(meta: &Metadata) -> Stat {
+        Stat {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            mode: meta.mode(),
+            nlink: meta.nlink() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            rdev: meta.rdev(),
+            size: meta.size() as i64,
+            blksize: meta.blksize() as i32,
+            blocks: meta.blocks() as i64,
+            atime: (meta.atime(), meta.atime_nsec()),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+            ctime: (meta.ctime(), meta.ctime_nsec()),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.dev.to_le_bytes());
+        buf.extend_from_slice(&self.ino.to_le_bytes());
+        for field in [self.mode, self.nlink, self.uid, self.gid] {
+            buf.extend_from_slice(&field.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.rdev.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.extend_from_slice(&self.size.to_le_bytes());
+        buf.extend_from_slice(&self.blksize.to_le_bytes());
+        buf.extend_from_slice(&0i32.to_le_bytes());
+        buf.extend_from_slice(&self.blocks.to_le_bytes());
+        for (sec, nsec) in [self.atime, self.mtime, self.ctime] {
+            buf.extend_from_slice(&sec.to_le_bytes());
+            buf.extend_from_slice(&nsec.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0; 8]);
+        buf
+    }
+}
+
+pub struct Syscalls {
+    abi: Abi,
+    /// Path of the program, returned for /proc/self/exe.
+    exe: String,
+    files: HashMap<u64, Handle>,
+    /// Start and current end of the heap.
+    brk_start: u64,
+    brk: u64,
+    /// Where the next mapping without a fixed address goes.
+    mmap_next: u64,
+    start: Instant,
+    /// State of the generator behind getrandom.
+    random: u64,
+    /// Exit request of the program, if it has not been handled yet.
+    request: Option<Shutdown>,
+}
+
+impl Syscalls {
+    fn new(abi: Abi, exe: &str, brk: u64) -> Self {
+        let files = HashMap::from([(0, Handle::Stdin), (1, Handle::Stdout), (2, Handle::Stderr)]);
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        Self {
+            abi,
+            exe: String::from(exe),
+            files,
+            brk_start: brk,
+            brk,
+            mmap_next: MMAP_BASE,
+            start: Instant::now(),
+            random: seed | 1,
+            request: None,
+        }
+    }
+
+    /// Load the ELF program at `path` into a fresh address space and start it in U-mode with
+    /// `args` as its command line and the environment of the emulator. Its syscalls are served
+    /// as `abi` defines them.
+    pub fn start(cpu: &mut CPU, abi: Abi, path: &str, args: &[String]) -> io::Result<()> {
+        let elf = Elf::parse(fs::read(path)?)?;
+        elf.validate()?;
+        let mut memory = SparseMemory::new();
+        elf.load_virtual(&mut memory)?;
+        memory.map(STACK_TOP - STACK_SIZE..STACK_TOP);
+        cpu.bus.memory = Some(memory);
+
+        let env: Vec<String> = env::vars().map(|(key, value)| format!("{}={}", key, value)).collect();
+        let mut syscalls = Syscalls::new(abi, path, elf.virtual_span().end.next_multiple_of(PAGE_SIZE));
+        cpu.regs[2] = syscalls.setup_stack(cpu, &elf, args, &env)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "arguments do not fit on the stack"))?;
+        cpu.pc = elf.entry;
+        cpu.mode = User;
+        cpu.syscalls = Some(syscalls);
+        Ok(())
+    }
+
+    /// Lay out the strings, argc, argv, envp and the auxiliary vector at the top of the stack
+    /// as Linux does, and return the stack pointer.
+    fn setup_stack(&mut self, cpu: &mut CPU, elf: &Elf, args: &[String], env: &[String]) -> Result<u64, i64> {
+        let mut sp = STACK_TOP;
+        let mut push = |cpu: &mut CPU, data: &[u8]| {
+            sp -= data.len() as u64;
+            write_bytes(cpu, sp, data).map(|_| sp)
+        };
+        let mut strings = Vec::new();
+        for s in args.iter().chain(env) {
+            strings.push(push(cpu, &[s.as_bytes(), &[0]].concat())?);
+        }
+        let random = [self.next_random().to_le_bytes(), self.next_random().to_le_bytes()].concat();
+        let random = push(cpu, &random)?;
+        let (argv, envp) = strings.split_at(args.len());
+
+        let (phdr, phnum) = elf.program_headers().unwrap_or((0, 0));
+        let execfn = argv.first().copied().unwrap_or(0);
+        let ids = host_ids();
+        let auxv = [
+            (AT_PHDR, phdr), (AT_PHENT, PHDR_ENT_SIZE), (AT_PHNUM, phnum), (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, elf.entry), (AT_UID, ids.0), (AT_EUID, ids.0), (AT_GID, ids.1), (AT_EGID, ids.1),
+            (AT_HWCAP, HWCAP), (AT_CLKTCK, CLK_TCK), (AT_RANDOM, random), (AT_EXECFN, execfn), (AT_NULL, 0),
+        ];
+        let mut words = vec![argv.len() as u64];
+        words.extend(argv);
+        words.push(0);
+        words.extend(envp);
+        words.push(0);
+        words.extend(auxv.iter().flat_map(|&(key, value)| [key, value]));
+
+        // The stack pointer is 16-byte aligned at entry.
+        let sp = (sp - words.len() as u64 * 8) & !0xf;
+        write_bytes(cpu, sp, &words.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<u8>>())?;
+        Ok(sp)
+    }
+
+    /// Return the pending exit request and clear it.
+    pub fn take_request(&mut self) -> Option<Shutdown> {
+        self.request.take()
+    }
+
+    /// Serve the syscall of the program and return to the instruction after the `ecall`.
+    pub fn call(&mut self, cpu: &mut CPU) {
+        let number = cpu.regs[17];
+        let args = [cpu.regs[10], cpu.regs[11], cpu.regs[12], cpu.regs[13], cpu.regs[14], cpu.regs[15]];
+        let (number, args) = match self.abi {
+            Abi::Linux => (number, args),
+            Abi::Pk => pk(number, args),
+        };
+        cpu.regs[10] = match self.syscall(cpu, number, args) {
+            Ok(value) => value,
+            Err(errno) => -errno as u64,
+        };
+        cpu.pc += 4;
+    }
+
+    fn syscall(&mut self, cpu: &mut CPU, number: u64, a: [u64; 6]) -> Result<u64, i64> {
+        match number {
+            SYS_GETCWD => {
+                let cwd = env::current_dir().map_err(errno)?;
+                let cwd = [cwd.as_os_str().as_encoded_bytes(), &[0]].concat();
+                if cwd.len() as u64 > a[1] {
+                    return Err(ERANGE);
+                }
+                write_bytes(cpu, a[0], &cwd)?;
+                Ok(cwd.len() as u64)
+            }
+            SYS_DUP => {
+                let handle = self.handle(a[0])?.try_clone().map_err(errno)?;
+                Ok(self.insert(handle, 0))
+            }
+            SYS_DUP3 => {
+                if a[0] == a[1] {
+                    return Err(EINVAL);
+                }
+                let handle = self.handle(a[0])?.try_clone().map_err(errno)?;
+                self.files.insert(a[1], handle);
+                Ok(a[1])
+            }
+            SYS_FCNTL => {
+                let handle = self.handle(a[0])?;
+                match a[1] {
+                    F_DUPFD | F_DUPFD_CLOEXEC => {
+                        let handle = handle.try_clone().map_err(errno)?;
+                        Ok(self.insert(handle, a[2]))
+                    }
+                    F_GETFD | F_SETFD | F_GETFL | F_SETFL => Ok(0),
+                    _ => Err(EINVAL),
+                }
+            }
+            SYS_IOCTL => {
+                // Only say whether the console is a terminal, which decides how stdio buffers.
+                if !self.handle(a[0])?.is_terminal() {
+                    return Err(ENOTTY);
+                }
+                match a[1] {
+                    TCGETS => write_bytes(cpu, a[2], &[0; TERMIOS_SIZE])?,
+                    TIOCGWINSZ => write_bytes(cpu, a[2], &[24, 0, 80, 0, 0, 0, 0, 0])?,
+                    _ => return Err(ENOTTY),
+                }
+                Ok(0)
+            }
+            SYS_MKDIRAT => {
+                let path = self.path(cpu, a[0], a[1])?;
+                fs::DirBuilder::new().mode(a[2] as u32).create(path).map_err(errno)?;
+                Ok(0)
+            }
+            SYS_UNLINKAT => {
+                let path = self.path(cpu, a[0], a[1])?;
+                match a[2] & AT_REMOVEDIR {
+                    0 => fs::remove_file(path),
+                    _ => fs::remove_dir(path),
+                }.map_err(errno)?;
+                Ok(0)
+            }
+            SYS_LINKAT => {
+                let (old, new) = (self.path(cpu, a[0], a[1])?, self.path(cpu, a[2], a[3])?);
+                fs::hard_link(old, new).map_err(errno)?;
+                Ok(0)
+            }
+            SYS_FACCESSAT => {
+                fs::metadata(self.path(cpu, a[0], a[1])?).map_err(errno)?;
+                Ok(0)
+            }
+            SYS_OPENAT => {
+                let path = self.path(cpu, a[0], a[1])?;
+                let flags = a[2];
+                let mut options = OpenOptions::new();
+                match flags & O_ACCMODE {
+                    O_WRONLY => options.write(true),
+                    O_RDWR => options.read(true).write(true),
+                    _ => options.read(true),
+                };
+                options.append(flags & O_APPEND != 0).truncate(flags & O_TRUNC != 0);
+                if flags & O_CREAT != 0 {
+                    options.create(flags & O_EXCL == 0).create_new(flags & O_EXCL != 0).mode(a[3] as u32);
+                }
+                let file = options.open(path).map_err(errno)?;
+                Ok(self.insert(Handle::File(file), 0))
+            }
+            SYS_CLOSE => {
+                self.files.remove(&a[0]).ok_or(EBADF)?;
+                Ok(0)
+            }
+            SYS_LSEEK => {
+                let pos = match a[2] {
+                    0 => SeekFrom::Start(a[1]),
+                    1 => SeekFrom::Current(a[1] as i64),
+                    2 => SeekFrom::End(a[1] as i64),
+                    _ => return Err(EINVAL),
+                };
+                match self.handle(a[0])? {
+                    Handle::File(file) => file.seek(pos).map_err(errno),
+                    _ => Err(ESPIPE),
+                }
+            }
+            SYS_READ => {
+                let mut buf = vec![0; a[2].min(MAX_COPY) as usize];
+                let len = self.handle(a[0])?.read(&mut buf).map_err(errno)?;
+                write_bytes(cpu, a[1], &buf[..len])?;
+                Ok(len as u64)
+            }
+            SYS_WRITE => {
+                let data = read_bytes(cpu, a[1], a[2].min(MAX_COPY))?;
+                self.handle(a[0])?.write(&data).map(|len| len as u64).map_err(errno)
+            }
+            SYS_READV | SYS_WRITEV => {
+                if a[2] > IOV_MAX {
+                    return Err(EINVAL);
+                }
+                let mut total = 0;
+                for i in 0..a[2] {
+                    let iov = read_bytes(cpu, a[1].wrapping_add(i * 16), 16)?;
+                    let base = u64::from_le_bytes(iov[..8].try_into().unwrap());
+                    let len = u64::from_le_bytes(iov[8..].try_into().unwrap());
+                    let done = if number == SYS_READV {
+                        let mut buf = vec![0; len.min(MAX_COPY) as usize];
+                        let done = self.handle(a[0])?.read(&mut buf).map_err(errno)?;
+                        write_bytes(cpu, base, &buf[..done])?;
+                        done as u64
+                    } else {
+                        let data = read_bytes(cpu, base, len.min(MAX_COPY))?;
+                        self.handle(a[0])?.write(&data).map_err(errno)? as u64
+                    };
+                    total += done;
+                    if done < len {
+                        break;
+                    }
+                }
+                Ok(total)
+            }
+            SYS_PREAD64 => {
+                let Handle::File(file) = self.handle(a[0])? else { return Err(ESPIPE) };
+                let mut buf = vec![0; a[2].min(MAX_COPY) as usize];
+                let len = file.read_at(&mut buf, a[3]).map_err(errno)?;
+                write_bytes(cpu, a[1], &buf[..len])?;
+                Ok(len as u64)
+            }
+            SYS_PWRITE64 => {
+                let data = read_bytes(cpu, a[1], a[2].min(MAX_COPY))?;
+                let Handle::File(file) = self.handle(a[0])? else { return Err(ESPIPE) };
+                file.write_at(&data, a[3]).map(|len| len as u64).map_err(errno)
+            }
+            SYS_READLINKAT => {
+                let path = self.path(cpu, a[0], a[1])?;
+                let target = match path.as_str() {
+                    "/proc/self/exe" => fs::canonicalize(&self.exe).map_err(errno)?,
+                    _ => fs::read_link(path).map_err(errno)?,
+                };
+                let target = target.as_os_str().as_encoded_bytes();
+                let len = target.len().min(a[3] as usize);
+                write_bytes(cpu, a[2], &target[..len])?;
+                Ok(len as u64)
+            }
+            SYS_NEWFSTATAT => {
+                let stat = if a[3] & AT_EMPTY_PATH != 0 && read_str(cpu, a[1])?.is_empty() {
+                    self.handle(a[0])?.stat().map_err(errno)?
+                } else {
+                    let path = self.path(cpu, a[0], a[1])?;
+                    let meta = match a[3] & AT_SYMLINK_NOFOLLOW {
+                        0 => fs::metadata(path),
+                        _ => fs::symlink_metadata(path),
+                    };
+                    Stat::from(&meta.map_err(errno)?)
+                };
+                write_bytes(cpu, a[2], &stat.to_bytes())?;
+                Ok(0)
+            }
+            SYS_FSTAT => {
+                let stat = self.handle(a[0])?.stat().map_err(errno)?;
+                write_bytes(cpu, a[1], &stat.to_bytes())?;
+                Ok(0)
+            }
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.request = Some(Shutdown::PowerOff(a[0] as i32 & 0xff));
+                Ok(0)
+            }
+            // There is a single thread, so there is no one to wait for or to wake up.
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(process::id() as u64),
+            SYS_FUTEX | SYS_SET_ROBUST_LIST | SYS_SCHED_YIELD => Ok(0),
+            SYS_CLOCK_GETTIME => {
+                let time = match a[0] {
+                    CLOCK_REALTIME => SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+                    _ => self.start.elapsed(),
+                };
+                write_u64s(cpu, a[1], &[time.as_secs(), time.subsec_nanos() as u64])?;
+                Ok(0)
+            }
+            // Signals are never delivered, so every handler and mask is accepted.
+            SYS_RT_SIGACTION => {
+                if a[2] != 0 {
+                    write_bytes(cpu, a[2], &[0; SIGACTION_SIZE])?;
+                }
+                Ok(0)
+            }
+            SYS_RT_SIGPROCMASK => {
+                if a[2] != 0 {
+                    write_u64s(cpu, a[2], &[0])?;
+                }
+                Ok(0)
+            }
+            SYS_TIMES => {
+                // The program has the hart to itself, so all of its time is user time.
+                let ticks = self.start.elapsed().as_millis() as u64 * CLK_TCK / 1000;
+                if a[0] != 0 {
+                    write_u64s(cpu, a[0], &[ticks, 0, 0, 0])?;
+                }
+                Ok(ticks)
+            }
+            SYS_UNAME => {
+                let mut buf = Vec::new();
+                for field in ["Linux", "rrve", "6.1.0", "#1", "riscv64", ""] {
+                    let mut field = field.as_bytes().to_vec();
+                    field.resize(65, 0);
+                    buf.extend(field);
+                }
+                write_bytes(cpu, a[0], &buf)?;
+                Ok(0)
+            }
+            SYS_GETTIMEOFDAY => {
+                let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                if a[0] != 0 {
+                    write_u64s(cpu, a[0], &[time.as_secs(), time.subsec_micros() as u64])?;
+                }
+                Ok(0)
+            }
+            SYS_GETPPID => Ok(std::os::unix::process::parent_id() as u64),
+            SYS_GETUID | SYS_GETEUID => Ok(host_ids().0),
+            SYS_GETGID | SYS_GETEGID => Ok(host_ids().1),
+            SYS_BRK => {
+                // A request outside of the heap only returns the current break.
+                if (self.brk_start..MMAP_BASE).contains(&a[0]) {
+                    let old = self.brk.next_multiple_of(PAGE_SIZE);
+                    let new = a[0].next_multiple_of(PAGE_SIZE);
+                    if new > old {
+                        memory(cpu).map(old..new);
+                    } else if new < old {
+                        memory(cpu).unmap(new..old);
+                    }
+                    self.brk = a[0];
+                }
+                Ok(self.brk)
+            }
+            SYS_MUNMAP => {
+                if !a[0].is_multiple_of(PAGE_SIZE) {
+                    return Err(EINVAL);
+                }
+                memory(cpu).unmap(a[0]..a[0].saturating_add(a[1]));
+                Ok(0)
+            }
+            SYS_MMAP => {
+                let (addr, flags, offset) = (a[0], a[3], a[5]);
+                if a[1] == 0 || !offset.is_multiple_of(PAGE_SIZE) {
+                    return Err(EINVAL);
+                }
+                let len = a[1].checked_next_multiple_of(PAGE_SIZE).ok_or(ENOMEM)?;
+                if len > MMAP_END - MMAP_BASE {
+                    return Err(ENOMEM);
+                }
+                let file = flags & MAP_ANONYMOUS == 0;
+                if file && !matches!(self.handle(a[4])?, Handle::File(_)) {
+                    return Err(EBADF);
+                }
+                let range = if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+                    if !addr.is_multiple_of(PAGE_SIZE) {
+                        return Err(EINVAL);
+                    }
+                    let range = addr..addr.checked_add(len).ok_or(ENOMEM)?;
+                    if flags & MAP_FIXED_NOREPLACE != 0 && memory(cpu).overlaps(range.clone()) {
+                        return Err(EEXIST);
+                    }
+                    range
+                } else {
+                    let range = self.mmap_next..self.mmap_next + len;
+                    if range.end > MMAP_END {
+                        return Err(ENOMEM);
+                    }
+                    self.mmap_next = range.end;
+                    range
+                };
+                memory(cpu).map(range.clone());
+                // A file mapping is a private copy of the file, read in pieces up to its end.
+                if file {
+                    let Handle::File(file) = self.handle(a[4])? else { return Err(EBADF) };
+                    let mut buf = vec![0; len.min(MAX_COPY) as usize];
+                    let mut done = 0;
+                    while done < len {
+                        let chunk = (len - done).min(MAX_COPY) as usize;
+                        let n = file.read_at(&mut buf[..chunk], offset.saturating_add(done)).map_err(errno)?;
+                        if n == 0 {
+                            break;
+                        }
+                        write_bytes(cpu, range.start + done, &buf[..n])?;
+                        done += n as u64;
+                    }
+                }
+                Ok(range.start)
+            }
+            // All memory is readable, writable and executable.
+            SYS_MPROTECT | SYS_MADVISE => Ok(0),
+            SYS_PRLIMIT64 => {
+                if a[0] != 0 && a[0] != process::id() as u64 {
+                    return Err(EPERM);
+                }
+                if a[3] != 0 {
+                    let limit = if a[1] == RLIMIT_STACK { STACK_SIZE } else { RLIM_INFINITY };
+                    write_u64s(cpu, a[3], &[limit, RLIM_INFINITY])?;
+                }
+                Ok(0)
+            }
+            SYS_GETRANDOM => {
+                let mut buf = Vec::new();
+                while (buf.len() as u64) < a[1].min(MAX_COPY) {
+                    buf.extend(self.next_random().to_le_bytes());
+                }
+                buf.truncate(a[1] as usize);
+                write_bytes(cpu, a[0], &buf)?;
+                Ok(buf.len() as u64)
+            }
+            SYS_TIME if self.abi == Abi::Pk => {
+                let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                if a[0] != 0 {
+                    write_u64s(cpu, a[0], &[secs])?;
+                }
+                Ok(secs)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn handle(&mut self, fd: u64) -> Result<&mut Handle, i64> {
+        self.files.get_mut(&fd).ok_or(EBADF)
+    }
+
+    /// Add `handle` at the lowest free descriptor from `min`, and return the descriptor.
+    fn insert(&mut self, handle: Handle, min: u64) -> u64 {
+        let fd = (min..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        self.files.insert(fd, handle);
+        fd
+    }
+
+    /// Read the path at `addr`, which is relative to `dirfd`. Only the working directory is
+    /// supported as `dirfd`.
+    fn path(&self, cpu: &mut CPU, dirfd: u64, addr: u64) -> Result<String, i64> {
+        let path = read_str(cpu, addr)?;
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(EBADF);
+        }
+        Ok(path)
+    }
+
+    /// Return the next number of a xorshift generator.
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+}
+
+/// Turn a pk syscall that takes a path into the Linux one that takes a directory and a path.
+/// The flags of open are passed through, as pk does, since newlib uses the values of Linux.
+fn pk(number: u64, a: [u64; 6]) -> (u64, [u64; 6]) {
+    match number {
+        SYS_OPEN => (SYS_OPENAT, [AT_FDCWD, a[0], a[1], a[2], 0, 0]),
+        SYS_LINK => (SYS_LINKAT, [AT_FDCWD, a[0], AT_FDCWD, a[1], 0, 0]),
+        SYS_UNLINK => (SYS_UNLINKAT, [AT_FDCWD, a[0], 0, 0, 0, 0]),
+        SYS_MKDIR => (SYS_MKDIRAT, [AT_FDCWD, a[0], a[1], 0, 0, 0]),
+        SYS_ACCESS => (SYS_FACCESSAT, [AT_FDCWD, a[0], a[1], 0, 0, 0]),
+        SYS_STAT => (SYS_NEWFSTATAT, [AT_FDCWD, a[0], a[1], 0, 0, 0]),
+        SYS_LSTAT => (SYS_NEWFSTATAT, [AT_FDCWD, a[0], a[1], AT_SYMLINK_NOFOLLOW, 0, 0]),
+        _ => (number, a),
+    }
+}
+
+/// Return the user and group ids of the emulator, which the program runs as. Without libc, only
+/// Linux tells them, as the owner of /proc/self. Elsewhere the program runs as root.
+fn host_ids() -> (u64, u64) {
+    #[cfg(target_os = "linux")]
+    if let Ok(meta) = fs::metadata("/proc/self") {
+        return (meta.uid() as u64, meta.gid() as u64);
+    }
+    (0, 0)
+}
+
+fn memory(cpu: &mut CPU) -> &mut SparseMemory {
+    cpu.bus.memory.as_mut().expect("user mode without an address space")
+}
+
+fn read_bytes(cpu: &mut CPU, addr: u64, len: u64) -> Result<Vec<u8>, i64> {
+    let mut buf = vec![0; len as usize];
+    cpu.bus.load_bytes(addr, &mut buf).map_err(|_| EFAULT)?;
+    Ok(buf)
+}
+
+/// Read the NUL-terminated string at `addr`.
+fn read_str(cpu: &mut CPU, addr: u64) -> Result<String, i64> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = read_bytes(cpu, addr + bytes.len() as u64, 1)?[0];
+        if byte == 0 {
+            return Ok(String::from_utf8_lossy(&bytes).into_owned());
+        }
+        bytes.push(byte);
+    }
+}
+
+fn write_bytes(cpu: &mut CPU, addr: u64, data: &[u8]) -> Result<(), i64> {
+    cpu.bus.store_bytes(addr, data).map_err(|_| EFAULT)
+}
+
+fn write_u64s(cpu: &mut CPU, addr: u64, values: &[u64]) -> Result<(), i64> {
+    write_bytes(cpu, addr, &values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>())
+}
+
+/// Return the errno of a failed host call.
+fn errno(e: io::Error) -> i64 {
+    match e.raw_os_error() {
+        Some(errno) => errno as i64,
+        None if e.kind() == io::ErrorKind::InvalidInput => EINVAL,
+        None => EIO,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::{executable, EM_RISCV};
+
+    const ENTRY: u64 = 0x10000;
+    // Scratch memory of the tests, at the bottom of the stack.
+    const BUF: u64 = STACK_TOP - STACK_SIZE;
+
+    /// Start a program that does nothing in user mode, with the arguments `args`.
+    fn start(name: &str, abi: Abi, args: &[&str]) -> CPU {
+        let path = std::env::temp_dir().join(format!("r-riscv-{}-{}", name, process::id()));
+        fs::write(&path, executable(EM_RISCV, ENTRY, &[0x13, 0, 0, 0], 0x100)).unwrap();
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Syscalls::start(&mut cpu, abi, path.to_str().unwrap(), &args).unwrap();
+        fs::remove_file(&path).unwrap();
+        cpu
+    }
+
+    /// Make the syscall `number` with `args` and return a0.
+    fn ecall(cpu: &mut CPU, number: u64, args: &[u64]) -> i64 {
+        cpu.regs[17] = number;
+        cpu.regs[10..10 + args.len()].copy_from_slice(args);
+        let mut syscalls = cpu.syscalls.take().unwrap();
+        syscalls.call(cpu);
+        cpu.syscalls = Some(syscalls);
+        cpu.regs[10] as i64
+    }
+
+    fn read_u64(cpu: &mut CPU, addr: u64) -> u64 {
+        u64::from_le_bytes(read_bytes(cpu, addr, 8).unwrap().try_into().unwrap())
+    }
+
+    #[test]
+    fn test_stack() {
+        let mut cpu = start("syscall-stack", Abi::Linux, &["prog", "arg"]);
+        let sp = cpu.regs[2];
+        assert_eq!((cpu.pc, cpu.mode), (ENTRY, User));
+        assert_eq!(sp % 16, 0);
+        assert_eq!(read_u64(&mut cpu, sp), 2);
+        let argv = [read_u64(&mut cpu, sp + 8), read_u64(&mut cpu, sp + 16)];
+        assert_eq!(read_str(&mut cpu, argv[0]).unwrap(), "prog");
+        assert_eq!(read_str(&mut cpu, argv[1]).unwrap(), "arg");
+        assert_eq!(read_u64(&mut cpu, sp + 24), 0);
+
+        // The auxiliary vector follows the environment and its terminating null.
+        let mut addr = sp + 32;
+        while read_u64(&mut cpu, addr) != 0 {
+            addr += 8;
+        }
+        let mut auxv = HashMap::new();
+        loop {
+            addr += 8;
+            let (key, value) = (read_u64(&mut cpu, addr), read_u64(&mut cpu, addr + 8));
+            if key == AT_NULL {
+                break;
+            }
+            auxv.insert(key, value);
+            addr += 8;
+        }
+        assert_eq!(auxv[&AT_PAGESZ], PAGE_SIZE);
+        assert_eq!(auxv[&AT_ENTRY], ENTRY);
+        assert_eq!(auxv[&AT_EXECFN], argv[0]);
+        assert!((sp..STACK_TOP).contains(&auxv[&AT_RANDOM]));
+    }
+
+    #[test]
+    fn test_files() {
+        let mut cpu = start("syscall-files", Abi::Linux, &["prog"]);
+        let path = std::env::temp_dir().join(format!("r-riscv-syscall-file-{}", process::id()));
+        write_bytes(&mut cpu, BUF, &[path.to_str().unwrap().as_bytes(), &[0]].concat()).unwrap();
+        write_bytes(&mut cpu, BUF + 0x100, b"hello").unwrap();
+
+        let fd = ecall(&mut cpu, SYS_OPENAT, &[AT_FDCWD, BUF, O_RDWR | O_CREAT | O_TRUNC, 0o644]);
+        assert_eq!(fd, 3);
+        assert_eq!(ecall(&mut cpu, SYS_WRITE, &[3, BUF + 0x100, 5]), 5);
+        assert_eq!(ecall(&mut cpu, SYS_LSEEK, &[3, 1, 0]), 1);
+        assert_eq!(ecall(&mut cpu, SYS_READ, &[3, BUF + 0x200, 16]), 4);
+        assert_eq!(read_bytes(&mut cpu, BUF + 0x200, 4).unwrap(), b"ello");
+        assert_eq!(ecall(&mut cpu, SYS_PREAD64, &[3, BUF + 0x200, 2, 3]), 2);
+        assert_eq!(read_bytes(&mut cpu, BUF + 0x200, 2).unwrap(), b"lo");
+        assert_eq!(ecall(&mut cpu, SYS_CLOSE, &[3]), 0);
+        assert_eq!(ecall(&mut cpu, SYS_READ, &[3, BUF + 0x200, 16]), -EBADF);
+        assert_eq!(fs::read(&path).unwrap(), b"hello");
+        fs::remove_file(&path).unwrap();
+
+        // The buffer of the program has to be mapped.
+        assert_eq!(ecall(&mut cpu, SYS_WRITE, &[1, 0, 5]), -EFAULT);
+        assert_eq!(ecall(&mut cpu, 0xffff, &[]), -ENOSYS);
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut cpu = start("syscall-memory", Abi::Linux, &["prog"]);
+        // The heap starts on the page after the program.
+        let brk = ecall(&mut cpu, SYS_BRK, &[0]) as u64;
+        assert_eq!(brk, ENTRY + PAGE_SIZE);
+        assert!(cpu.bus.store(brk, 64, 1).is_err());
+        assert_eq!(ecall(&mut cpu, SYS_BRK, &[brk + 0x2000]) as u64, brk + 0x2000);
+        cpu.bus.store(brk + 0x1ff8, 64, 1).unwrap();
+        assert_eq!(ecall(&mut cpu, SYS_BRK, &[brk]) as u64, brk);
+        assert!(cpu.bus.store(brk, 64, 1).is_err());
+
+        let (prot, anonymous) = (3, MAP_ANONYMOUS | 2);
+        let addr = ecall(&mut cpu, SYS_MMAP, &[0, 0x1800, prot, anonymous, -1i64 as u64, 0]) as u64;
+        assert_eq!(addr, MMAP_BASE);
+        cpu.bus.store(addr + 0x1ff8, 64, 1).unwrap();
+        let next = ecall(&mut cpu, SYS_MMAP, &[0, 0x1000, prot, anonymous, -1i64 as u64, 0]) as u64;
+        assert_eq!(next, MMAP_BASE + 0x2000);
+
+        // A fixed mapping replaces the memory there unless it says not to.
+        let fixed = anonymous | MAP_FIXED_NOREPLACE;
+        assert_eq!(ecall(&mut cpu, SYS_MMAP, &[addr, 0x1000, prot, fixed, -1i64 as u64, 0]), -EEXIST);
+        assert_eq!(ecall(&mut cpu, SYS_MMAP, &[addr + 0x1000, 0x1000, prot, anonymous | MAP_FIXED, -1i64 as u64, 0]) as u64, addr + 0x1000);
+        assert_eq!(cpu.bus.load(addr + 0x1ff8, 64).unwrap(), 0);
+        assert_eq!(ecall(&mut cpu, SYS_MMAP, &[addr + 1, 0x1000, prot, fixed, -1i64 as u64, 0]), -EINVAL);
+        assert_eq!(ecall(&mut cpu, SYS_MMAP, &[u64::MAX - 0xfff, 0x2000, prot, fixed, -1i64 as u64, 0]), -ENOMEM);
+        assert_eq!(ecall(&mut cpu, SYS_MMAP, &[0, 1 << 62, prot, anonymous, -1i64 as u64, 0]), -ENOMEM);
+
+        assert_eq!(ecall(&mut cpu, SYS_MUNMAP, &[addr, 0x2000]), 0);
+        assert!(cpu.bus.load(addr, 64).is_err());
+        assert_eq!(ecall(&mut cpu, SYS_MMAP, &[addr, 0x1000, prot, fixed, -1i64 as u64, 0]) as u64, addr);
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut cpu = start("syscall-exit", Abi::Linux, &["prog"]);
+        let pc = cpu.pc;
+        ecall(&mut cpu, SYS_EXIT_GROUP, &[3]);
+        assert_eq!(cpu.pc, pc + 4);
+        assert_eq!(cpu.shutdown(), Some(Shutdown::PowerOff(3)));
+        assert_eq!(cpu.shutdown(), None);
+    }
+
+    #[test]
+    fn test_pk() {
+        let mut cpu = start("syscall-pk", Abi::Pk, &["prog"]);
+        let path = std::env::temp_dir().join(format!("r-riscv-syscall-pk-{}", process::id()));
+        write_bytes(&mut cpu, BUF, &[path.to_str().unwrap().as_bytes(), &[0]].concat()).unwrap();
+        write_bytes(&mut cpu, BUF + 0x100, b"newlib").unwrap();
+
+        // open takes the path without a directory, then write and close are the ones of Linux.
+        let fd = ecall(&mut cpu, SYS_OPEN, &[BUF, O_WRONLY | O_CREAT | O_TRUNC, 0o644]);
+        assert_eq!(fd, 3);
+        assert_eq!(ecall(&mut cpu, SYS_WRITE, &[3, BUF + 0x100, 6]), 6);
+        assert_eq!(ecall(&mut cpu, SYS_CLOSE, &[3]), 0);
+        assert_eq!(fs::read(&path).unwrap(), b"newlib");
+        assert_eq!(ecall(&mut cpu, SYS_STAT, &[BUF, BUF + 0x200]), 0);
+        // st_size is at offset 48 of the struct stat of Linux on RISC-V.
+        assert_eq!(read_u64(&mut cpu, BUF + 0x200 + 48), 6);
+        assert_eq!(ecall(&mut cpu, SYS_UNLINK, &[BUF]), 0);
+        assert!(!path.exists());
+        assert!(ecall(&mut cpu, SYS_TIME, &[0]) > 0);
+
+        ecall(&mut cpu, SYS_EXIT, &[1]);
+        assert_eq!(cpu.shutdown(), Some(Shutdown::PowerOff(1)));
+    }
+}
@@ -0,0 +1,77 @@
+//! The syscon module emulates the SiFive test device of QEMU's virt machine. Guests power off or
+//! reboot the machine through it, either directly or via the syscon-poweroff/syscon-reboot drivers.
+
+use crate::bus::{exit_status, Shutdown};
+use crate::exception::Exception;
+use crate::exception::Exception::{LoadAccessFault, StoreAMOAccessFault};
+use crate::param::*;
+
+pub struct Syscon {
+    /// The last shutdown request written by the guest, if it has not been handled yet.
+    request: Option<Shutdown>,
+}
+
+impl Syscon {
+    pub fn new() -> Self {
+        Self { request: None }
+    }
+
+    /// Return the pending shutdown request and clear it.
+    pub fn take_request(&mut self) -> Option<Shutdown> {
+        self.request.take()
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(LoadAccessFault(addr));
+        }
+        Ok(0)
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        if addr != SYSCON_BASE {
+            return Ok(());
+        }
+        // The low 16 bits select the command, the high 16 bits carry the exit code of a failure.
+        // Like QEMU, a failure exits with the code, but with 1 if it is 0, so that it is not
+        // taken for a pass.
+        match value & 0xffff {
+            SYSCON_FAIL => self.request = Some(Shutdown::PowerOff(exit_status((value >> 16) & 0xffff).max(1))),
+            SYSCON_PASS => self.request = Some(Shutdown::PowerOff(0)),
+            SYSCON_RESET => self.request = Some(Shutdown::Reboot),
+            // Unknown commands are ignored like on real hardware.
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        let mut syscon = Syscon::new();
+        syscon.store(SYSCON_BASE, 32, SYSCON_PASS).unwrap();
+        assert_eq!(syscon.take_request(), Some(Shutdown::PowerOff(0)));
+        assert_eq!(syscon.take_request(), None);
+
+        syscon.store(SYSCON_BASE, 32, SYSCON_FAIL).unwrap();
+        assert_eq!(syscon.take_request(), Some(Shutdown::PowerOff(1)));
+        syscon.store(SYSCON_BASE, 32, (3 << 16) | SYSCON_FAIL).unwrap();
+        assert_eq!(syscon.take_request(), Some(Shutdown::PowerOff(3)));
+        syscon.store(SYSCON_BASE, 32, (0x100 << 16) | SYSCON_FAIL).unwrap();
+        assert_eq!(syscon.take_request(), Some(Shutdown::PowerOff(255)));
+
+        syscon.store(SYSCON_BASE, 32, SYSCON_RESET).unwrap();
+        assert_eq!(syscon.take_request(), Some(Shutdown::Reboot));
+
+        syscon.store(SYSCON_BASE, 32, 0x1234).unwrap();
+        assert_eq!(syscon.take_request(), None);
+        assert!(syscon.store(SYSCON_BASE, 8, SYSCON_PASS).is_err());
+    }
+}
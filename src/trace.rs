@@ -0,0 +1,225 @@
+//! The trace module writes a commit log of the retired instructions in the format of Spike's
+//! `--log-commits`, so that a run can be diffed against Spike. Each line has the privilege mode,
+//! the pc and the bits of the instruction, followed by the registers and CSRs it wrote and the
+//! memory it accessed:
+//!
+//! ```text
+//! core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000
+//! core   0: 3 0x0000000080000004 (0x0062a023) mem 0x0000000080001000 0x00000005
+//! ```
+//!
+//! The same lines are what a lockstep run compares with a reference log.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use crate::bus::Shutdown;
+use crate::cpu::{Mode, CPU};
+use crate::csr::*;
+use crate::lockstep::Lockstep;
+
+/// The instructions to write to the trace. Each filter that is set has to match.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    /// The addresses of the instructions.
+    pub pc: Option<Range<u64>>,
+    /// The privilege modes the instructions run in.
+    pub modes: Option<Vec<Mode>>,
+    /// The numbers of the instructions, counting the retired instructions from 0.
+    pub window: Option<Range<u64>>,
+}
+
+impl TraceFilter {
+    fn matches(&self, pc: u64, mode: Mode, instret: u64) -> bool {
+        self.pc.as_ref().is_none_or(|range| range.contains(&pc))
+            && self.modes.as_ref().is_none_or(|modes| modes.contains(&mode))
+            && self.window.as_ref().is_none_or(|range| range.contains(&instret))
+    }
+}
+
+/// A memory access of the instruction being executed.
+struct Access {
+    addr: u64,
+    /// The size of the access in bits.
+    size: u64,
+    /// The value written, for a store.
+    value: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct Tracer {
+    /// The file the commit log is written to, if any.
+    out: Option<BufWriter<File>>,
+    filter: TraceFilter,
+    /// The reference the commit log is checked against, if any.
+    lockstep: Option<Lockstep>,
+    accesses: Vec<Access>,
+}
+
+impl Tracer {
+    /// Write the instructions that match `filter` to the file at `path`.
+    pub fn write_to(&mut self, path: &str, filter: TraceFilter) -> io::Result<()> {
+        self.out = Some(BufWriter::new(File::create(path)?));
+        self.filter = filter;
+        Ok(())
+    }
+
+    /// Check every instruction against the reference commit log at `path`.
+    pub fn check_against(&mut self, path: &str) -> io::Result<()> {
+        self.lockstep = Some(Lockstep::open(path)?);
+        Ok(())
+    }
+
+    /// Forget the accesses made so far, before the next instruction is executed.
+    pub fn begin(&mut self) {
+        self.accesses.clear();
+    }
+
+    /// Record a load, or a store of `value`, made by the instruction being executed.
+    pub fn access(&mut self, addr: u64, size: u64, value: Option<u64>) {
+        self.accesses.push(Access { addr, size, value });
+    }
+
+    /// Log the instruction `inst` at `pc` that has just retired in `mode`. The registers it wrote
+    /// are read back from `cpu`.
+    pub fn commit(&mut self, cpu: &CPU, pc: u64, mode: Mode, inst: u64) -> io::Result<()> {
+        let line = self.line(cpu, pc, mode, inst);
+        if let Some(out) = &mut self.out {
+            if self.filter.matches(pc, mode, cpu.instret) {
+                writeln!(out, "{}", line)?;
+            }
+        }
+        match &mut self.lockstep {
+            Some(lockstep) => lockstep.check(&line),
+            None => Ok(()),
+        }
+    }
+
+    fn line(&self, cpu: &CPU, pc: u64, mode: Mode, inst: u64) -> String {
+        let mut line = format!("core {:3}: {} {:#018x} ({:#010x})", cpu.csr.load(MHARTID), mode, pc, inst);
+        let rd = ((inst >> 7) & 0x1f) as usize;
+        if rd != 0 && writes_rd(inst) {
+            line += &format!(" x{:<2} {:#018x}", rd, cpu.regs[rd]);
+        }
+        for csr in written_csrs(inst) {
+            let name = CSR_NAMES.iter().find(|(_, addr)| *addr == csr).map_or("unknown", |(name, _)| name);
+            line += &format!(" c{}_{} {:#018x}", csr, name, cpu.csr.load(csr));
+        }
+        for access in self.accesses.iter().filter(|access| access.value.is_none()) {
+            line += &format!(" mem {:#018x}", access.addr);
+        }
+        for access in &self.accesses {
+            if let Some(value) = access.value {
+                let digits = (access.size / 4) as usize;
+                line += &format!(" mem {:#018x} {:#0width$x}", access.addr, value, width = digits + 2);
+            }
+        }
+        line
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.out {
+            Some(out) => out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Return the request to stop the machine, once a lockstep run is over.
+    pub fn take_request(&mut self) -> Option<Shutdown> {
+        self.lockstep.as_mut().and_then(|lockstep| lockstep.take_request())
+    }
+}
+
+/// Return true if `inst` writes its rd field, as Spike logs it.
+fn writes_rd(inst: u64) -> bool {
+    match inst & 0x7f {
+        0x03 | 0x13 | 0x17 | 0x1b | 0x2f | 0x33 | 0x37 | 0x3b | 0x67 | 0x6f => true,
+        0x73 => (inst >> 12) & 0x7 != 0,
+        _ => false,
+    }
+}
+
+/// Return the CSRs `inst` writes, sorted by address as Spike logs them. A supervisor CSR is a
+/// view of a machine CSR, which is written too.
+fn written_csrs(inst: u64) -> Vec<usize> {
+    if inst & 0x7f != 0x73 {
+        return Vec::new();
+    }
+    let funct3 = (inst >> 12) & 0x7;
+    let rs1 = (inst >> 15) & 0x1f;
+    let csr = match funct3 {
+        0x0 => match inst >> 20 {
+            // sret, mret
+            0x102 => SSTATUS,
+            0x302 => MSTATUS,
+            _ => return Vec::new(),
+        },
+        // csrrw and csrrwi always write, the others only with a non-zero rs1 or immediate.
+        0x1 | 0x5 => (inst >> 20) as usize,
+        0x2 | 0x3 | 0x6 | 0x7 if rs1 != 0 => (inst >> 20) as usize,
+        _ => return Vec::new(),
+    };
+    match csr {
+        SSTATUS => vec![SSTATUS, MSTATUS],
+        SIE => vec![SIE, MIE],
+        SIP => vec![SIP, MIP],
+        _ => vec![csr],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{Supervisor, User};
+    use crate::param::DRAM_BASE;
+
+    // The lines of Spike's commit log, which a lockstep run compares character by character.
+    #[test]
+    fn test_line() {
+        let mut cpu = CPU::new(Vec::new(), Vec::new());
+        let mut tracer = Tracer::default();
+
+        // auipc t0, 0
+        cpu.regs[5] = DRAM_BASE;
+        assert_eq!(tracer.line(&cpu, DRAM_BASE, 3, 0x00000297),
+            "core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000");
+
+        // sw t1, 0(t0)
+        tracer.access(DRAM_BASE + 0x1000, 32, Some(5));
+        assert_eq!(tracer.line(&cpu, DRAM_BASE + 4, 3, 0x0062a023),
+            "core   0: 3 0x0000000080000004 (0x0062a023) mem 0x0000000080001000 0x00000005");
+
+        // ld a0, 0(a1)
+        tracer.begin();
+        tracer.access(DRAM_BASE + 0x1000, 64, None);
+        cpu.regs[10] = 5;
+        assert_eq!(tracer.line(&cpu, DRAM_BASE + 8, 1, 0x0005b503),
+            "core   0: 1 0x0000000080000008 (0x0005b503) x10 0x0000000000000005 mem 0x0000000080001000");
+
+        // csrw sstatus, t0, which writes mstatus too.
+        tracer.begin();
+        cpu.csr.store(SSTATUS, 0x2);
+        assert_eq!(tracer.line(&cpu, DRAM_BASE + 12, 1, 0x10029073),
+            "core   0: 1 0x000000008000000c (0x10029073) c256_sstatus 0x0000000000000002 \
+             c768_mstatus 0x0000000000000002");
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = TraceFilter::default();
+        assert!(filter.matches(0, User, 0));
+
+        let filter = TraceFilter {
+            pc: Some(DRAM_BASE..DRAM_BASE + 0x100),
+            modes: Some(vec![User, Supervisor]),
+            window: Some(10..20),
+        };
+        assert!(filter.matches(DRAM_BASE, User, 10));
+        assert!(filter.matches(DRAM_BASE + 0xfc, Supervisor, 19));
+        assert!(!filter.matches(DRAM_BASE + 0x100, User, 10));
+        assert!(!filter.matches(DRAM_BASE - 4, User, 10));
+        assert!(!filter.matches(DRAM_BASE, 3, 10));
+        assert!(!filter.matches(DRAM_BASE, User, 9));
+        assert!(!filter.matches(DRAM_BASE, User, 20));
+    }
+}
@@ -1,16 +1,42 @@
 #[warn(unused_variables)]
 use std::sync::{Arc, Condvar, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::{io, thread};
 use std::io::{Read, Write};
 use crate::exception::Exception;
 use crate::param::*;
+use crate::snapshot::{Reader, Writer};
+
+// The console escape: Ctrl-A followed by 'c' switches to the monitor, and Ctrl-A twice sends
+// one Ctrl-A to the guest.
+const ESCAPE_KEY: u8 = 0x01;
+const ESCAPE_MONITOR: u8 = b'c';
 
 pub struct UART {
     /// Pair of an array for UART buffer and a conditional variable.
     uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
     /// Bit if an interrupt happens.
     interrupt: Arc<AtomicBool>,
+    /// Whether the thread reading the console has been started.
+    started: bool,
+    /// Whether the console escape is recognized.
+    escape: bool,
+    /// Set when the escape to the monitor was typed.
+    escaped: Arc<AtomicBool>,
+    /// Whether the console input goes to the monitor instead of the guest.
+    monitor: Arc<AtomicBool>,
+    /// Console input for the monitor, and the end the reading thread takes.
+    monitor_rx: Receiver<u8>,
+    monitor_tx: Option<Sender<u8>>,
+    /// Console input held back for `take_input`, when the input is deferred.
+    input_rx: Receiver<u8>,
+    input_tx: Option<Sender<u8>>,
+    /// Whether the console input reaches the guest only through `inject`, so that it arrives
+    /// between instructions chosen by the caller instead of whenever it is typed.
+    deferred: bool,
+    /// Whether the output of the guest is dropped.
+    muted: bool,
 }
 
 impl UART {
@@ -21,31 +47,157 @@ impl UART {
 
         let uart = Arc::new(((Mutex::new(array)), Condvar::new()));
         let interrupt = Arc::new(AtomicBool::new(false));
+        let (monitor_tx, monitor_rx) = mpsc::channel();
+        let (input_tx, input_rx) = mpsc::channel();
+
+        Self {
+            uart,
+            interrupt,
+            started: false,
+            escape: false,
+            escaped: Arc::new(AtomicBool::new(false)),
+            monitor: Arc::new(AtomicBool::new(false)),
+            monitor_rx,
+            monitor_tx: Some(monitor_tx),
+            input_rx,
+            input_tx: Some(input_tx),
+            deferred: false,
+            muted: false,
+        }
+    }
+
+    /// Hold the console input back until it is taken with `take_input` and injected.
+    pub fn defer_input(&mut self) {
+        self.deferred = true;
+    }
+
+    /// Return the next byte of deferred console input, if there is one and the guest has read
+    /// the previous one.
+    pub fn take_input(&mut self) -> Option<u8> {
+        let array = self.uart.0.lock().unwrap();
+        if array[UART_LSR as usize] & MASK_UART_LSR_RX != 0 {
+            return None;
+        }
+        self.input_rx.try_recv().ok()
+    }
+
+    /// Make `byte` the received byte, as if it had just been typed.
+    pub fn inject(&self, byte: u8) {
+        let mut array = self.uart.0.lock().unwrap();
+        array[UART_RHR as usize] = byte;
+        array[UART_LSR as usize] |= MASK_UART_LSR_RX;
+        self.interrupt.store(true, Ordering::Release);
+    }
+
+    /// Drop the output of the guest, or print it again.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Recognize the console escape to the monitor.
+    pub fn enable_escape(&mut self) {
+        self.escape = true;
+    }
+
+    /// Return true if the escape to the monitor was typed since the last call.
+    pub fn take_escape(&self) -> bool {
+        self.escaped.swap(false, Ordering::Acquire)
+    }
+
+    /// Read a line of console input for the monitor, or return None at the end of the input.
+    /// The input stays with the monitor until it is released.
+    pub fn read_line(&mut self) -> Option<String> {
+        if !self.started {
+            // Nobody else reads the console.
+            let mut line = String::new();
+            return match io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => None,
+                Ok(_) => Some(line),
+            };
+        }
+        self.monitor.store(true, Ordering::Release);
+        let mut line = Vec::new();
+        loop {
+            match self.monitor_rx.recv() {
+                Ok(b'\n') => return Some(String::from_utf8_lossy(&line).into_owned()),
+                Ok(byte) => line.push(byte),
+                Err(_) if line.is_empty() => return None,
+                Err(_) => return Some(String::from_utf8_lossy(&line).into_owned()),
+            }
+        }
+    }
+
+    /// Give the console input back to the guest.
+    pub fn release_console(&self) {
+        self.monitor.store(false, Ordering::Release);
+    }
+
+    /// Start reading the console. This waits for the first access of the guest, so that the
+    /// console stays with the host while the uart is not in use, e.g. in user mode.
+    fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
 
         // receive part
-        let read_uart = Arc::clone(&uart);
-        let read_interrupt = Arc::clone(&interrupt);
+        let read_uart = Arc::clone(&self.uart);
+        let read_interrupt = Arc::clone(&self.interrupt);
+        let escape = self.escape;
+        let escaped = Arc::clone(&self.escaped);
+        let monitor = Arc::clone(&self.monitor);
+        let monitor_tx = self.monitor_tx.take().unwrap();
+        let input_tx = self.input_tx.take().unwrap();
+        let deferred = self.deferred;
+        let deliver = move |byte: u8| {
+            if deferred {
+                let _ = input_tx.send(byte);
+                return;
+            }
+            let (uart, cvar) = &*read_uart;
+            let mut array = uart.lock().unwrap();
+            // if data have been received but not yet be transferred.
+            // this thread wait for it to be transferred.
+            while (array[UART_LSR as usize] & MASK_UART_LSR_RX) == 1 {
+                array = cvar.wait(array).unwrap();
+            }
+            // data have been transferred, so receive next one.
+            array[UART_RHR as usize] = byte;
+            read_interrupt.store(true, Ordering::Release);
+            array[UART_LSR as usize] |= MASK_UART_LSR_RX;
+        };
         let mut byte = [0];
+        let mut after_escape = false;
         thread::spawn(move || loop {
             match io::stdin().read(&mut byte) {
-                Ok(_) => {
-                    let (uart, cvar) = &*read_uart;
-                    let mut array = uart.lock().unwrap();
-                    // if data have been received but not yet be transferred.
-                    // this thread wait for it to be transferred.
-                    while (array[UART_LSR as usize] & MASK_UART_LSR_RX) == 1 {
-                        array = cvar.wait(array).unwrap();
+                // The console was closed.
+                Ok(0) => return,
+                Ok(_) if after_escape => {
+                    after_escape = false;
+                    match byte[0] {
+                        ESCAPE_MONITOR => {
+                            monitor.store(true, Ordering::Release);
+                            escaped.store(true, Ordering::Release);
+                        }
+                        ESCAPE_KEY => deliver(ESCAPE_KEY),
+                        other => {
+                            deliver(ESCAPE_KEY);
+                            deliver(other);
+                        }
                     }
-                    // data have been transferred, so receive next one.
-                    array[UART_RHR as usize] = byte[0];
-                    read_interrupt.store(true, Ordering::Release);
-                    array[UART_LSR as usize] |= MASK_UART_LSR_RX;
                 }
+                Ok(_) if monitor.load(Ordering::Acquire) => {
+                    let _ = monitor_tx.send(byte[0]);
+                }
+                Ok(_) if escape && byte[0] == ESCAPE_KEY => after_escape = true,
+                Ok(_) => deliver(byte[0]),
                 Err(e) => println!("{}", e),
             }
         });
-
-        Self { uart, interrupt }
     }
 
     /// Return true if an interrupt is pending. Clear the interrupt flag by swapping a value.
@@ -54,10 +206,40 @@ impl UART {
     }
 
 
+    /// Print the registers and where the console input goes.
+    pub fn dump(&self) {
+        println!("{:-^80}", "uart");
+        let array = self.uart.0.lock().unwrap();
+        println!(
+            "rhr = {:#04x}  ier = {:#04x}  isr = {:#04x}  lcr = {:#04x}  mcr = {:#04x}  lsr = {:#04x}  msr = {:#04x}  scr = {:#04x}",
+            array[0], array[1], array[2], array[3], array[4], array[5], array[6], array[7],
+        );
+        let input = match (self.started, self.monitor.load(Ordering::Acquire)) {
+            (false, _) => "not read yet",
+            (true, false) => "guest",
+            (true, true) => "monitor",
+        };
+        println!("console input: {}\n", input);
+    }
+
+    /// Save the registers and the pending interrupt. The console itself is not part of the
+    /// machine.
+    pub fn save(&self, w: &mut Writer) {
+        w.bytes(&*self.uart.0.lock().unwrap());
+        w.bool(self.interrupt.load(Ordering::Acquire));
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        self.uart.0.lock().unwrap().copy_from_slice(r.bytes(UART_SIZE as usize)?);
+        self.interrupt.store(r.bool()?, Ordering::Release);
+        Ok(())
+    }
+
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         if size != 8 {
             return Err(Exception::LoadAccessFault(addr));
         }
+        self.start();
         let (uart, cvar) = &*self.uart;
         let mut array = uart.lock().unwrap();
         let index = addr - UART_BASE;
@@ -76,13 +258,16 @@ impl UART {
         if size != 8 {
             return Err(Exception::StoreAMOAccessFault(addr));
         }
+        self.start();
         let (uart, _cvar) = &*self.uart;
         let mut array = uart.lock().unwrap();
         let index = addr - UART_BASE;
         match index {
             UART_THR => {
-                print!("{}", value as u8 as char);
-                io::stdout().flush().unwrap();
+                if !self.muted {
+                    print!("{}", value as u8 as char);
+                    io::stdout().flush().unwrap();
+                }
                 return Ok(());
             }
             _ => {
@@ -5,6 +5,12 @@ use crate::cpu::*;
 use crate::exception::*;
 use crate::param::*;
 use crate::bus::*;
+use crate::dram::Dram;
+use crate::snapshot::{invalid, Reader, Writer};
+use std::collections::BTreeMap;
+use std::io;
+use std::mem::size_of;
+use std::ops::Range;
 use Exception::*;
 
 
@@ -18,6 +24,8 @@ pub struct VirtioBlock {
     queue_notify: u32,
     status: u32,
     disk: Vec<u8>,
+    /// The sectors the guest has written, with their contents in the disk image.
+    original: BTreeMap<u64, Vec<u8>>,
 }
 
 const MAX_BLOCK_QUEUE: u32 = 1;
@@ -37,9 +45,81 @@ impl VirtioBlock {
             queue_notify: MAX_BLOCK_QUEUE,
             status: 0,
             disk,
+            original: BTreeMap::new(),
         }
     }
 
+    /// Reset the device registers. The disk contents survive a reset.
+    pub fn reset(&mut self) {
+        let disk = std::mem::take(&mut self.disk);
+        let original = std::mem::take(&mut self.original);
+        *self = Self { original, ..Self::new(disk) };
+    }
+
+    /// Save the registers, and the sectors the guest wrote as an overlay of the disk image.
+    pub fn save(&self, w: &mut Writer) {
+        for value in [self.driver_features, self.page_size, self.queue_sel, self.queue_num, self.queue_pfn, self.queue_notify, self.status] {
+            w.u32(value);
+        }
+        w.u64(self.id);
+        w.u64(self.disk.len() as u64);
+        w.u32(self.original.len() as u32);
+        for &sector in self.original.keys() {
+            w.u64(sector);
+            w.bytes(&self.disk[self.sector(sector)]);
+        }
+    }
+
+    /// Restore the registers, and apply the overlay to the disk image, which has to be the one
+    /// the snapshot was taken with. The sectors written since are reverted.
+    pub fn restore(&mut self, r: &mut Reader) -> io::Result<()> {
+        for value in [&mut self.driver_features, &mut self.page_size, &mut self.queue_sel, &mut self.queue_num, &mut self.queue_pfn, &mut self.queue_notify, &mut self.status] {
+            *value = r.u32()?;
+        }
+        self.id = r.u64()?;
+        if r.u64()? != self.disk.len() as u64 {
+            return Err(invalid("the snapshot was taken with a disk image of another size"));
+        }
+        for (sector, data) in std::mem::take(&mut self.original) {
+            let range = self.sector(sector);
+            self.disk[range].copy_from_slice(&data);
+        }
+        for _ in 0..r.u32()? {
+            let sector = r.u64()?;
+            if sector >= (self.disk.len() as u64).div_ceil(SECTOR_SIZE) {
+                return Err(invalid("invalid sector in snapshot"));
+            }
+            let range = self.sector(sector);
+            let data = r.bytes(range.len())?;
+            for (i, &byte) in data.iter().enumerate() {
+                self.write_disk((range.start + i) as u64, byte as u64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Print the registers and the indexes of the queue, which live in `dram`.
+    pub fn dump(&self, dram: &Dram) {
+        println!("{:-^80}", "virtio");
+        println!(
+            "status = {:<#10x}  features = {:<#10x}  page size = {:<#10x}  disk = {} bytes",
+            self.status, self.driver_features, self.page_size, self.disk.len(),
+        );
+        println!(
+            "queue sel = {}  num = {}  pfn = {:#x}  desc = {:#x}  last request = {}",
+            self.queue_sel, self.queue_num, self.queue_pfn, self.desc_addr(), self.id,
+        );
+        // The same layout as the one the requests are taken from.
+        let avail = self.desc_addr() + DESC_NUM as u64 * size_of::<VirtqDesc>() as u64;
+        let used = self.desc_addr() + PAGE_SIZE;
+        if self.queue_pfn == 0 || self.desc_addr() < DRAM_BASE || used + 4 > DRAM_END {
+            println!("queue not set up\n");
+            return;
+        }
+        let (avail, used) = (dram.load(avail + 2, 16).unwrap(), dram.load(used + 2, 16).unwrap());
+        println!("avail idx = {}  used idx = {}\n", avail, used);
+    }
+
     pub fn is_interrupting(&mut self) -> bool {
         if self.queue_notify < MAX_BLOCK_QUEUE {
             self.queue_notify = MAX_BLOCK_QUEUE;
@@ -100,8 +180,19 @@ impl VirtioBlock {
     }
 
     pub fn write_disk(&mut self, addr: u64, value: u64) {
+        let sector = addr / SECTOR_SIZE;
+        if !self.original.contains_key(&sector) {
+            self.original.insert(sector, self.disk[self.sector(sector)].to_vec());
+        }
         self.disk[addr as usize] = value as u8;
     }
+
+    /// Return the bytes of the disk in `sector`. The last sector of an image whose size is not a
+    /// multiple of the sector size is shorter.
+    fn sector(&self, sector: u64) -> Range<usize> {
+        let start = (sector * SECTOR_SIZE) as usize;
+        start..(start + SECTOR_SIZE as usize).min(self.disk.len())
+    }
 }
 
 #[repr(C)]
@@ -140,4 +231,21 @@ pub struct VirtioBlkRequest {
     pub iotype: u32,
     pub reserved: u32,
     pub sector: u64,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use crate::cpu::CPU;
+    use crate::snapshot;
+
+    #[test]
+    fn test_partial_sector() {
+        // The last sector of the image has 188 bytes.
+        let mut cpu = CPU::new(Vec::new(), vec![0; 700]);
+        cpu.bus.virtio_blk.write_disk(699, 1);
+        let state = snapshot::checkpoint(&cpu);
+        cpu.bus.virtio_blk.write_disk(690, 2);
+        snapshot::rewind(&mut cpu, &state);
+        assert_eq!(cpu.bus.virtio_blk.read_disk(699), 1);
+        assert_eq!(cpu.bus.virtio_blk.read_disk(690), 0);
+    }
+}
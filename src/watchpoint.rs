@@ -0,0 +1,112 @@
+//! The watchpoint module watches guest accesses to ranges of memory. The CPU checks every load,
+//! store and instruction fetch that succeeds against the watchpoints. A watchpoint catches reads,
+//! writes or fetches of virtual or physical addresses, optionally only those of a given value.
+//!
+//! The watchpoints of gdb record the first access they catch, so that gdb can stop the hart after
+//! the instruction. The ones of the command line work without a debugger: they log the access,
+//! and optionally open the monitor after the instruction:
+//!
+//! ```text
+//! Watchpoint 0: write of 8 bytes at 0x80001000 (physical 0x80001000) = 0x0 by pc 0x80000024
+//! ```
+
+use std::fmt;
+use std::ops::Range;
+
+/// The kind of an access.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Fetch,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Fetch => write!(f, "fetch"),
+        }
+    }
+}
+
+/// What happens when an access sets off a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Record the access for the debugger.
+    Stop,
+    /// Log the access.
+    Log,
+    /// Log the access and open the monitor.
+    Pause,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    /// The watched addresses, virtual unless `physical` is set.
+    pub range: Range<u64>,
+    pub physical: bool,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// The value an access has to read or write, or the instruction it has to fetch.
+    pub value: Option<u64>,
+    pub action: Action,
+}
+
+/// An access that matched a watchpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub watchpoint: Watchpoint,
+    /// The address of the access.
+    pub addr: u64,
+}
+
+impl Watchpoint {
+    /// Create a watchpoint of the debugger on the `len` virtual addresses from `addr`.
+    pub fn new(addr: u64, len: u64, read: bool, write: bool) -> Self {
+        Self { read, write, ..Self::with_action(addr..addr.saturating_add(len.max(1)), Action::Stop) }
+    }
+
+    /// Create a watchpoint on the virtual addresses `range` that takes `action`. It catches no
+    /// access until its kinds of access are set.
+    pub fn with_action(range: Range<u64>, action: Action) -> Self {
+        Self { range, physical: false, read: false, write: false, execute: false, value: None, action }
+    }
+
+    /// Return true if `access` of `size` bits at the virtual address `addr`, which is the
+    /// physical address `p_addr`, with `value` is caught.
+    pub fn matches(&self, access: Access, addr: u64, p_addr: u64, size: u64, value: u64) -> bool {
+        let kind = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Fetch => self.execute,
+        };
+        let addr = if self.physical { p_addr } else { addr };
+        let mask = if size == 64 { u64::MAX } else { (1 << size) - 1 };
+        kind
+            && addr < self.range.end
+            && self.range.start < addr.saturating_add(size / 8)
+            && self.value.is_none_or(|v| v & mask == value & mask)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let mut watchpoint = Watchpoint::new(0x1000, 8, false, true);
+        watchpoint.physical = true;
+        watchpoint.value = Some(0x1ff);
+        // The range is of physical addresses, and the value is compared at the size of the access.
+        assert!(watchpoint.matches(Access::Write, 0x5000, 0x1004, 8, 0xff));
+        assert!(watchpoint.matches(Access::Write, 0x5000, 0x1000, 16, 0x1ff));
+        assert!(!watchpoint.matches(Access::Write, 0x1000, 0x5000, 8, 0xff));
+        assert!(!watchpoint.matches(Access::Write, 0x5000, 0x1000, 16, 0xff));
+        assert!(!watchpoint.matches(Access::Read, 0x5000, 0x1000, 8, 0xff));
+        assert!(watchpoint.matches(Access::Write, 0x5000, 0x0ffc, 64, 0x1ff));
+    }
+}